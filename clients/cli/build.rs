@@ -22,6 +22,9 @@ fn main() -> Result<(), Box<dyn Error>> {
         }
     }
 
+    // The target triple selects the binary to download when auto-updating from a release
+    println!("cargo:rustc-env=TARGET={}", std::env::var("TARGET")?);
+
      // Tell Cargo to rerun this script if the proto file changes
      println!("cargo:rerun-if-changed={}", proto_file.display());
 
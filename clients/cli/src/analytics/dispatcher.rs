@@ -0,0 +1,556 @@
+//! Background delivery of analytics events
+//!
+//! `track` only enqueues events. A single dispatcher task batches them per sink, retries failed
+//! or timed out deliveries with exponential backoff, and spools whatever could not be delivered to
+//! disk so it survives a restart. Both the in-memory queue and the spool are bounded, dropping the
+//! oldest events first, so being offline for a long time never grows memory or disk usage
+//! unboundedly.
+//!
+//! Each batch is sent by a task of its own, so the dispatcher keeps taking events off the channel
+//! while a slow collector holds up a delivery; otherwise the channel would fill up and `track`
+//! would drop events.
+
+use super::sinks::{AnalyticsSink, SinkError};
+use super::AnalyticsEvent;
+use futures::future::BoxFuture;
+use futures::stream::{FuturesUnordered, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{Instant, MissedTickBehavior};
+
+#[derive(Debug, Clone)]
+pub struct DispatcherConfig {
+    /// Maximum number of events delivered to a sink at once
+    pub batch_size: usize,
+    /// How often queued events are delivered, even if a batch isn't full
+    pub flush_interval: Duration,
+    /// Maximum number of undelivered events kept in memory per sink
+    pub max_queued_events: usize,
+    /// Maximum number of undelivered events persisted to disk per sink
+    pub max_spooled_events: usize,
+    /// Delay before the first retry of a failed delivery, doubled on every further failure
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// How long a single delivery may take before it is given up and retried like a failure, so
+    /// that an unresponsive collector doesn't hold up the dispatcher
+    pub send_timeout: Duration,
+    /// How long a shutdown may spend delivering the remaining events
+    pub shutdown_timeout: Duration,
+}
+
+impl Default for DispatcherConfig {
+    fn default() -> Self {
+        Self {
+            batch_size: 20,
+            flush_interval: Duration::from_secs(5),
+            max_queued_events: 5000,
+            max_spooled_events: 1000,
+            initial_backoff: Duration::from_secs(2),
+            max_backoff: Duration::from_secs(300),
+            send_timeout: Duration::from_secs(10),
+            shutdown_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+pub enum DispatcherMessage {
+    Event(AnalyticsEvent),
+    /// Deliver (or spool) everything that is queued, then acknowledge and stop
+    Shutdown(oneshot::Sender<()>),
+}
+
+/// A batch being sent, resolving to the index of its queue, its size and the outcome
+type Delivery = BoxFuture<'static, (usize, usize, Result<(), SinkError>)>;
+
+#[derive(Serialize, Deserialize)]
+struct SpooledEvent {
+    sink: String,
+    event: AnalyticsEvent,
+}
+
+struct SinkQueue {
+    sink: Arc<dyn AnalyticsSink>,
+    name: String,
+    events: VecDeque<AnalyticsEvent>,
+    /// Whether a batch is being sent
+    delivering: bool,
+    /// How many of the oldest events are in the batch being sent
+    in_flight: usize,
+    consecutive_failures: u32,
+    next_attempt: Instant,
+}
+
+impl SinkQueue {
+    fn push(&mut self, event: AnalyticsEvent, max_queued_events: usize) {
+        if self.events.len() >= max_queued_events {
+            self.events.pop_front();
+            self.in_flight = self.in_flight.saturating_sub(1);
+        }
+        self.events.push_back(event);
+    }
+
+    fn backoff(&self, config: &DispatcherConfig) -> Duration {
+        let exponent = self.consecutive_failures.saturating_sub(1).min(16);
+        config
+            .initial_backoff
+            .saturating_mul(2u32.pow(exponent))
+            .min(config.max_backoff)
+    }
+
+    /// Start sending the oldest events in a task of their own, unless a batch is already being
+    /// sent, there is nothing to send or the last failure is still being backed off from
+    fn start_delivery(
+        &mut self,
+        index: usize,
+        config: &DispatcherConfig,
+        deliveries: &mut FuturesUnordered<Delivery>,
+    ) {
+        if self.delivering || self.events.is_empty() || Instant::now() < self.next_attempt {
+            return;
+        }
+        let count = self.events.len().min(config.batch_size.max(1));
+        let batch: Vec<AnalyticsEvent> = self.events.iter().take(count).cloned().collect();
+        let sink = self.sink.clone();
+        let send_timeout = config.send_timeout;
+        let task = tokio::spawn(async move {
+            tokio::time::timeout(send_timeout, sink.send_batch(&batch))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(format!("timed out after {} seconds", send_timeout.as_secs()).into())
+                })
+        });
+
+        self.delivering = true;
+        self.in_flight = count;
+        deliveries.push(Box::pin(async move {
+            (index, count, task.await.unwrap_or_else(|e| Err(e.into())))
+        }));
+    }
+
+    /// Remove the delivered events, or back off after a failure. Returns whether any event was
+    /// delivered.
+    fn finish_delivery(
+        &mut self,
+        count: usize,
+        result: Result<(), SinkError>,
+        config: &DispatcherConfig,
+    ) -> bool {
+        self.delivering = false;
+        let in_flight = std::mem::take(&mut self.in_flight);
+        match result {
+            Ok(()) => {
+                self.events.drain(..in_flight);
+                self.consecutive_failures = 0;
+                true
+            }
+            Err(e) => {
+                self.consecutive_failures += 1;
+                let delay = self.backoff(config);
+                self.next_attempt = Instant::now() + delay;
+                eprintln!(
+                    "Failed to deliver {} analytics events to {} (retrying in {} seconds): {}",
+                    count,
+                    self.name,
+                    delay.as_secs(),
+                    e
+                );
+                false
+            }
+        }
+    }
+}
+
+/// Run the dispatcher until a shutdown message is received or every sender is dropped
+pub async fn run_dispatcher(
+    sinks: Vec<Arc<dyn AnalyticsSink>>,
+    mut messages: mpsc::Receiver<DispatcherMessage>,
+    spool_path: Option<PathBuf>,
+    config: DispatcherConfig,
+) {
+    let mut queues: Vec<SinkQueue> = sinks
+        .into_iter()
+        .map(|sink| SinkQueue {
+            name: sink.name(),
+            sink,
+            events: VecDeque::new(),
+            delivering: false,
+            in_flight: 0,
+            consecutive_failures: 0,
+            next_attempt: Instant::now(),
+        })
+        .collect();
+
+    // Events left over from a previous run go out first
+    if let Some(path) = &spool_path {
+        for spooled in load_spool(path) {
+            if let Some(queue) = queues.iter_mut().find(|queue| queue.name == spooled.sink) {
+                queue.push(spooled.event, config.max_queued_events);
+            }
+        }
+    }
+
+    let mut deliveries = FuturesUnordered::new();
+    let mut ticker = tokio::time::interval(config.flush_interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // Whether the queues changed since the spool was last written
+    let mut dirty = false;
+
+    let shutdown_ack = loop {
+        tokio::select! {
+            message = messages.recv() => match message {
+                Some(DispatcherMessage::Event(event)) => {
+                    // Full batches go out right away rather than on the next tick
+                    for (index, queue) in queues.iter_mut().enumerate() {
+                        queue.push(event.clone(), config.max_queued_events);
+                        if queue.events.len() >= config.batch_size {
+                            queue.start_delivery(index, &config, &mut deliveries);
+                        }
+                    }
+                    dirty = true;
+                }
+                Some(DispatcherMessage::Shutdown(ack)) => break Some(ack),
+                None => break None,
+            },
+            Some((index, count, result)) = deliveries.next(), if !deliveries.is_empty() => {
+                let queue = &mut queues[index];
+                dirty |= queue.finish_delivery(count, result, &config);
+                queue.start_delivery(index, &config, &mut deliveries);
+            }
+            _ = ticker.tick() => {
+                start_deliveries(&mut queues, &config, &mut deliveries);
+                if dirty {
+                    if let Some(path) = &spool_path {
+                        write_spool(path, &queues, config.max_spooled_events);
+                    }
+                    dirty = false;
+                }
+            }
+        }
+    };
+
+    // Pick up events that were tracked right before the shutdown
+    while let Ok(DispatcherMessage::Event(event)) = messages.try_recv() {
+        for queue in &mut queues {
+            queue.push(event.clone(), config.max_queued_events);
+        }
+    }
+
+    // One last attempt regardless of backoff, then spool whatever is left, including batches
+    // that are still being sent
+    for queue in &mut queues {
+        queue.next_attempt = Instant::now();
+    }
+    let last_attempt = async {
+        start_deliveries(&mut queues, &config, &mut deliveries);
+        while let Some((index, count, result)) = deliveries.next().await {
+            let queue = &mut queues[index];
+            queue.finish_delivery(count, result, &config);
+            queue.start_delivery(index, &config, &mut deliveries);
+        }
+    };
+    let _ = tokio::time::timeout(config.shutdown_timeout, last_attempt).await;
+    if let Some(path) = &spool_path {
+        write_spool(path, &queues, config.max_spooled_events);
+    }
+
+    if let Some(ack) = shutdown_ack {
+        let _ = ack.send(());
+    }
+}
+
+fn start_deliveries(
+    queues: &mut [SinkQueue],
+    config: &DispatcherConfig,
+    deliveries: &mut FuturesUnordered<Delivery>,
+) {
+    for (index, queue) in queues.iter_mut().enumerate() {
+        queue.start_delivery(index, config, deliveries);
+    }
+}
+
+fn load_spool(path: &Path) -> Vec<SpooledEvent> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(_) => return Vec::new(),
+    };
+
+    content
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect()
+}
+
+// Persist the newest undelivered events of every sink, or remove the spool if there are none
+fn write_spool(path: &Path, queues: &[SinkQueue], max_spooled_events: usize) {
+    let mut content = String::new();
+    for queue in queues {
+        let skip = queue.events.len().saturating_sub(max_spooled_events);
+        for event in queue.events.iter().skip(skip) {
+            let spooled = SpooledEvent {
+                sink: queue.name.clone(),
+                event: event.clone(),
+            };
+            if let Ok(line) = serde_json::to_string(&spooled) {
+                content.push_str(&line);
+                content.push('\n');
+            }
+        }
+    }
+
+    let result = if content.is_empty() {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    } else {
+        path.parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(path, content))
+    };
+    if let Err(e) = result {
+        eprintln!(
+            "Failed to persist undelivered analytics events to {}: {}",
+            path.display(),
+            e
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parking_lot::Mutex;
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    /// A sink that fails (or never answers) a given number of times before accepting events
+    struct FlakySink {
+        failures_left: Mutex<u32>,
+        stalls_left: Mutex<u32>,
+        received: Mutex<Vec<Vec<String>>>,
+    }
+
+    impl FlakySink {
+        fn new(failures: u32) -> Arc<Self> {
+            Arc::new(Self {
+                failures_left: Mutex::new(failures),
+                stalls_left: Mutex::new(0),
+                received: Mutex::new(Vec::new()),
+            })
+        }
+
+        fn stalling(stalls: u32) -> Arc<Self> {
+            Arc::new(Self {
+                failures_left: Mutex::new(0),
+                stalls_left: Mutex::new(stalls),
+                received: Mutex::new(Vec::new()),
+            })
+        }
+    }
+
+    impl AnalyticsSink for FlakySink {
+        fn name(&self) -> String {
+            "flaky".into()
+        }
+
+        fn render(&self, events: &[AnalyticsEvent]) -> String {
+            format!("{} events", events.len())
+        }
+
+        fn send_batch<'a>(
+            &'a self,
+            events: &'a [AnalyticsEvent],
+        ) -> BoxFuture<'a, Result<(), SinkError>> {
+            Box::pin(async move {
+                let stalled = {
+                    let mut stalls_left = self.stalls_left.lock();
+                    let stalled = *stalls_left > 0;
+                    *stalls_left = stalls_left.saturating_sub(1);
+                    stalled
+                };
+                if stalled {
+                    std::future::pending::<()>().await;
+                }
+                let mut failures_left = self.failures_left.lock();
+                if *failures_left > 0 {
+                    *failures_left -= 1;
+                    return Err("collector unavailable".into());
+                }
+                let names = events.iter().map(|event| event.name.clone()).collect();
+                self.received.lock().push(names);
+                Ok(())
+            })
+        }
+    }
+
+    fn event(name: &str) -> AnalyticsEvent {
+        AnalyticsEvent {
+            name: name.into(),
+            app_instance_id: json!("happy-prover-42"),
+            params: json!({}),
+        }
+    }
+
+    fn test_config() -> DispatcherConfig {
+        DispatcherConfig {
+            batch_size: 2,
+            flush_interval: Duration::from_millis(10),
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(40),
+            send_timeout: Duration::from_millis(50),
+            shutdown_timeout: Duration::from_millis(200),
+            ..DispatcherConfig::default()
+        }
+    }
+
+    /// Let the dispatcher run for a while of the paused clock, a few milliseconds at a time
+    async fn run_for(duration: Duration) {
+        let step = Duration::from_millis(5);
+        for _ in 0..duration.as_millis() / step.as_millis() {
+            tokio::time::advance(step).await;
+        }
+    }
+
+    /// Tests that events are batched and redelivered after transient failures
+    #[tokio::test(start_paused = true)]
+    async fn test_batches_and_retries() {
+        let sink = FlakySink::new(2);
+        let (sender, receiver) = mpsc::channel(16);
+        let handle = tokio::spawn(run_dispatcher(
+            vec![sink.clone() as Arc<dyn AnalyticsSink>],
+            receiver,
+            None,
+            test_config(),
+        ));
+
+        for name in ["connect", "register", "proof"] {
+            sender
+                .send(DispatcherMessage::Event(event(name)))
+                .await
+                .unwrap();
+        }
+        run_for(Duration::from_millis(300)).await;
+
+        let (ack, done) = oneshot::channel();
+        sender.send(DispatcherMessage::Shutdown(ack)).await.unwrap();
+        done.await.unwrap();
+        handle.await.unwrap();
+
+        let received = sink.received.lock().clone();
+        assert!(received.iter().all(|batch| batch.len() <= 2));
+        let names: Vec<String> = received.into_iter().flatten().collect();
+        assert_eq!(names, vec!["connect", "register", "proof"]);
+    }
+
+    /// Tests that a delivery the collector never answers times out and is retried
+    #[tokio::test(start_paused = true)]
+    async fn test_stalled_delivery_times_out() {
+        let sink = FlakySink::stalling(2);
+        let (sender, receiver) = mpsc::channel(16);
+        let handle = tokio::spawn(run_dispatcher(
+            vec![sink.clone() as Arc<dyn AnalyticsSink>],
+            receiver,
+            None,
+            test_config(),
+        ));
+
+        for name in ["connect", "register"] {
+            sender
+                .send(DispatcherMessage::Event(event(name)))
+                .await
+                .unwrap();
+        }
+        run_for(Duration::from_millis(300)).await;
+        assert_eq!(
+            sink.received.lock().clone(),
+            vec![vec!["connect", "register"]]
+        );
+
+        drop(sender);
+        handle.await.unwrap();
+    }
+
+    /// Tests that events keep being taken off the channel while a delivery is held up
+    #[tokio::test(start_paused = true)]
+    async fn test_receives_during_delivery() {
+        let sink = FlakySink::stalling(1);
+        let (sender, receiver) = mpsc::channel(2);
+        let handle = tokio::spawn(run_dispatcher(
+            vec![sink.clone() as Arc<dyn AnalyticsSink>],
+            receiver,
+            None,
+            test_config(),
+        ));
+
+        let names: Vec<String> = (0..10).map(|i| format!("step-{}", i)).collect();
+        for name in &names {
+            tokio::task::yield_now().await;
+            sender
+                .try_send(DispatcherMessage::Event(event(name)))
+                .unwrap_or_else(|_| panic!("{} should fit in the channel", name));
+        }
+        run_for(Duration::from_millis(300)).await;
+        drop(sender);
+        handle.await.unwrap();
+
+        let received: Vec<String> = sink.received.lock().iter().flatten().cloned().collect();
+        assert_eq!(received, names);
+    }
+
+    /// Tests that undelivered events are spooled on shutdown and delivered by the next run
+    #[tokio::test(start_paused = true)]
+    async fn test_spool_survives_restart() {
+        let temp_dir = TempDir::new().unwrap();
+        let spool_path = temp_dir.path().join("analytics-spool.jsonl");
+
+        // First run: the sink never accepts anything
+        let offline_sink = FlakySink::new(u32::MAX);
+        let (sender, receiver) = mpsc::channel(16);
+        let handle = tokio::spawn(run_dispatcher(
+            vec![offline_sink as Arc<dyn AnalyticsSink>],
+            receiver,
+            Some(spool_path.clone()),
+            test_config(),
+        ));
+        sender
+            .send(DispatcherMessage::Event(event("proof")))
+            .await
+            .unwrap();
+        let (ack, done) = oneshot::channel();
+        sender.send(DispatcherMessage::Shutdown(ack)).await.unwrap();
+        done.await.unwrap();
+        handle.await.unwrap();
+        assert!(spool_path.exists(), "Undelivered events should be spooled");
+
+        // Second run: the sink is back online
+        let online_sink = FlakySink::new(0);
+        let (sender, receiver) = mpsc::channel(16);
+        let handle = tokio::spawn(run_dispatcher(
+            vec![online_sink.clone() as Arc<dyn AnalyticsSink>],
+            receiver,
+            Some(spool_path.clone()),
+            test_config(),
+        ));
+        run_for(Duration::from_millis(100)).await;
+        drop(sender);
+        handle.await.unwrap();
+
+        let names: Vec<String> = online_sink
+            .received
+            .lock()
+            .iter()
+            .flatten()
+            .cloned()
+            .collect();
+        assert_eq!(names, vec!["proof"]);
+        assert!(
+            !spool_path.exists(),
+            "Spool should be removed once delivered"
+        );
+    }
+}
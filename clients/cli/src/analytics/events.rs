@@ -0,0 +1,242 @@
+//! The analytics event schema
+//!
+//! Every event the CLI emits is a variant of [`Event`]. Each one is wrapped in an
+//! [`EventEnvelope`] carrying the schema version, a per-process session ID and a sequence
+//! number, so the analytics pipeline can validate events, order them and detect gaps.
+//!
+//! Changing a variant in a way that breaks existing consumers (renaming or removing a property,
+//! changing its type) requires bumping [`SCHEMA_VERSION`]. The JSON schema document checked in at
+//! `clients/cli/analytics-events.schema.json` is generated from these types with
+//! `cargo run -- analytics schema`, and a test keeps it up to date.
+//!
+//! The properties in [`REDACTABLE`] can be redacted in the configuration file. Redacted properties
+//! are left out of the events, so they are optional in the schema, except for the prover ID,
+//! which is replaced by a pseudonym.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// The properties that can be redacted
+pub const REDACTABLE: &[&str] = &[
+    "prover_id",
+    "time_zone",
+    "local_hour",
+    "local_weekday_number_from_monday",
+    "operating_system",
+    "ws_addr_string",
+];
+
+/// An analytics event and its event-specific properties
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// The CLI is about to connect to the orchestrator
+    Connect { prover_id: String },
+    /// A websocket connection to the orchestrator was established
+    Connected { prover_id: String },
+    /// The prover is registered and about to start proving
+    Register { prover_id: String },
+    /// A proof of a step range was completed
+    Proof {
+        prover_id: String,
+        program_name: String,
+        steps_in_trace: usize,
+        steps_to_prove: usize,
+        steps_proven: i32,
+        cycles_proven: i32,
+        k: i32,
+        proof_duration_sec: u64,
+        proof_duration_millis: u64,
+        proof_cycles_per_minute: f64,
+    },
+    /// The websocket connection could not be closed cleanly
+    CloseError {
+        prover_id: String,
+        program_name: String,
+        error: String,
+    },
+    /// The CLI closed the connection after proving
+    Disconnect {
+        prover_id: String,
+        program_name: String,
+    },
+    /// The prover got an arm of an experiment for the first time
+    Exposure {
+        prover_id: String,
+        experiment: String,
+        /// The program assigned by the experiment, or "control" if the prover is not enrolled
+        arm: String,
+        /// Whether the arm was pinned locally with `experiments override`
+        overridden: bool,
+    },
+    /// The auto-updater found a new version but is configured to only report it
+    UpdateAvailable {
+        prover_id: String,
+        current_version: String,
+        available_version: String,
+    },
+}
+
+impl Event {
+    /// The event name, as reported to the sinks
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::Connect { .. } => "connect",
+            Event::Connected { .. } => "connected",
+            Event::Register { .. } => "register",
+            Event::Proof { .. } => "proof",
+            Event::CloseError { .. } => "close_error",
+            Event::Disconnect { .. } => "disconnect",
+            Event::Exposure { .. } => "exposure",
+            Event::UpdateAvailable { .. } => "update_available",
+        }
+    }
+
+    pub fn prover_id(&self) -> &str {
+        match self {
+            Event::Connect { prover_id }
+            | Event::Connected { prover_id }
+            | Event::Register { prover_id }
+            | Event::Proof { prover_id, .. }
+            | Event::CloseError { prover_id, .. }
+            | Event::Disconnect { prover_id, .. }
+            | Event::Exposure { prover_id, .. }
+            | Event::UpdateAvailable { prover_id, .. } => prover_id,
+        }
+    }
+
+    /// A human-readable description, printed when the event is tracked with `print_description`
+    pub fn description(&self, ws_addr_string: &str) -> String {
+        match self {
+            Event::Connect { .. } => format!("Connecting to {}...", ws_addr_string),
+            Event::Connected { .. } => "Connected.".into(),
+            Event::Register { prover_id } => {
+                format!("Your current prover identifier is {}.", prover_id)
+            }
+            Event::Proof { .. } => "Proof generated".into(),
+            Event::CloseError { .. } => "Failed to close WebSocket connection".into(),
+            Event::Disconnect { .. } => "Sent proof and closed connection...".into(),
+            Event::Exposure {
+                experiment, arm, ..
+            } => format!("Assigned arm {} of experiment {}.", arm, experiment),
+            Event::UpdateAvailable {
+                available_version, ..
+            } => format!("Version {} is available.", available_version),
+        }
+    }
+}
+
+/// Properties common to every event
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct EventEnvelope {
+    /// Version of this schema the event conforms to
+    pub schema_version: u32,
+    /// Random identifier of the CLI process that emitted the event
+    pub session_id: String,
+    /// Position of the event within its session, starting at 0
+    pub sequence_number: u64,
+    /// Unix time of the event in milliseconds
+    pub time: u64,
+    /// The prover ID, under the key Firebase uses to track the same user across sessions
+    pub app_instance_id: String,
+    /// The prover ID again, kept for backwards compatibility
+    pub distinct_id: String,
+    pub prover_type: String,
+    pub client_type: String,
+    /// Left out if redacted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub operating_system: Option<String>,
+    /// Left out if redacted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time_zone: Option<String>,
+    /// Left out if redacted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub local_hour: Option<u32>,
+    /// Left out if redacted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub local_weekday_number_from_monday: Option<u32>,
+    /// Left out if redacted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ws_addr_string: Option<String>,
+    /// Experiment arms pinned on this machine with `experiments override`, e.g.
+    /// "NEX-1=cancer-diagnostic". Events with overrides should be left out of experiment results.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub experiment_overrides: Option<String>,
+}
+
+/// The properties of an event as delivered to the sinks: the envelope and the event-specific
+/// properties side by side in one flat object
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct EventParams {
+    #[serde(flatten)]
+    pub envelope: EventEnvelope,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+/// The JSON schema document describing the properties of every event
+pub fn schema_document() -> Value {
+    let mut schema = serde_json::to_value(schemars::schema_for!(EventParams)).unwrap_or_default();
+    schema["title"] = Value::from("Nexus CLI analytics event");
+    schema["$comment"] = Value::from(format!("schema_version {}", SCHEMA_VERSION));
+    schema
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHECKED_IN_SCHEMA: &str = include_str!("../../analytics-events.schema.json");
+
+    /// Tests that the checked-in schema document matches the event types
+    #[test]
+    fn test_schema_document_is_up_to_date() {
+        let checked_in: Value =
+            serde_json::from_str(CHECKED_IN_SCHEMA).expect("Schema document should be JSON");
+        assert_eq!(
+            checked_in,
+            schema_document(),
+            "analytics-events.schema.json is out of date. Regenerate it with \
+             `cargo run -- analytics schema > analytics-events.schema.json`"
+        );
+    }
+
+    /// Tests that event properties and the envelope serialize into one flat object
+    #[test]
+    fn test_params_are_flat() {
+        let params = EventParams {
+            envelope: EventEnvelope {
+                schema_version: SCHEMA_VERSION,
+                session_id: "session".into(),
+                sequence_number: 3,
+                time: 0,
+                app_instance_id: "happy-prover-42".into(),
+                distinct_id: "happy-prover-42".into(),
+                prover_type: "volunteer".into(),
+                client_type: "cli".into(),
+                operating_system: Some("linux".into()),
+                time_zone: Some("UTC".into()),
+                local_hour: Some(12),
+                local_weekday_number_from_monday: Some(1),
+                ws_addr_string: Some("wss://beta.orchestrator.nexus.xyz:443/prove".into()),
+                experiment_overrides: None,
+            },
+            event: Event::Disconnect {
+                prover_id: "happy-prover-42".into(),
+                program_name: "fast-fib".into(),
+            },
+        };
+
+        let value = serde_json::to_value(&params).unwrap();
+        assert_eq!(value["event"], "disconnect");
+        assert_eq!(value["program_name"], "fast-fib");
+        assert_eq!(value["sequence_number"], 3);
+        assert_eq!(
+            serde_json::from_value::<EventParams>(value).unwrap(),
+            params
+        );
+    }
+}
@@ -0,0 +1,466 @@
+pub mod dispatcher;
+pub mod events;
+pub mod pseudonym;
+pub mod sinks;
+
+use crate::config::AnalyticsConfig;
+use crate::utils::experiment;
+use chrono::Datelike;
+use chrono::Timelike;
+use dispatcher::{DispatcherConfig, DispatcherMessage};
+pub use events::Event;
+use events::{EventEnvelope, EventParams, REDACTABLE, SCHEMA_VERSION};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sinks::AnalyticsSink;
+use std::{
+    collections::BTreeSet,
+    env,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::{mpsc, oneshot};
+
+// Events waiting for the dispatcher beyond this are dropped rather than blocking the prover
+const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+// Identifies the events of this process, so the pipeline can order them and detect gaps
+static SESSION_ID: OnceLock<String> = OnceLock::new();
+static SEQUENCE_NUMBER: AtomicU64 = AtomicU64::new(0);
+
+fn session_id() -> &'static str {
+    SESSION_ID.get_or_init(|| uuid::Uuid::new_v4().to_string())
+}
+
+// Privacy settings and destinations for analytics, configured once at startup
+static ANALYTICS: OnceLock<Analytics> = OnceLock::new();
+
+struct Analytics {
+    settings: AnalyticsSettings,
+    sinks: Vec<Arc<dyn AnalyticsSink>>,
+    sender: mpsc::Sender<DispatcherMessage>,
+    // Taken by `start` when the dispatcher task is spawned
+    receiver: Mutex<Option<mpsc::Receiver<DispatcherMessage>>>,
+}
+
+impl Analytics {
+    fn new(settings: AnalyticsSettings, sinks: Vec<Arc<dyn AnalyticsSink>>) -> Self {
+        let (sender, receiver) = mpsc::channel(EVENT_CHANNEL_CAPACITY);
+        Self {
+            settings,
+            sinks,
+            sender,
+            receiver: Mutex::new(Some(receiver)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AnalyticsSettings {
+    /// Why analytics are turned off, or None if they are on
+    pub opt_out: Option<&'static str>,
+    /// Event properties to leave out of the events, or to pseudonymize for the prover ID
+    pub redact: BTreeSet<String>,
+    /// The key of the pseudonym that replaces the prover ID, if it is redacted
+    pub pseudonym_salt: Option<Vec<u8>>,
+}
+
+impl AnalyticsSettings {
+    /// Combine the configuration file, the --no-analytics flag and the DO_NOT_TRACK convention.
+    /// Fails if the configuration file asks to redact a property that can't be redacted.
+    pub fn resolve(config: &AnalyticsConfig, no_analytics_flag: bool) -> Result<Self, String> {
+        let opt_out = if no_analytics_flag {
+            Some("--no-analytics flag")
+        } else if do_not_track() {
+            Some("DO_NOT_TRACK environment variable")
+        } else if !config.enabled {
+            Some("\"enabled\": false in the configuration file")
+        } else {
+            None
+        };
+
+        let redact: BTreeSet<String> = config.redact.iter().cloned().collect();
+        if let Some(property) = redact
+            .iter()
+            .find(|property| !REDACTABLE.contains(&property.as_str()))
+        {
+            return Err(format!(
+                "\"{}\" can't be redacted, only {}",
+                property,
+                REDACTABLE.join(", ")
+            ));
+        }
+        let pseudonym_salt = (opt_out.is_none() && redact.contains("prover_id"))
+            .then(|| pseudonym::load_or_create_salt(pseudonym::salt_path().as_deref()));
+        Ok(Self {
+            opt_out,
+            redact,
+            pseudonym_salt,
+        })
+    }
+
+    /// Whether events are sent at all
+    pub fn is_enabled(&self) -> bool {
+        self.opt_out.is_none()
+    }
+}
+
+// https://consoledonottrack.com/: any value other than empty, "0" or "false" opts out
+fn do_not_track() -> bool {
+    env::var("DO_NOT_TRACK")
+        .map(|value| {
+            let value = value.trim();
+            !value.is_empty() && value != "0" && !value.eq_ignore_ascii_case("false")
+        })
+        .unwrap_or(false)
+}
+
+/// An event with the privacy settings already applied, ready to be handed to the sinks
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnalyticsEvent {
+    pub name: String,
+    /// app_instance_id is the standard key Firebase uses to track the same user across sessions
+    pub app_instance_id: Value,
+    pub params: Value,
+}
+
+/// Set the privacy settings and destinations used by every later call to `track`
+pub fn configure(settings: AnalyticsSettings, sinks: Vec<Arc<dyn AnalyticsSink>>) {
+    if ANALYTICS.set(Analytics::new(settings, sinks)).is_err() {
+        eprintln!("Warning: analytics were already configured");
+    }
+}
+
+fn analytics() -> &'static Analytics {
+    ANALYTICS.get_or_init(|| {
+        let settings = AnalyticsSettings::resolve(&AnalyticsConfig::default(), false)
+            .expect("Nothing is redacted by default");
+        Analytics::new(settings, Vec::new())
+    })
+}
+
+/// Spawn the background task that delivers tracked events. Events tracked before this are
+/// buffered, and undelivered events from a previous run are loaded from the spool file.
+pub fn start(spool_path: Option<PathBuf>) {
+    let analytics = analytics();
+    if !analytics.settings.is_enabled() || analytics.sinks.is_empty() {
+        return;
+    }
+
+    let receiver = analytics
+        .receiver
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .take();
+    if let Some(receiver) = receiver {
+        tokio::spawn(dispatcher::run_dispatcher(
+            analytics.sinks.clone(),
+            receiver,
+            spool_path,
+            DispatcherConfig::default(),
+        ));
+    }
+}
+
+/// Deliver the queued events (spooling those that can't be delivered) before the process exits
+pub async fn shutdown() {
+    let analytics = analytics();
+    let started = analytics
+        .receiver
+        .lock()
+        .map(|receiver| receiver.is_none())
+        .unwrap_or(false);
+    if !started {
+        return;
+    }
+
+    let (ack, done) = oneshot::channel();
+    if analytics
+        .sender
+        .send(DispatcherMessage::Shutdown(ack))
+        .await
+        .is_ok()
+    {
+        let shutdown_timeout = DispatcherConfig::default().shutdown_timeout;
+        let _ = tokio::time::timeout(shutdown_timeout + Duration::from_secs(1), done).await;
+    }
+}
+
+/// Queue an event for the sinks, printing its description first if asked to. Never blocks: the
+/// event is dropped if the queue is full.
+pub fn track(event: Event, ws_addr_string: &str, print_description: bool) {
+    if print_description {
+        println!("{}", event.description(ws_addr_string));
+    }
+
+    let analytics = analytics();
+    if !analytics.settings.is_enabled() || analytics.sinks.is_empty() {
+        return;
+    }
+
+    let event = build_event(&event, ws_addr_string, &analytics.settings);
+    let event_name = event.name.clone();
+    if let Err(e) = analytics.sender.try_send(DispatcherMessage::Event(event)) {
+        eprintln!("Dropped analytics event '{}': {}", event_name, e);
+    }
+}
+
+/// Print exactly what would be sent for the events emitted while connecting, without sending
+/// anything
+pub fn print_preview(ws_addr_string: &str, prover_id: &str) {
+    let analytics = analytics();
+    if let Some(reason) = analytics.settings.opt_out {
+        println!("Analytics are disabled by the {}. Nothing is sent.", reason);
+        return;
+    }
+    if analytics.sinks.is_empty() {
+        println!(
+            "No analytics destination is configured for {}. Nothing is sent.",
+            ws_addr_string
+        );
+        return;
+    }
+
+    if !analytics.settings.redact.is_empty() {
+        let redacted: Vec<&str> = analytics
+            .settings
+            .redact
+            .iter()
+            .map(String::as_str)
+            .collect();
+        println!("Redacted properties: {}", redacted.join(", "));
+    }
+
+    let sample_events = [
+        Event::Connect {
+            prover_id: prover_id.to_string(),
+        },
+        Event::Connected {
+            prover_id: prover_id.to_string(),
+        },
+        Event::Register {
+            prover_id: prover_id.to_string(),
+        },
+    ];
+    let events: Vec<AnalyticsEvent> = sample_events
+        .iter()
+        .map(|event| build_event(event, ws_addr_string, &analytics.settings))
+        .collect();
+    for sink in &analytics.sinks {
+        println!("\nSent to {}:\n{}", sink.name(), sink.render(&events));
+    }
+}
+
+// Build the properties of an event, with the privacy settings applied
+fn build_event(
+    event: &Event,
+    ws_addr_string: &str,
+    settings: &AnalyticsSettings,
+) -> AnalyticsEvent {
+    let local_now = chrono::offset::Local::now();
+
+    // For tracking events, we use the Firebase Measurement Protocol
+    // Firebase is mostly designed for mobile and web apps, but for our use case of a CLI,
+    // we can use the Measurement Protocol to track events by POST to a URL.
+    // The only thing that may be unexpected is that the URL we use includes a firebase key
+
+    // Firebase format for properties for Measurement protocol:
+    // https://developers.google.com/analytics/devguides/collection/protocol/ga4/reference?client_type=firebase#payload
+    // https://developers.google.com/analytics/devguides/collection/protocol/ga4/reference?client_type=firebase#payload_query_parameters
+
+    let system_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_else(|e| {
+            eprintln!("Error calculating system time: {}", e);
+            std::time::Duration::from_secs(0) // fallback to epoch start
+        })
+        .as_millis() as u64;
+
+    let timezone = iana_time_zone::get_timezone().ok().map_or_else(
+        || String::from("UTC"), // fallback to UTC
+        |tz| tz,
+    );
+
+    let keep = |property: &str| !settings.redact.contains(property);
+    let params = EventParams {
+        envelope: EventEnvelope {
+            schema_version: SCHEMA_VERSION,
+            session_id: session_id().to_string(),
+            sequence_number: SEQUENCE_NUMBER.fetch_add(1, Ordering::Relaxed),
+            time: system_time,
+            app_instance_id: event.prover_id().to_string(),
+            distinct_id: event.prover_id().to_string(),
+            prover_type: "volunteer".into(),
+            client_type: "cli".into(),
+            operating_system: keep("operating_system").then(|| env::consts::OS.into()),
+            time_zone: keep("time_zone").then_some(timezone),
+            local_hour: keep("local_hour").then(|| local_now.hour()),
+            local_weekday_number_from_monday: keep("local_weekday_number_from_monday")
+                .then(|| local_now.weekday().number_from_monday()),
+            ws_addr_string: keep("ws_addr_string").then(|| ws_addr_string.to_string()),
+            experiment_overrides: experiment::registry().override_summary(),
+        },
+        event: event.clone(),
+    };
+    let mut properties = serde_json::to_value(&params).unwrap_or_else(|e| {
+        eprintln!("Warning: could not serialize analytics event: {}", e);
+        json!({})
+    });
+
+    // Firebase needs a stable app_instance_id to group events, so a redacted prover ID is
+    // replaced by a pseudonym rather than dropped
+    let app_instance_id = if let Some(salt) = &settings.pseudonym_salt {
+        let pseudonym = json!(pseudonym::pseudonym(salt, event.prover_id()));
+        properties["prover_id"] = pseudonym.clone();
+        properties["app_instance_id"] = pseudonym.clone();
+        properties["distinct_id"] = pseudonym.clone();
+        pseudonym
+    } else {
+        json!(event.prover_id())
+    };
+
+    AnalyticsEvent {
+        name: event.name().to_string(),
+        app_instance_id,
+        params: properties,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHECKED_IN_SCHEMA: &str = include_str!("../../analytics-events.schema.json");
+
+    // Check `value` against the parts of JSON schema draft 7 that the event schema uses
+    fn validate(schema: &Value, value: &Value) -> Result<(), String> {
+        if let Some(types) = schema.get("type") {
+            let types: Vec<&str> = match types {
+                Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+                _ => types.as_str().into_iter().collect(),
+            };
+            let has_type = |name: &str| match name {
+                "string" => value.is_string(),
+                "integer" => value.is_i64() || value.is_u64(),
+                "number" => value.is_number(),
+                "boolean" => value.is_boolean(),
+                "object" => value.is_object(),
+                "null" => value.is_null(),
+                _ => false,
+            };
+            if !types.iter().any(|name| has_type(name)) {
+                return Err(format!("{} is not of type {:?}", value, types));
+            }
+        }
+        if let Some(minimum) = schema.get("minimum").and_then(Value::as_f64) {
+            if value.as_f64().is_some_and(|number| number < minimum) {
+                return Err(format!("{} is less than {}", value, minimum));
+            }
+        }
+        if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+            if !allowed.contains(value) {
+                return Err(format!("{} is not one of {:?}", value, allowed));
+            }
+        }
+        for name in schema["required"].as_array().into_iter().flatten() {
+            if value.get(name.as_str().unwrap_or_default()).is_none() {
+                return Err(format!("{} is missing", name));
+            }
+        }
+        for (name, property) in schema["properties"].as_object().into_iter().flatten() {
+            if let Some(value) = value.get(name) {
+                validate(property, value).map_err(|e| format!("{}: {}", name, e))?;
+            }
+        }
+        if let Some(variants) = schema.get("oneOf").and_then(Value::as_array) {
+            let errors: Vec<String> = variants
+                .iter()
+                .filter_map(|variant| validate(variant, value).err())
+                .collect();
+            if errors.len() != variants.len() - 1 {
+                return Err(format!(
+                    "{} matches none or several of oneOf: {:?}",
+                    value, errors
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn proof_event() -> Event {
+        Event::Proof {
+            prover_id: "happy-prover-42".into(),
+            program_name: "fast-fib".into(),
+            steps_in_trace: 100,
+            steps_to_prove: 10,
+            steps_proven: 10,
+            cycles_proven: 40,
+            k: 4,
+            proof_duration_sec: 1,
+            proof_duration_millis: 1500,
+            proof_cycles_per_minute: 1600.0,
+        }
+    }
+
+    /// Tests that events conform to the checked-in schema, with and without redacted properties
+    #[test]
+    fn test_redacted_event_matches_schema() {
+        let schema: Value = serde_json::from_str(CHECKED_IN_SCHEMA).unwrap();
+        let address = "wss://beta.orchestrator.nexus.xyz:443/prove";
+
+        let settings = AnalyticsSettings {
+            opt_out: None,
+            redact: BTreeSet::new(),
+            pseudonym_salt: None,
+        };
+        let event = build_event(&proof_event(), address, &settings);
+        validate(&schema, &event.params).unwrap();
+        assert_eq!(event.params["prover_id"], "happy-prover-42");
+        assert!(event.params.get("local_hour").is_some());
+
+        let settings = AnalyticsSettings {
+            opt_out: None,
+            redact: REDACTABLE
+                .iter()
+                .map(|property| property.to_string())
+                .collect(),
+            pseudonym_salt: Some(vec![7; 32]),
+        };
+        let mut event = build_event(&proof_event(), address, &settings);
+        validate(&schema, &event.params).unwrap();
+        for property in REDACTABLE
+            .iter()
+            .filter(|property| **property != "prover_id")
+        {
+            assert!(
+                event.params.get(property).is_none(),
+                "{} was sent",
+                property
+            );
+        }
+        let pseudonym = pseudonym::pseudonym(&[7; 32], "happy-prover-42");
+        assert_eq!(event.app_instance_id, pseudonym);
+        assert_eq!(event.params["prover_id"], pseudonym);
+        assert_eq!(event.params["distinct_id"], pseudonym);
+        assert!(!event.params.to_string().contains("happy-prover-42"));
+
+        // What redacted properties used to be replaced with
+        event.params["local_hour"] = json!("redacted");
+        assert!(validate(&schema, &event.params).is_err());
+    }
+
+    /// Tests that only the properties the schema allows to be left out can be redacted
+    #[test]
+    fn test_redact_only_redactable_properties() {
+        let config = AnalyticsConfig {
+            redact: vec!["time_zone".into(), "program_name".into()],
+            ..Default::default()
+        };
+        let error = AnalyticsSettings::resolve(&config, true).unwrap_err();
+        assert!(error.starts_with("\"program_name\" can't be redacted"));
+    }
+}
@@ -0,0 +1,118 @@
+//! Pseudonyms for redacted prover IDs
+//!
+//! Firebase groups events by `app_instance_id`, so a redacted prover ID is replaced by a stable
+//! pseudonym rather than dropped: an HMAC-SHA256 of the prover ID, keyed with a random salt that
+//! is generated once per installation and kept in the state directory. Prover IDs are easy to
+//! guess, so unlike a plain hash, the pseudonym can't be reversed by hashing candidate IDs.
+
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+const SALT_LEN: usize = 32;
+
+/// Where the salt of this installation is kept, e.g. `~/.local/state/nexus/analytics-salt`
+pub fn salt_path() -> Option<PathBuf> {
+    crate::state::current().map(|dirs| dirs.state.join("analytics-salt"))
+}
+
+/// The salt of this installation, created on first use. Without a state directory, or if the
+/// salt can't be read or written, a salt of this process only is used: the pseudonyms then
+/// change with every run, but still can't be reversed.
+pub fn load_or_create_salt(path: Option<&Path>) -> Vec<u8> {
+    let salt = path.map(|path| {
+        load_or_create_salt_at(path).map_err(|e| {
+            eprintln!(
+                "Warning: could not use the analytics salt {}: {}",
+                path.display(),
+                e
+            )
+        })
+    });
+    match salt {
+        Some(Ok(salt)) => salt,
+        _ => random_salt(),
+    }
+}
+
+fn load_or_create_salt_at(path: &Path) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if path.exists() {
+        return read_salt(path);
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // The salt is written in full before it is linked into place, so no other process ever reads
+    // a partial salt, and only one of two processes starting at the same time gets to create it
+    let salt = random_salt();
+    let temp_path = path.with_extension(format!("tmp-{}", std::process::id()));
+    fs::write(&temp_path, hex::encode(&salt))?;
+    let linked = fs::hard_link(&temp_path, path);
+    let _ = fs::remove_file(&temp_path);
+    match linked {
+        Ok(()) => Ok(salt),
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => read_salt(path),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn read_salt(path: &Path) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let salt = hex::decode(fs::read_to_string(path)?.trim())?;
+    if salt.len() != SALT_LEN {
+        return Err(format!("expected {} bytes, found {}", SALT_LEN, salt.len()).into());
+    }
+    Ok(salt)
+}
+
+fn random_salt() -> Vec<u8> {
+    let mut salt = vec![0; SALT_LEN];
+    rand::thread_rng().fill_bytes(&mut salt);
+    salt
+}
+
+/// The pseudonym of `prover_id`, hex encoded
+pub fn pseudonym(salt: &[u8], prover_id: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(salt).expect("HMAC takes keys of any length");
+    mac.update(prover_id.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// Tests that pseudonyms are HMAC-SHA256 digests, against a test vector of RFC 4231
+    #[test]
+    fn test_pseudonym() {
+        assert_eq!(
+            pseudonym(b"Jefe", "what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    /// Tests that the salt is created once and then reused, so pseudonyms are stable
+    #[test]
+    fn test_salt_is_kept() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("state").join("analytics-salt");
+        let salt = load_or_create_salt(Some(&path));
+        assert_eq!(salt.len(), SALT_LEN);
+        assert_eq!(load_or_create_salt(Some(&path)), salt);
+        // Without leaving the temporary file behind
+        assert_eq!(fs::read_dir(path.parent().unwrap()).unwrap().count(), 1);
+
+        let pseudonym_of = |salt: &[u8]| pseudonym(salt, "happy-prover-42");
+        assert_eq!(pseudonym_of(&salt).len(), 64);
+        assert_ne!(pseudonym_of(&salt), pseudonym_of(&random_salt()));
+
+        // A damaged salt is not used, nor replaced
+        fs::write(&path, "not hex").unwrap();
+        assert_ne!(load_or_create_salt(Some(&path)), salt);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "not hex");
+    }
+}
@@ -0,0 +1,331 @@
+//! Destinations for analytics events
+//!
+//! Every event is handed to each configured sink, in batches, by the dispatcher. The Firebase
+//! sink preserves the original behavior of the CLI; the JSON Lines and HTTP sinks let operators
+//! feed the same event stream into their own observability stack.
+
+use super::AnalyticsEvent;
+use crate::config::{analytics_api_key, analytics_id, expand_home, AnalyticsSinkConfig};
+use futures::future::BoxFuture;
+use reqwest::header::{ACCEPT, CONTENT_TYPE};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+
+// The Measurement Protocol accepts at most 25 events per request
+const FIREBASE_MAX_EVENTS_PER_REQUEST: usize = 25;
+
+pub type SinkError = Box<dyn std::error::Error + Send + Sync>;
+
+pub trait AnalyticsSink: Send + Sync {
+    /// Short human-readable name, used in logs and previews
+    fn name(&self) -> String;
+
+    /// The exact payload that `send_batch` would deliver for these events
+    fn render(&self, events: &[AnalyticsEvent]) -> String;
+
+    /// Deliver a batch of events. On error the whole batch is retried later.
+    fn send_batch<'a>(
+        &'a self,
+        events: &'a [AnalyticsEvent],
+    ) -> BoxFuture<'a, Result<(), SinkError>>;
+}
+
+/// Build the sinks described in the configuration file. Sinks that can't be used for this
+/// orchestrator (e.g. Firebase for an unknown host) are skipped.
+pub fn build_sinks(
+    configs: &[AnalyticsSinkConfig],
+    ws_addr_string: &str,
+) -> Vec<Arc<dyn AnalyticsSink>> {
+    configs
+        .iter()
+        .filter_map(|config| -> Option<Arc<dyn AnalyticsSink>> {
+            match config {
+                AnalyticsSinkConfig::Firebase => FirebaseSink::for_orchestrator(ws_addr_string)
+                    .map(|sink| Arc::new(sink) as Arc<dyn AnalyticsSink>),
+                AnalyticsSinkConfig::Jsonl { path } => Some(Arc::new(JsonlSink::new(path))),
+                AnalyticsSinkConfig::Http { url, headers } => {
+                    Some(Arc::new(HttpSink::new(url.clone(), headers.clone())))
+                }
+            }
+        })
+        .collect()
+}
+
+/// Sends events to Google Analytics through the Firebase Measurement Protocol
+pub struct FirebaseSink {
+    client: reqwest::Client,
+    app_id: String,
+    api_secret: String,
+}
+
+impl FirebaseSink {
+    pub fn for_orchestrator(ws_addr_string: &str) -> Option<Self> {
+        let app_id = analytics_id(ws_addr_string);
+        if app_id.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            client: crate::proxy::http_client(),
+            app_id,
+            api_secret: analytics_api_key(ws_addr_string),
+        })
+    }
+
+    fn url(&self) -> String {
+        format!(
+            "https://www.google-analytics.com/mp/collect?firebase_app_id={}&api_secret={}",
+            self.app_id, self.api_secret
+        )
+    }
+
+    // One request body per run of events from the same app instance, at most 25 events each
+    fn request_bodies(events: &[AnalyticsEvent]) -> Vec<String> {
+        let mut bodies = Vec::new();
+        for same_instance in events.chunk_by(|a, b| a.app_instance_id == b.app_instance_id) {
+            for chunk in same_instance.chunks(FIREBASE_MAX_EVENTS_PER_REQUEST) {
+                let events: Vec<_> = chunk
+                    .iter()
+                    .map(|event| json!({"name": event.name, "params": event.params}))
+                    .collect();
+                // Firebase format for events
+                let body = json!({
+                    "app_instance_id": chunk[0].app_instance_id,
+                    "events": events,
+                });
+                bodies.push(format!("[{}]", body));
+            }
+        }
+        bodies
+    }
+}
+
+impl AnalyticsSink for FirebaseSink {
+    fn name(&self) -> String {
+        format!("firebase (app id {})", self.app_id)
+    }
+
+    fn render(&self, events: &[AnalyticsEvent]) -> String {
+        Self::request_bodies(events).join("\n")
+    }
+
+    fn send_batch<'a>(
+        &'a self,
+        events: &'a [AnalyticsEvent],
+    ) -> BoxFuture<'a, Result<(), SinkError>> {
+        Box::pin(async move {
+            for body in Self::request_bodies(events) {
+                self.client
+                    .post(self.url())
+                    .body(body)
+                    .header(ACCEPT, "text/plain")
+                    .header(CONTENT_TYPE, "application/json")
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Appends one JSON object per event to a local file
+pub struct JsonlSink {
+    path: PathBuf,
+    // Serializes appends so concurrent events never interleave within a line
+    lock: tokio::sync::Mutex<()>,
+}
+
+impl JsonlSink {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: expand_home(path),
+            lock: tokio::sync::Mutex::new(()),
+        }
+    }
+}
+
+impl AnalyticsSink for JsonlSink {
+    fn name(&self) -> String {
+        format!("jsonl ({})", self.path.display())
+    }
+
+    fn render(&self, events: &[AnalyticsEvent]) -> String {
+        events
+            .iter()
+            .map(|event| serde_json::to_string(event).unwrap_or_default() + "\n")
+            .collect()
+    }
+
+    fn send_batch<'a>(
+        &'a self,
+        events: &'a [AnalyticsEvent],
+    ) -> BoxFuture<'a, Result<(), SinkError>> {
+        Box::pin(async move {
+            let lines = self.render(events);
+            let _guard = self.lock.lock().await;
+            if let Some(parent) = self.path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .await?;
+            file.write_all(lines.as_bytes()).await?;
+            file.flush().await?;
+            Ok(())
+        })
+    }
+}
+
+/// POSTs each batch of events as a JSON array to an arbitrary collector
+pub struct HttpSink {
+    client: reqwest::Client,
+    url: String,
+    headers: BTreeMap<String, String>,
+}
+
+impl HttpSink {
+    pub fn new(url: String, headers: BTreeMap<String, String>) -> Self {
+        Self {
+            client: crate::proxy::http_client(),
+            url,
+            headers,
+        }
+    }
+}
+
+impl AnalyticsSink for HttpSink {
+    fn name(&self) -> String {
+        format!("http ({})", self.url)
+    }
+
+    fn render(&self, events: &[AnalyticsEvent]) -> String {
+        serde_json::to_string(events).unwrap_or_default()
+    }
+
+    fn send_batch<'a>(
+        &'a self,
+        events: &'a [AnalyticsEvent],
+    ) -> BoxFuture<'a, Result<(), SinkError>> {
+        Box::pin(async move {
+            let mut request = self
+                .client
+                .post(&self.url)
+                .header(CONTENT_TYPE, "application/json")
+                .body(self.render(events));
+            for (name, value) in &self.headers {
+                request = request.header(name.as_str(), value.as_str());
+            }
+            request.send().await?.error_for_status()?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn sample_event(name: &str) -> AnalyticsEvent {
+        AnalyticsEvent {
+            name: name.into(),
+            app_instance_id: json!("happy-prover-42"),
+            params: json!({"prover_id": "happy-prover-42", "k": 4}),
+        }
+    }
+
+    /// Tests that the JSON Lines sink appends exactly one parseable line per event
+    #[tokio::test]
+    async fn test_jsonl_sink_appends_lines() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("analytics").join("events.jsonl");
+        let sink = JsonlSink::new(path.to_str().unwrap());
+
+        sink.send_batch(&[sample_event("connect")]).await.unwrap();
+        sink.send_batch(&[sample_event("proof")]).await.unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).expect("Each line should be valid JSON"))
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["name"], "connect");
+        assert_eq!(lines[1]["params"]["k"], 4);
+    }
+
+    /// Tests that the HTTP sink posts the rendered batch with the configured headers
+    #[tokio::test]
+    async fn test_http_sink_posts_event() {
+        use tokio::io::AsyncReadExt;
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/collect", listener.local_addr().unwrap());
+
+        // Minimal collector that captures one request and answers 204
+        let server_handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request);
+                if let Some(header_end) = text.find("\r\n\r\n") {
+                    let content_length = text[..header_end]
+                        .lines()
+                        .find_map(|line| {
+                            line.to_lowercase()
+                                .strip_prefix("content-length:")
+                                .map(|v| v.trim().parse::<usize>().unwrap())
+                        })
+                        .unwrap_or(0);
+                    if request.len() >= header_end + 4 + content_length {
+                        break;
+                    }
+                }
+            }
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\ncontent-length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let headers = BTreeMap::from([("x-collector-token".to_string(), "secret".to_string())]);
+        let sink = HttpSink::new(url, headers);
+        sink.send_batch(&[sample_event("register"), sample_event("proof")])
+            .await
+            .unwrap();
+
+        let request = server_handle.await.unwrap();
+        assert!(request.starts_with("POST /collect"));
+        assert!(request.to_lowercase().contains("x-collector-token: secret"));
+        let body = &request[request.find("\r\n\r\n").unwrap() + 4..];
+        let events: Vec<serde_json::Value> = serde_json::from_str(body).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["name"], "register");
+    }
+
+    /// Tests that Firebase requests are split per app instance and capped at 25 events
+    #[test]
+    fn test_firebase_request_bodies() {
+        let mut events: Vec<AnalyticsEvent> = (0..30).map(|_| sample_event("proof")).collect();
+        let mut other = sample_event("connect");
+        other.app_instance_id = json!("other-prover-7");
+        events.push(other);
+
+        let bodies = FirebaseSink::request_bodies(&events);
+        assert_eq!(bodies.len(), 3);
+        let last: serde_json::Value = serde_json::from_str(&bodies[2]).unwrap();
+        assert_eq!(last[0]["app_instance_id"], "other-prover-7");
+        assert_eq!(last[0]["events"].as_array().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,312 @@
+//! Checkpointing of in-progress proofs
+//!
+//! Proving a long step range can take hours, and all of that work used to be lost when the
+//! process died (crash, reboot, restart after an update). The prover now periodically writes
+//! the partial proof, its position in the trace and the job metadata to the state directory,
+//! and on startup offers to resume the unfinished job.
+
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Bumped whenever the layout of [`ProofCheckpoint`] changes, so stale files are discarded
+pub const CHECKPOINT_FORMAT_VERSION: u32 = 2;
+
+/// The version of the CLI, recorded in the checkpoints it writes
+pub const CLI_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// What to do with an unfinished job found on startup
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum ResumeMode {
+    /// Ask interactively (resumes without asking when stdin is not a terminal)
+    Ask,
+    /// Continue proving the unfinished job locally
+    Resume,
+    /// Report the unfinished job's progress to the orchestrator and start a new job
+    Report,
+    /// Throw the unfinished job away
+    Discard,
+}
+
+/// Everything needed to pick up a proof where it was left off
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProofCheckpoint {
+    pub format_version: u32,
+    /// The version of the CLI that wrote the checkpoint. Another version may generate a
+    /// different trace, so it can only report the progress.
+    pub cli_version: String,
+    pub prover_id: String,
+    pub program_name: String,
+    /// The program input, so the trace can be regenerated deterministically
+    pub input: Vec<u8>,
+    pub k: i32,
+    pub total_steps: usize,
+    pub start: usize,
+    pub end: usize,
+    /// The next step of the trace that still has to be proven
+    pub next_step: usize,
+    pub steps_proven: i32,
+    /// Progress that had not been reported to the orchestrator yet
+    pub queued_steps_proven: i32,
+    pub queued_proof_duration_millis: i32,
+    /// Time spent proving the job so far, across restarts
+    #[serde(default)]
+    pub proving_duration_millis: u64,
+    /// Compressed serialization of the partial proof, base64 encoded
+    pub proof: String,
+    /// Unix timestamp (in seconds) at which the checkpoint was written
+    pub saved_at: u64,
+}
+
+impl ProofCheckpoint {
+    pub fn proof_bytes(&self) -> Result<Vec<u8>, base64::DecodeError> {
+        BASE64_STANDARD.decode(&self.proof)
+    }
+
+    pub fn set_proof_bytes(&mut self, bytes: &[u8]) {
+        self.proof = BASE64_STANDARD.encode(bytes);
+    }
+
+    /// Number of steps left to prove in this job
+    pub fn remaining_steps(&self) -> usize {
+        self.end.saturating_sub(self.next_step)
+    }
+
+    /// Why this CLI can't resume the proof with the given `k`, if it can't
+    pub fn resume_conflict(&self, k: i32) -> Option<String> {
+        if self.cli_version != CLI_VERSION {
+            return Some(format!("it was started by version {}", self.cli_version));
+        }
+        if self.k != k {
+            return Some(format!("it was started with k = {}", self.k));
+        }
+        None
+    }
+
+    /// Whether the proof continues the job from `start` to `end` of a trace of `total_steps`
+    pub fn fits_trace(&self, total_steps: usize, start: usize, end: usize) -> bool {
+        self.total_steps == total_steps
+            && self.start == start
+            && self.end == end
+            && (start..end).contains(&self.next_step)
+    }
+}
+
+/// Location of the checkpoint for a given run, e.g. `~/.local/state/nexus/runs/1/checkpoint.json`
+pub fn checkpoint_path(run_id: &str) -> Option<PathBuf> {
+    Some(
+        crate::state::current()?
+            .run_dir(run_id)
+            .join("checkpoint.json"),
+    )
+}
+
+/// Write the checkpoint atomically (temporary file + rename) so a crash while saving
+/// never leaves a truncated checkpoint behind
+pub fn save_checkpoint(
+    path: &Path,
+    checkpoint: &ProofCheckpoint,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut checkpoint = checkpoint.clone();
+    checkpoint.saved_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    let tmp_path = path.with_extension("json.tmp");
+    fs::write(&tmp_path, serde_json::to_vec(&checkpoint)?)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Load a checkpoint if one exists. Checkpoints written by an incompatible version of the
+/// CLI are treated as missing.
+pub fn load_checkpoint(path: &Path) -> Result<Option<ProofCheckpoint>, Box<dyn std::error::Error>> {
+    let content = match fs::read(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let checkpoint: ProofCheckpoint = serde_json::from_slice(&content)?;
+    if checkpoint.format_version != CHECKPOINT_FORMAT_VERSION {
+        return Ok(None);
+    }
+
+    Ok(Some(checkpoint))
+}
+
+/// Forget the progress a checkpoint had not reported yet, once it has been handed over for
+/// reporting, so that resuming the checkpoint doesn't report it again
+pub fn clear_queued_progress(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    match load_checkpoint(path)? {
+        Some(mut checkpoint)
+            if checkpoint.queued_steps_proven != 0
+                || checkpoint.queued_proof_duration_millis != 0 =>
+        {
+            checkpoint.queued_steps_proven = 0;
+            checkpoint.queued_proof_duration_millis = 0;
+            save_checkpoint(path, &checkpoint)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Remove the checkpoint once its job is finished (or discarded)
+pub fn remove_checkpoint(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            eprintln!(
+                "Failed to remove proof checkpoint {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Decide what to do with an unfinished job, prompting the user if needed
+pub fn resolve_resume_mode(mode: ResumeMode, checkpoint: &ProofCheckpoint) -> ResumeMode {
+    use std::io::{BufRead, IsTerminal, Write};
+
+    if mode != ResumeMode::Ask {
+        return mode;
+    }
+    if !std::io::stdin().is_terminal() {
+        return ResumeMode::Resume;
+    }
+
+    println!(
+        "Found an unfinished proof of {} ({} of {} steps left).",
+        checkpoint.program_name,
+        checkpoint.remaining_steps(),
+        checkpoint.end - checkpoint.start
+    );
+    loop {
+        print!("[r]esume it, [s]end its progress to the orchestrator and start over, or [d]iscard it? (R/s/d) ");
+        let _ = std::io::stdout().flush();
+
+        let mut answer = String::new();
+        if std::io::stdin().lock().read_line(&mut answer).is_err() {
+            return ResumeMode::Resume;
+        }
+        match answer.trim().to_lowercase().as_str() {
+            "" | "r" | "resume" => return ResumeMode::Resume,
+            "s" | "send" => return ResumeMode::Report,
+            "d" | "discard" => return ResumeMode::Discard,
+            _ => println!("Please answer r, s or d."),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn sample_checkpoint() -> ProofCheckpoint {
+        let mut checkpoint = ProofCheckpoint {
+            format_version: CHECKPOINT_FORMAT_VERSION,
+            cli_version: CLI_VERSION.into(),
+            prover_id: "happy-prover-42".into(),
+            program_name: "fast-fib".into(),
+            input: vec![5, 17, 42],
+            k: 4,
+            total_steps: 120,
+            start: 0,
+            end: 10,
+            next_step: 6,
+            steps_proven: 6,
+            queued_steps_proven: 21,
+            queued_proof_duration_millis: 12_000,
+            proving_duration_millis: 95_000,
+            proof: String::new(),
+            saved_at: 0,
+        };
+        checkpoint.set_proof_bytes(&[1, 2, 3, 4]);
+        checkpoint
+    }
+
+    /// Tests that a saved checkpoint can be loaded back unchanged
+    #[test]
+    fn test_checkpoint_roundtrip() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("checkpoints").join("1.json");
+        let checkpoint = sample_checkpoint();
+
+        save_checkpoint(&path, &checkpoint).expect("Should save checkpoint");
+        let loaded = load_checkpoint(&path)
+            .expect("Should load checkpoint")
+            .expect("Checkpoint should exist");
+
+        assert_eq!(loaded.program_name, checkpoint.program_name);
+        assert_eq!(loaded.next_step, 6);
+        assert_eq!(loaded.remaining_steps(), 4);
+        assert_eq!(loaded.proof_bytes().unwrap(), vec![1, 2, 3, 4]);
+        assert!(loaded.saved_at > 0, "Save time should be recorded");
+
+        remove_checkpoint(&path);
+        assert!(load_checkpoint(&path).unwrap().is_none());
+    }
+
+    /// Tests that the queued progress is cleared, and the rest of the checkpoint kept
+    #[test]
+    fn test_clear_queued_progress() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("1.json");
+        clear_queued_progress(&path).expect("A missing checkpoint is left missing");
+        assert!(!path.exists());
+
+        save_checkpoint(&path, &sample_checkpoint()).unwrap();
+        clear_queued_progress(&path).unwrap();
+        let cleared = load_checkpoint(&path).unwrap().unwrap();
+        assert_eq!(cleared.queued_steps_proven, 0);
+        assert_eq!(cleared.queued_proof_duration_millis, 0);
+        assert_eq!(cleared.next_step, 6);
+        assert_eq!(cleared.proof_bytes().unwrap(), vec![1, 2, 3, 4]);
+    }
+
+    /// Tests that only proofs started by this version, with the same trace, can be resumed
+    #[test]
+    fn test_resume_conflicts() {
+        let checkpoint = sample_checkpoint();
+        assert_eq!(checkpoint.resume_conflict(4), None);
+        assert!(checkpoint.resume_conflict(5).is_some());
+        let older = ProofCheckpoint {
+            cli_version: "0.3.5".into(),
+            ..sample_checkpoint()
+        };
+        assert_eq!(
+            older.resume_conflict(4),
+            Some("it was started by version 0.3.5".into())
+        );
+
+        assert!(checkpoint.fits_trace(120, 0, 10));
+        assert!(!checkpoint.fits_trace(121, 0, 10));
+        assert!(!checkpoint.fits_trace(120, 0, 6));
+        let finished = ProofCheckpoint {
+            next_step: 10,
+            ..sample_checkpoint()
+        };
+        assert!(!finished.fits_trace(120, 0, 10));
+    }
+
+    /// Tests that checkpoints from an incompatible format are ignored
+    #[test]
+    fn test_incompatible_checkpoint_is_ignored() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("1.json");
+        let mut checkpoint = sample_checkpoint();
+        checkpoint.format_version = CHECKPOINT_FORMAT_VERSION + 1;
+
+        save_checkpoint(&path, &checkpoint).unwrap();
+        assert!(load_checkpoint(&path).unwrap().is_none());
+    }
+}
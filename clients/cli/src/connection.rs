@@ -1,113 +1,446 @@
-use crate::analytics::track;
+use crate::analytics::{track, Event};
+use crate::delivery::Outbox;
+use crate::keepalive::Keepalive;
+use crate::metrics;
+use crate::protocol::{self, Protocol, ProtocolMode};
+use crate::proxy::ProxySettings;
+use crate::tls::TlsSettings;
+use crate::transport::{Connector, Transport, TransportError};
+use crate::utils::retry::{Backoff, RetryPolicy};
+use crate::utils::updater::release;
 use colored::Colorize;
-use serde_json::json;
 use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::client::Request;
+use tokio_tungstenite::tungstenite::http::header::{AUTHORIZATION, USER_AGENT};
+use tokio_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::Error as WsError;
 use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 
+/// Close code of an orchestrator that no longer supports this version of the CLI, like HTTP's 426
+/// Upgrade Required
+pub const CLOSE_CLIENT_TOO_OLD: u16 = 4426;
+
+/// The protocol versions the client offers in the handshake, and the one the orchestrator picked
+/// in its response
+pub const PROTOCOL_VERSION_HEADER: &str = "x-nexus-protocol-version";
+pub const PROVER_ID_HEADER: &str = "x-nexus-prover-id";
+
+/// How connections to the orchestrator are made
+#[derive(Clone)]
+pub struct ConnectionSettings {
+    pub tls: TlsSettings,
+    pub proxy: ProxySettings,
+    /// Sent in the handshake, so the orchestrator knows the prover before its first message
+    pub prover_id: Option<String>,
+    /// A bearer token for orchestrators that require authentication
+    pub auth_token: Option<String>,
+    pub protocol: ProtocolMode,
+}
+
+/// e.g. "nexus-network/0.4.0 (x86_64-unknown-linux-gnu)"
+pub fn user_agent() -> String {
+    format!(
+        "{}/{} ({})",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        release::TARGET
+    )
+}
+
+/// The handshake request, identifying the client
+fn handshake_request(
+    ws_addr: &str,
+    settings: &ConnectionSettings,
+) -> Result<Request, TransportError> {
+    let mut request = ws_addr.into_client_request()?;
+    let headers = request.headers_mut();
+    headers.insert(USER_AGENT, HeaderValue::from_str(&user_agent())?);
+    headers.insert(
+        PROTOCOL_VERSION_HEADER,
+        HeaderValue::from_str(&settings.protocol.offer())?,
+    );
+    if let Some(prover_id) = &settings.prover_id {
+        headers.insert(PROVER_ID_HEADER, HeaderValue::from_str(prover_id)?);
+    }
+    if let Some(token) = &settings.auth_token {
+        let mut value = HeaderValue::from_str(&format!("Bearer {}", token))
+            .map_err(|_| "The auth token contains invalid characters")?;
+        value.set_sensitive(true);
+        headers.insert(AUTHORIZATION, value);
+    }
+    Ok(request)
+}
+
+/// Explain why the orchestrator refused the handshake
+fn rejection_error(
+    status: StatusCode,
+    body: Option<&[u8]>,
+    settings: &ConnectionSettings,
+) -> TransportError {
+    let reason = body
+        .map(|body| String::from_utf8_lossy(body).trim().to_string())
+        .filter(|body| !body.is_empty())
+        .map_or(String::new(), |body| format!(": {}", body));
+    match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => format!(
+            "The orchestrator rejected the credentials ({}){}. Check connection.auth_token in the \
+             configuration file.",
+            status, reason
+        ),
+        StatusCode::UPGRADE_REQUIRED => format!(
+            "The orchestrator no longer supports version {} of the CLI (protocol versions {}){}. \
+             Update the CLI, or run it with --auto-update to stay up to date.",
+            env!("CARGO_PKG_VERSION"),
+            settings.protocol.offer(),
+            reason
+        ),
+        _ => format!(
+            "The orchestrator refused the connection ({}){}",
+            status, reason
+        ),
+    }
+    .into()
+}
+
+/// Connect, and find out which protocol the orchestrator picked
 pub async fn connect_to_orchestrator(
     ws_addr: &str,
-) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, Box<dyn std::error::Error + Send + Sync>> {
-    let (client, _) = tokio_tungstenite::connect_async(ws_addr)
-        .await
-        .map_err(|e| {
-            eprintln!("Failed to connect to orchestrator at {}: {}", ws_addr, e);
-            e
-        })?;
+    settings: &ConnectionSettings,
+) -> Result<(WebSocketStream<MaybeTlsStream<TcpStream>>, Protocol), TransportError> {
+    let result = async {
+        let request = handshake_request(ws_addr, settings)?;
+        let uri = request.uri();
+        let secure = match uri.scheme_str() {
+            Some("wss") => true,
+            Some("ws") => false,
+            _ => return Err(format!("Unsupported orchestrator URL {}", ws_addr).into()),
+        };
+        // IPv6 addresses are in brackets
+        let host = uri
+            .host()
+            .ok_or_else(|| format!("No host in {}", ws_addr))?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+        let port = uri.port_u16().unwrap_or(if secure { 443 } else { 80 });
+
+        let stream = settings.proxy.connect(&host, port, secure).await?;
+        let stream = if secure {
+            MaybeTlsStream::NativeTls(settings.tls.connect(&host, stream).await?)
+        } else {
+            MaybeTlsStream::Plain(stream)
+        };
+        let (client, response) = tokio_tungstenite::client_async(request, stream)
+            .await
+            .map_err(|e| match e {
+                WsError::Http(response) => {
+                    rejection_error(response.status(), response.body().as_deref(), settings)
+                }
+                e => e.into(),
+            })?;
+        let picked = response
+            .headers()
+            .get(PROTOCOL_VERSION_HEADER)
+            .map(|version| version.to_str())
+            .transpose()?;
+        let protocol = settings.protocol.negotiate(picked)?;
+        Ok::<_, TransportError>((client, protocol))
+    }
+    .await;
 
+    result.map_err(|e| {
+        eprintln!("Failed to connect to orchestrator at {}: {}", ws_addr, e);
+        e
+    })
+}
+
+/// Connect, and introduce the prover if the orchestrator speaks the streaming protocol
+async fn connect_and_register(
+    connector: &dyn Connector,
+    prover_id: &str,
+) -> Result<Box<dyn Transport>, TransportError> {
+    let mut client = connector.connect().await?;
+    if client.protocol() == Protocol::Streaming {
+        client.send(protocol::registration(prover_id)).await?;
+    }
     Ok(client)
 }
 
-pub async fn connect_to_orchestrator_with_infinite_retry(
-    ws_addr: &str,
+/// Connect, retrying with the backoff of `backoff` until it gives up. Fails right away while the
+/// backoff's circuit is open.
+pub async fn connect_to_orchestrator_with_retry(
+    connector: &dyn Connector,
     prover_id: &str,
-) -> WebSocketStream<MaybeTlsStream<TcpStream>> {
-    let mut attempt = 1;
+    backoff: &mut Backoff,
+) -> Result<Box<dyn Transport>, TransportError> {
+    if let Some(open_for) = backoff.open_for() {
+        return Err(format!(
+            "Not reconnecting to the orchestrator for another {} seconds",
+            open_for.as_secs()
+        )
+        .into());
+    }
 
     loop {
-        match connect_to_orchestrator(ws_addr).await {
+        match connect_and_register(connector, prover_id).await {
             Ok(client) => {
-                println!("\t✓ Connected to Nexus Network.");
-
+                backoff.succeeded();
+                metrics::increment(
+                    "nexus_orchestrator_connections_total",
+                    &[("protocol", client.protocol().name())],
+                );
                 track(
-                    "connected".into(),
-                    "Connected.".into(),
-                    ws_addr,
-                    json!({"prover_id": prover_id}),
+                    Event::Connected {
+                        prover_id: prover_id.to_string(),
+                    },
+                    connector.address(),
                     false,
                 );
-                return client;
+                println!(
+                    "{} (protocol version {}, {})\n",
+                    "✓ Success! Connected to Nexus Network.".green(),
+                    client.protocol().version(),
+                    client.protocol().name()
+                );
+                return Ok(client);
             }
-            Err(_e) => {
+            Err(e) => {
+                metrics::increment("nexus_orchestrator_connection_failures_total", &[]);
+                let Some(delay) = backoff.failed() else {
+                    // Only limited policies give up
+                    let attempts = backoff.policy().max_attempts.unwrap_or_default();
+                    return Err(
+                        format!("Failed to connect after {} attempts: {}", attempts, e).into(),
+                    );
+                };
+
+                let attempt = backoff.failures();
+                let max_attempts = backoff
+                    .policy()
+                    .max_attempts
+                    .map_or(String::new(), |max_attempts| format!("/{}", max_attempts));
                 eprintln!(
-                    "Could not connect to orchestrator (attempt {}). Retrying in {} seconds...",
+                    "Could not connect to orchestrator (attempt {}{}). Retrying in {:.1} seconds...",
                     attempt,
-                    2u64.pow(attempt.min(6)),
+                    max_attempts,
+                    delay.as_secs_f64(),
                 );
-
-                tokio::time::sleep(tokio::time::Duration::from_secs(2u64.pow(attempt.min(6))))
-                    .await;
-
-                attempt += 1;
+                tokio::time::sleep(delay).await;
             }
         }
     }
 }
 
-pub async fn connect_to_orchestrator_with_limited_retry(
-    ws_addr: &str,
+/// Connect, retrying with the backoff of `policy` for as long as it takes
+pub async fn connect_to_orchestrator_with_infinite_retry(
+    connector: &dyn Connector,
     prover_id: &str,
-) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, Box<dyn std::error::Error + Send + Sync>> {
-    let max_attempts = 5;
-    let mut attempt = 1;
-
+    policy: &RetryPolicy,
+) -> Box<dyn Transport> {
+    let mut backoff = Backoff::new(RetryPolicy {
+        max_attempts: None,
+        ..policy.clone()
+    });
     loop {
-        if attempt >= max_attempts {
-            return Err(format!("Failed to connect after {} attempts", max_attempts).into());
+        // Unlimited attempts never give up, but the circuit may still be open
+        match connect_to_orchestrator_with_retry(connector, prover_id, &mut backoff).await {
+            Ok(client) => return client,
+            Err(_) => tokio::time::sleep(policy.max_delay).await,
         }
+    }
+}
 
-        match connect_to_orchestrator(ws_addr).await {
-            Ok(client) => {
-                track(
-                    "connected".into(),
-                    "Connected.".into(),
-                    ws_addr,
-                    json!({"prover_id": prover_id}),
-                    false,
-                );
-                println!("{}", "✓ Success! Connected to Nexus Network.\n".green());
-                return Ok(client);
-            }
-            Err(e) => {
-                if attempt >= max_attempts {
-                    return Err(format!(
-                        "Failed to connect after {} attempts: {}",
-                        max_attempts, e
-                    )
-                    .into());
-                }
+/// What the orchestrator asks for when it closes the connection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CloseAction {
+    /// Reconnect with the usual backoff, e.g. after a normal close or a restart
+    Reconnect,
+    /// Wait longer than usual before reconnecting: the orchestrator is overloaded
+    BackOff,
+    /// Update the CLI: the orchestrator no longer supports this version
+    Update,
+    /// Stop: reconnecting would be refused the same way, e.g. after a policy violation
+    Stop,
+}
 
-                eprintln!(
-                    "Could not connect to orchestrator (attempt {}/{}). Retrying in {} seconds...",
-                    attempt,
-                    max_attempts,
-                    2u64.pow(attempt.min(6)),
-                );
+impl CloseAction {
+    fn name(&self) -> &'static str {
+        match self {
+            CloseAction::Reconnect => "reconnect",
+            CloseAction::BackOff => "back_off",
+            CloseAction::Update => "update",
+            CloseAction::Stop => "stop",
+        }
+    }
+}
+
+/// A close frame from the orchestrator
+#[derive(Debug, Clone, PartialEq)]
+pub struct Closed {
+    /// None if the frame had no code
+    pub code: Option<u16>,
+    pub reason: String,
+    pub action: CloseAction,
+}
+
+impl Closed {
+    pub fn from_frame(frame: Option<&CloseFrame>) -> Self {
+        let action = match frame.map(|frame| frame.code) {
+            Some(CloseCode::Policy) => CloseAction::Stop,
+            Some(CloseCode::Again) => CloseAction::BackOff,
+            Some(CloseCode::Library(CLOSE_CLIENT_TOO_OLD)) => CloseAction::Update,
+            _ => CloseAction::Reconnect,
+        };
+        Self {
+            code: frame.map(|frame| frame.code.into()),
+            reason: frame.map_or(String::new(), |frame| frame.reason.to_string()),
+            action,
+        }
+    }
+}
+
+impl std::fmt::Display for Closed {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "The orchestrator closed the connection")?;
+        match (self.code, self.reason.is_empty()) {
+            (Some(code), true) => write!(f, " (code {})", code),
+            (Some(code), false) => write!(f, " (code {}: {})", code, self.reason),
+            (None, _) => Ok(()),
+        }
+    }
+}
+
+/// Why a connection stopped working
+#[derive(Debug, Clone, PartialEq)]
+pub enum Broken {
+    Closed(Closed),
+    /// The kind of failure, for the metrics, and the error
+    Failed(&'static str, String),
+}
+
+/// Send the queued progress updates to the orchestrator, unless the keepalive found the connection
+/// dead, after handling the acknowledgements that have arrived. A dead connection is replaced (if
+/// reconnecting succeeds), and the updates are left for the next time. Returns whether the
+/// updates were sent, or the close frame if the orchestrator closed the connection asking to stop
+/// or to update.
+pub async fn send_progress(
+    client: &mut Keepalive,
+    connector: &dyn Connector,
+    prover_id: &str,
+    backoff: &mut Backoff,
+    outbox: &mut Outbox,
+) -> Result<bool, Closed> {
+    while let Some(message) = client.try_receive() {
+        match client.protocol() {
+            Protocol::Legacy => outbox.acknowledge(&message),
+            Protocol::Streaming => protocol::handle_streaming_message(&message),
+        }
+    }
+
+    // Move back to a preferred orchestrator once it is reachable again
+    let connecting = client
+        .dead()
+        .is_none()
+        .then(|| connector.connect_preferred())
+        .flatten();
+    if let Some(connecting) = connecting {
+        if let Ok(new_client) = connecting.await {
+            let _ = client
+                .close(Some(CloseFrame {
+                    code: CloseCode::Normal,
+                    reason: "Returning to a preferred orchestrator".into(),
+                }))
+                .await;
+            *client = Keepalive::start(new_client, client.settings());
+            outbox.connected();
+        }
+    }
 
-                tokio::time::sleep(tokio::time::Duration::from_secs(2u64.pow(attempt.min(6))))
-                    .await;
+    let broken = match client.dead() {
+        Some(broken) => broken,
+        None => match outbox.send(client).await {
+            Ok(()) => return Ok(true),
+            // The keepalive task knows best why the connection broke
+            Err(e) => client
+                .wait_until_dead()
+                .await
+                .unwrap_or(Broken::Failed("send", e.to_string())),
+        },
+    };
 
-                attempt += 1;
+    match broken {
+        Broken::Closed(closed) => {
+            metrics::increment(
+                "nexus_orchestrator_closes_total",
+                &[
+                    (
+                        "code",
+                        &closed.code.map_or("none".into(), |code| code.to_string()),
+                    ),
+                    ("action", closed.action.name()),
+                ],
+            );
+            match closed.action {
+                CloseAction::Reconnect => eprintln!("{}. Reconnecting...", closed),
+                CloseAction::BackOff => {
+                    // Longer than any usual delay, with jitter so not everyone returns at once
+                    let policy = backoff.policy();
+                    let delay = policy.max_delay + policy.delay(u32::MAX, &mut rand::thread_rng());
+                    eprintln!(
+                        "{}. It is overloaded, reconnecting in {} seconds.",
+                        closed,
+                        delay.as_secs()
+                    );
+                    backoff.defer(delay);
+                }
+                CloseAction::Update | CloseAction::Stop => {
+                    eprintln!("{}.", closed);
+                    return Err(closed);
+                }
             }
         }
+        Broken::Failed(kind, e) => {
+            metrics::increment("nexus_orchestrator_errors_total", &[("kind", kind)]);
+            eprintln!("Lost the connection to the orchestrator: {}", e);
+        }
+    }
+
+    reconnect(client, connector, prover_id, backoff, outbox).await;
+    Ok(false)
+}
+
+/// Replace a dead connection, retrying with the backoff of `backoff`
+pub async fn reconnect(
+    client: &mut Keepalive,
+    connector: &dyn Connector,
+    prover_id: &str,
+    backoff: &mut Backoff,
+    outbox: &mut Outbox,
+) {
+    // Continue using the existing client and try again next update if reconnecting fails
+    if let Ok(new_client) = connect_to_orchestrator_with_retry(connector, prover_id, backoff).await
+    {
+        *client = Keepalive::start(new_client, client.settings());
+        outbox.connected();
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::connect_to_orchestrator; // Just the function we're testing
+    use super::*;
+    use crate::generated::pb::{ClientProgramProofRequest, Progress};
+    use crate::keepalive::KeepaliveSettings;
+    use crate::transport::memory;
     use futures::{SinkExt, StreamExt};
+    use prost::Message as _;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
     use tokio::net::TcpListener;
-    use tokio_tungstenite::tungstenite::Message;
+    use tokio_tungstenite::tungstenite::handshake::server::Response;
+    use tokio_tungstenite::tungstenite::protocol::Message;
 
     #[tokio::test]
     async fn test_basic_connection() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
@@ -121,14 +454,23 @@ mod tests {
             let (stream, _) = listener.accept().await?;
             let mut ws_stream = tokio_tungstenite::accept_async(stream).await?;
 
-            // SinkExt::send is available because of the import above
-            ws_stream.send(Message::Text("test".into())).await?;
+            // Transport::send would work too, so name the SinkExt one
+            SinkExt::send(&mut ws_stream, Message::Text("test".into())).await?;
 
             Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
         });
 
         // Connect client
-        let mut client = connect_to_orchestrator(&ws_addr).await?;
+        let settings = ConnectionSettings {
+            tls: TlsSettings::from_config(&Default::default()).unwrap(),
+            proxy: ProxySettings::default(),
+            prover_id: None,
+            auth_token: None,
+            protocol: ProtocolMode::Auto,
+        };
+        let (mut client, protocol) = connect_to_orchestrator(&ws_addr, &settings).await?;
+        // The mock server predates the negotiation
+        assert_eq!(protocol, Protocol::Legacy);
 
         // StreamExt::next is available for receiving
         if let Some(msg) = client.next().await {
@@ -140,4 +482,338 @@ mod tests {
         server_handle.await??;
         Ok(())
     }
+
+    fn backoff(max_attempts: Option<u32>) -> Backoff {
+        Backoff::new(RetryPolicy {
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(10),
+            max_attempts,
+            reset_after: Duration::from_secs(60),
+            circuit_open_for: Duration::from_secs(600),
+        })
+    }
+
+    /// Tests that reconnecting makes exactly the configured number of attempts, then fails fast
+    #[tokio::test]
+    async fn test_retry_makes_max_attempts() {
+        let (connector, _listener) = memory::connector();
+        connector.refuse_next(u32::MAX);
+
+        let mut backoff = backoff(Some(5));
+        let result =
+            connect_to_orchestrator_with_retry(&connector, "test-prover", &mut backoff).await;
+        assert!(result.is_err());
+        assert_eq!(connector.attempts(), 5);
+
+        // The circuit is open now
+        assert!(backoff.open_for().is_some());
+        let result =
+            connect_to_orchestrator_with_retry(&connector, "test-prover", &mut backoff).await;
+        assert!(result.is_err());
+        assert_eq!(connector.attempts(), 5);
+    }
+
+    /// A connection to the in-memory orchestrator, pinging only once at the start
+    async fn connect(connector: &memory::MemoryConnector) -> Keepalive {
+        let settings = KeepaliveSettings {
+            interval: Duration::from_secs(600),
+            max_missed_pongs: 3,
+        };
+        Keepalive::start(connector.connect().await.unwrap(), settings)
+    }
+
+    /// An outbox with one update
+    fn outbox(steps_proven: i32) -> Outbox {
+        let mut outbox = Outbox::default();
+        outbox.push(
+            ClientProgramProofRequest {
+                steps_proven,
+                ..Default::default()
+            },
+            Progress {
+                steps_proven,
+                ..Default::default()
+            },
+        );
+        outbox
+    }
+
+    /// The next binary message, skipping the keepalive's pings
+    async fn receive_binary(orchestrator: &mut memory::MemoryTransport) -> Option<Vec<u8>> {
+        loop {
+            match orchestrator.receive().await?.ok()? {
+                Message::Binary(data) => return Some(data),
+                Message::Ping(_) => continue,
+                _ => return None,
+            }
+        }
+    }
+
+    /// Tests that progress is sent over a live connection
+    #[tokio::test]
+    async fn test_send_progress() {
+        let (connector, mut listener) = memory::connector();
+        let mut client = connect(&connector).await;
+        let mut orchestrator = listener.accept().await.unwrap();
+
+        let mut outbox = outbox(3);
+        let sent = send_progress(
+            &mut client,
+            &connector,
+            "test-prover",
+            &mut backoff(Some(5)),
+            &mut outbox,
+        )
+        .await
+        .unwrap();
+        assert!(sent);
+        let data = receive_binary(&mut orchestrator).await.unwrap();
+        let update = ClientProgramProofRequest::decode(data.as_slice()).unwrap();
+        assert_eq!(update.steps_proven, 3);
+        assert!(!update.message_id.is_empty());
+        assert_eq!(connector.attempts(), 1);
+    }
+
+    /// Tests that a dead connection is replaced, keeping the progress
+    #[tokio::test]
+    async fn test_send_progress_reconnects_dead_connection() -> Result<(), TransportError> {
+        let (connector, mut listener) = memory::connector();
+        let mut client = connect(&connector).await;
+        // An orchestrator that has gone away
+        drop(listener.accept().await);
+
+        // Reconnecting takes a few attempts
+        connector.refuse_next(2);
+        let mut outbox = outbox(3);
+        let sent = send_progress(
+            &mut client,
+            &connector,
+            "test-prover",
+            &mut backoff(Some(5)),
+            &mut outbox,
+        )
+        .await
+        .unwrap();
+        assert!(!sent);
+        assert_eq!(outbox.unacknowledged(), 1);
+        assert_eq!(connector.attempts(), 4);
+
+        // The next update goes over the new connection
+        let mut orchestrator = listener.try_accept().unwrap();
+        client.send(Message::Binary(vec![4])).await?;
+        assert_eq!(receive_binary(&mut orchestrator).await, Some(vec![4]));
+        Ok(())
+    }
+
+    /// An orchestrator closing the connection with `code`, and then sending progress
+    async fn send_progress_until_closed(
+        code: CloseCode,
+        backoff: &mut Backoff,
+    ) -> (Result<bool, Closed>, memory::MemoryConnector) {
+        let (connector, mut listener) = memory::connector();
+        let mut client = connect(&connector).await;
+        let mut orchestrator = listener.accept().await.unwrap();
+        orchestrator
+            .close(Some(CloseFrame {
+                code,
+                reason: "because".into(),
+            }))
+            .await
+            .unwrap();
+        client.wait_until_dead().await;
+
+        // The listener stays around for reconnections
+        let result = send_progress(
+            &mut client,
+            &connector,
+            "test-prover",
+            backoff,
+            &mut outbox(1),
+        )
+        .await;
+        (result, connector)
+    }
+
+    /// Tests how close codes are interpreted
+    #[test]
+    fn test_close_actions() {
+        let action = |code| {
+            let frame = CloseFrame {
+                code,
+                reason: "".into(),
+            };
+            Closed::from_frame(Some(&frame)).action
+        };
+        assert_eq!(action(CloseCode::Normal), CloseAction::Reconnect);
+        assert_eq!(action(CloseCode::Restart), CloseAction::Reconnect);
+        assert_eq!(action(CloseCode::Library(4000)), CloseAction::Reconnect);
+        assert_eq!(action(CloseCode::Policy), CloseAction::Stop);
+        assert_eq!(action(CloseCode::Again), CloseAction::BackOff);
+        assert_eq!(
+            action(CloseCode::from(CLOSE_CLIENT_TOO_OLD)),
+            CloseAction::Update
+        );
+
+        let closed = Closed::from_frame(None);
+        assert_eq!(closed.action, CloseAction::Reconnect);
+        assert_eq!(closed.to_string(), "The orchestrator closed the connection");
+        let frame = CloseFrame {
+            code: CloseCode::Policy,
+            reason: "banned".into(),
+        };
+        assert_eq!(
+            Closed::from_frame(Some(&frame)).to_string(),
+            "The orchestrator closed the connection (code 1008: banned)"
+        );
+    }
+
+    /// Tests that a normal close reconnects, and that a policy violation or an outdated client
+    /// stops sending instead
+    #[tokio::test]
+    async fn test_send_progress_close_frames() {
+        let (result, connector) =
+            send_progress_until_closed(CloseCode::Normal, &mut backoff(Some(5))).await;
+        assert_eq!(result, Ok(false));
+        assert_eq!(connector.attempts(), 2);
+
+        let (result, connector) =
+            send_progress_until_closed(CloseCode::Policy, &mut backoff(Some(5))).await;
+        let closed = result.unwrap_err();
+        assert_eq!(closed.code, Some(1008));
+        assert_eq!(closed.reason, "because");
+        assert_eq!(closed.action, CloseAction::Stop);
+        assert_eq!(connector.attempts(), 1);
+
+        let (result, _) = send_progress_until_closed(
+            CloseCode::from(CLOSE_CLIENT_TOO_OLD),
+            &mut backoff(Some(5)),
+        )
+        .await;
+        assert_eq!(result.unwrap_err().action, CloseAction::Update);
+    }
+
+    /// Tests that an overloaded orchestrator is left alone for longer than the usual backoff
+    #[tokio::test]
+    async fn test_send_progress_backs_off_when_overloaded() {
+        let mut backoff = backoff(Some(5));
+        let (result, connector) = send_progress_until_closed(CloseCode::Again, &mut backoff).await;
+        assert_eq!(result, Ok(false));
+        // Reconnecting waits for the circuit to close
+        assert_eq!(connector.attempts(), 1);
+        assert!(backoff.open_for().unwrap() > backoff.policy().max_delay);
+    }
+
+    fn settings() -> ConnectionSettings {
+        ConnectionSettings {
+            tls: TlsSettings::from_config(&Default::default()).unwrap(),
+            proxy: ProxySettings::default(),
+            prover_id: Some("happy-prover-42".into()),
+            auth_token: Some("secret".into()),
+            protocol: ProtocolMode::Auto,
+        }
+    }
+
+    /// Tests that the handshake identifies the client, its prover and its protocol version
+    #[tokio::test]
+    async fn test_handshake_identifies_client() -> Result<(), TransportError> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let ws_addr = format!("ws://{}/prove", listener.local_addr()?);
+        let (headers_tx, headers_rx) = std::sync::mpsc::channel();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            // The callback's signature is up to tungstenite
+            #[allow(clippy::result_large_err)]
+            let callback = |request: &Request, response| {
+                headers_tx.send(request.headers().clone()).unwrap();
+                Ok(response)
+            };
+            let _ = tokio_tungstenite::accept_hdr_async(stream, callback).await;
+        });
+
+        connect_to_orchestrator(&ws_addr, &settings()).await?;
+        let headers = headers_rx.recv()?;
+        assert!(headers[USER_AGENT].to_str()?.starts_with(&format!(
+            "{}/{} (",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION")
+        )));
+        assert_eq!(headers[PROTOCOL_VERSION_HEADER], "2, 1");
+        assert_eq!(headers[PROVER_ID_HEADER], "happy-prover-42");
+        assert_eq!(headers[AUTHORIZATION], "Bearer secret");
+        Ok(())
+    }
+
+    /// Tests that the protocol the orchestrator picks is used, and that forcing a protocol the
+    /// orchestrator doesn't speak fails the handshake
+    #[tokio::test]
+    async fn test_handshake_negotiates_protocol() -> Result<(), TransportError> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let ws_addr = format!("ws://{}/prove", listener.local_addr()?);
+        tokio::spawn(async move {
+            for picked in [Some("2"), None] {
+                let (stream, _) = listener.accept().await.unwrap();
+                #[allow(clippy::result_large_err)]
+                let callback = |_: &Request, mut response: Response| {
+                    if let Some(picked) = picked {
+                        response
+                            .headers_mut()
+                            .insert(PROTOCOL_VERSION_HEADER, HeaderValue::from_static(picked));
+                    }
+                    Ok(response)
+                };
+                let _ = tokio_tungstenite::accept_hdr_async(stream, callback).await;
+            }
+        });
+
+        let (_, protocol) = connect_to_orchestrator(&ws_addr, &settings()).await?;
+        assert_eq!(protocol, Protocol::Streaming);
+
+        let settings = ConnectionSettings {
+            protocol: ProtocolMode::Streaming,
+            ..settings()
+        };
+        let error = connect_to_orchestrator(&ws_addr, &settings)
+            .await
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("only speaks the legacy protocol"));
+        Ok(())
+    }
+
+    /// Tests that HTTP rejections of the handshake are explained
+    #[tokio::test]
+    async fn test_handshake_rejections() -> Result<(), TransportError> {
+        for (response, expected) in [
+            (
+                "HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\n\r\n",
+                "Check connection.auth_token",
+            ),
+            (
+                "HTTP/1.1 426 Upgrade Required\r\nContent-Length: 0\r\n\r\n",
+                "--auto-update",
+            ),
+            (
+                "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n",
+                "503 Service Unavailable",
+            ),
+        ] {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let ws_addr = format!("ws://{}/prove", listener.local_addr()?);
+            tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    head.push(stream.read_u8().await.unwrap());
+                }
+                stream.write_all(response.as_bytes()).await.unwrap();
+            });
+
+            let error = connect_to_orchestrator(&ws_addr, &settings())
+                .await
+                .unwrap_err();
+            assert!(error.to_string().contains(expected), "{}", error);
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,268 @@
+//! Delivering progress updates to the orchestrator
+//!
+//! Every progress update gets a unique message ID and waits in the [`Outbox`] until the
+//! orchestrator acknowledges it with a `ClientProgramProofResponse` carrying the same ID. Updates
+//! that are still unacknowledged at the next progress update, e.g. because the connection broke,
+//! are sent again with the same ID, so that the orchestrator can ignore duplicates.
+//!
+//! Orchestrators that don't acknowledge updates get the old behavior: until the first
+//! acknowledgement arrives over a connection, an update counts as delivered as soon as it was
+//! sent. Whether acknowledgements arrive is found out again for every connection, since a
+//! reconnection or failover may land on a different orchestrator.
+//!
+//! Over the streaming protocol, there are no acknowledgements: only the latest progress of the
+//! current proof is sent, and it supersedes the updates queued before.
+
+use crate::generated::pb::{ClientProgramProofRequest, ClientProgramProofResponse, Progress};
+use crate::metrics;
+use crate::protocol::{self, Protocol};
+use crate::transport::{Transport, TransportError};
+use prost::Message as _;
+use std::collections::VecDeque;
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+// Beyond this, the oldest unacknowledged updates are given up on
+const MAX_UNACKNOWLEDGED: usize = 100;
+
+#[derive(Debug, Default)]
+pub struct Outbox {
+    // Oldest first
+    pending: VecDeque<Pending>,
+    // Whether the orchestrator has acknowledged any update over the current connection
+    acknowledges: bool,
+    // The progress of the current proof as of the latest update, for the streaming protocol
+    progress: Option<Progress>,
+}
+
+#[derive(Debug)]
+struct Pending {
+    request: ClientProgramProofRequest,
+    sent: bool,
+}
+
+impl Outbox {
+    /// Queue an update, giving it a new message ID. `progress` is the same update for the
+    /// streaming protocol.
+    pub fn push(&mut self, mut request: ClientProgramProofRequest, progress: Progress) {
+        self.progress = Some(progress);
+        request.message_id = uuid::Uuid::new_v4().to_string();
+        self.pending.push_back(Pending {
+            request,
+            sent: false,
+        });
+        if self.pending.len() > MAX_UNACKNOWLEDGED {
+            if let Some(dropped) = self.pending.pop_front() {
+                eprintln!(
+                    "Warning: giving up on progress update {}, which was never acknowledged",
+                    dropped.request.message_id
+                );
+                metrics::increment("nexus_progress_updates_total", &[("result", "dropped")]);
+            }
+        }
+    }
+
+    /// Forget whether the orchestrator acknowledges updates, once connected to a (possibly)
+    /// different one
+    pub fn connected(&mut self) {
+        self.acknowledges = false;
+    }
+
+    /// How many updates were queued but not acknowledged yet
+    pub fn unacknowledged(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Handle a message from the orchestrator, if it acknowledges an update
+    pub fn acknowledge(&mut self, message: &Message) {
+        let Message::Binary(data) = message else {
+            return;
+        };
+        let Ok(response) = ClientProgramProofResponse::decode(data.as_slice()) else {
+            return;
+        };
+        if response.message_id.is_empty() {
+            return;
+        }
+
+        self.acknowledges = true;
+        let before = self.pending.len();
+        self.pending
+            .retain(|pending| pending.request.message_id != response.message_id);
+        if self.pending.len() < before {
+            metrics::increment(
+                "nexus_progress_updates_total",
+                &[("result", "acknowledged")],
+            );
+        }
+    }
+
+    /// Send the queued updates, oldest first, including those that were sent before but not
+    /// acknowledged yet. Stops at the first failure, keeping the rest for next time.
+    pub async fn send(&mut self, client: &mut dyn Transport) -> Result<(), TransportError> {
+        if client.protocol() == Protocol::Streaming {
+            return self.stream(client).await;
+        }
+
+        let resent = self.pending.iter().filter(|pending| pending.sent).count();
+        if resent > 0 {
+            println!("\tSending {} unacknowledged updates again", resent);
+        }
+
+        for pending in self.pending.iter_mut() {
+            client
+                .send(Message::Binary(pending.request.encode_to_vec()))
+                .await?;
+            let result = if pending.sent { "resent" } else { "sent" };
+            metrics::increment("nexus_progress_updates_total", &[("result", result)]);
+            pending.sent = true;
+        }
+        if !self.acknowledges {
+            self.pending.clear();
+        }
+        self.progress = None;
+        Ok(())
+    }
+
+    async fn stream(&mut self, client: &mut dyn Transport) -> Result<(), TransportError> {
+        if let Some(progress) = self.progress {
+            client.send(protocol::progress(progress)).await?;
+            metrics::increment("nexus_progress_updates_total", &[("result", "sent")]);
+            self.progress = None;
+        }
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generated::pb::{prover_request, ProverRequest};
+    use crate::transport::memory;
+
+    fn push(outbox: &mut Outbox, steps_proven: i32) {
+        let request = ClientProgramProofRequest {
+            steps_proven,
+            ..Default::default()
+        };
+        let progress = Progress {
+            steps_proven,
+            ..Default::default()
+        };
+        outbox.push(request, progress);
+    }
+
+    fn acknowledgement(message_id: &str) -> Message {
+        let response = ClientProgramProofResponse {
+            message_id: message_id.to_string(),
+        };
+        Message::Binary(response.encode_to_vec())
+    }
+
+    fn received(orchestrator: &mut memory::MemoryTransport) -> Vec<ClientProgramProofRequest> {
+        std::iter::from_fn(|| orchestrator.try_receive())
+            .map(|message| ClientProgramProofRequest::decode(&message.into_data()[..]).unwrap())
+            .collect()
+    }
+
+    /// Tests that updates are sent again with the same ID until they are acknowledged
+    #[tokio::test]
+    async fn test_resend_until_acknowledged() -> Result<(), TransportError> {
+        let (mut client, mut orchestrator) = memory::pair();
+        let mut outbox = Outbox::default();
+
+        // Until the orchestrator acknowledges anything, sent updates count as delivered
+        push(&mut outbox, 1);
+        outbox.send(&mut client).await?;
+        let first = received(&mut orchestrator);
+        assert_eq!(outbox.unacknowledged(), 0);
+        outbox.acknowledge(&acknowledgement(&first[0].message_id));
+
+        push(&mut outbox, 2);
+        push(&mut outbox, 3);
+        outbox.send(&mut client).await?;
+        let sent = received(&mut orchestrator);
+        assert_eq!(sent.len(), 2);
+        assert_ne!(sent[0].message_id, sent[1].message_id);
+        assert_ne!(sent[0].message_id, first[0].message_id);
+
+        // Only the unacknowledged update is sent again, with its ID
+        outbox.acknowledge(&acknowledgement(&sent[0].message_id));
+        outbox.acknowledge(&Message::Text("unrelated".into()));
+        assert_eq!(outbox.unacknowledged(), 1);
+        outbox.send(&mut client).await?;
+        assert_eq!(received(&mut orchestrator), [sent[1].clone()]);
+
+        outbox.acknowledge(&acknowledgement(&sent[1].message_id));
+        assert_eq!(outbox.unacknowledged(), 0);
+        Ok(())
+    }
+
+    /// Tests that updates that could not be sent are kept
+    #[tokio::test]
+    async fn test_failed_send_keeps_updates() {
+        let (mut client, orchestrator) = memory::pair();
+        drop(orchestrator);
+        let mut outbox = Outbox::default();
+
+        push(&mut outbox, 1);
+        assert!(outbox.send(&mut client).await.is_err());
+        assert_eq!(outbox.unacknowledged(), 1);
+
+        for steps_proven in 0..MAX_UNACKNOWLEDGED as i32 {
+            push(&mut outbox, steps_proven);
+        }
+        assert_eq!(outbox.unacknowledged(), MAX_UNACKNOWLEDGED);
+    }
+
+    /// Tests that over the streaming protocol only the latest progress is sent
+    #[tokio::test]
+    async fn test_stream_latest_progress() -> Result<(), TransportError> {
+        let (mut client, mut orchestrator) = memory::pair();
+        client.set_protocol(Protocol::Streaming);
+        let mut outbox = Outbox::default();
+
+        push(&mut outbox, 1);
+        push(&mut outbox, 2);
+        outbox.send(&mut client).await?;
+        let sent: Vec<ProverRequest> = std::iter::from_fn(|| orchestrator.try_receive())
+            .map(|message| ProverRequest::decode(&message.into_data()[..]).unwrap())
+            .collect();
+        let [ProverRequest {
+            contents: Some(prover_request::Contents::Progress(progress)),
+        }] = sent.as_slice()
+        else {
+            panic!("Expected one progress update, got {:?}", sent);
+        };
+        assert_eq!(progress.steps_proven, 2);
+        assert_eq!(outbox.unacknowledged(), 0);
+
+        // Nothing new to report
+        outbox.send(&mut client).await?;
+        assert!(orchestrator.try_receive().is_none());
+        Ok(())
+    }
+
+    /// Tests that acknowledgements are expected only over the connection that sent them
+    #[tokio::test]
+    async fn test_acknowledgements_per_connection() -> Result<(), TransportError> {
+        let (mut client, mut orchestrator) = memory::pair();
+        let mut outbox = Outbox::default();
+        push(&mut outbox, 1);
+        outbox.send(&mut client).await?;
+        outbox.acknowledge(&acknowledgement(&received(&mut orchestrator)[0].message_id));
+
+        push(&mut outbox, 2);
+        outbox.send(&mut client).await?;
+        assert_eq!(outbox.unacknowledged(), 1);
+
+        // An orchestrator that doesn't acknowledge updates, after failing over
+        let (mut client, mut orchestrator) = memory::pair();
+        outbox.connected();
+        push(&mut outbox, 3);
+        outbox.send(&mut client).await?;
+        assert_eq!(received(&mut orchestrator).len(), 2);
+        assert_eq!(outbox.unacknowledged(), 0);
+        Ok(())
+    }
+}
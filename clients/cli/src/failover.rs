@@ -0,0 +1,394 @@
+//! Failing over between orchestrators
+//!
+//! The prover can be given several orchestrator endpoints in order of preference: as hostname
+//! arguments, in `connection.endpoints` in the configuration file, or in a discovery manifest
+//! that `connection.discovery` points to. A [`FailoverConnector`] connects to the first endpoint
+//! that works, trying the endpoints that recently failed last. Once it had to fail over, it
+//! periodically tries to return to the endpoints it prefers, so that a regional outage only moves
+//! provers away for as long as it lasts.
+
+use crate::config::{expand_home, ConnectionConfig, FailoverConfig};
+use crate::metrics;
+use crate::transport::{Connector, Transport, TransportError};
+use futures::future::BoxFuture;
+use parking_lot::Mutex;
+use reqwest::Url;
+use serde::Deserialize;
+use std::net::Ipv6Addr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_HOSTNAME: &str = "beta.orchestrator.nexus.xyz";
+
+/// A discovery manifest
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    endpoints: Vec<String>,
+}
+
+/// The websocket URL of an endpoint given as a hostname, a hostname and a port, or a URL. IPv6
+/// addresses are given bare, or in brackets with a port: `::1` or `[::1]:8080`.
+pub fn endpoint_url(
+    endpoint: &str,
+    default_port: u16,
+) -> Result<String, Box<dyn std::error::Error>> {
+    if endpoint.contains("://") {
+        let url =
+            Url::parse(endpoint).map_err(|e| format!("Invalid endpoint {}: {}", endpoint, e))?;
+        if !matches!(url.scheme(), "ws" | "wss") {
+            return Err(format!(
+                "Invalid endpoint {}: expected a ws:// or wss:// URL",
+                endpoint
+            )
+            .into());
+        }
+        return Ok(endpoint.to_string());
+    }
+
+    let parse_port = |port: &str| {
+        port.parse::<u16>()
+            .map_err(|_| format!("Invalid port in endpoint {}", endpoint))
+    };
+    let (host, port) = if let Some(bracketed) = endpoint.strip_prefix('[') {
+        let (address, rest) = bracketed
+            .split_once(']')
+            .ok_or_else(|| format!("Invalid endpoint {}: missing ]", endpoint))?;
+        address
+            .parse::<Ipv6Addr>()
+            .map_err(|_| format!("Invalid IPv6 address in endpoint {}", endpoint))?;
+        let port = match rest.strip_prefix(':') {
+            Some(port) => parse_port(port)?,
+            None if rest.is_empty() => default_port,
+            None => return Err(format!("Invalid endpoint {}", endpoint).into()),
+        };
+        (format!("[{}]", address), port)
+    } else if endpoint.parse::<Ipv6Addr>().is_ok() {
+        (format!("[{}]", endpoint), default_port)
+    } else {
+        match endpoint.rsplit_once(':') {
+            Some((host, port)) => (host.to_string(), parse_port(port)?),
+            None => (endpoint.to_string(), default_port),
+        }
+    };
+    if host.is_empty() {
+        return Err(format!("Invalid endpoint {}: no hostname", endpoint).into());
+    }
+    Ok(format!(
+        "{}://{}:{}/prove",
+        if port == 443 { "wss" } else { "ws" },
+        host,
+        port
+    ))
+}
+
+/// The URLs of the endpoints to use, in order of preference: those given as arguments, those in
+/// the discovery manifest, or those in the configuration file
+pub async fn resolve_endpoints(
+    hostnames: &[String],
+    port: u16,
+    config: &ConnectionConfig,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut endpoints = hostnames.to_vec();
+    if let (true, Some(location)) = (endpoints.is_empty(), &config.discovery) {
+        match load_manifest(location).await {
+            Ok(manifest) => endpoints = manifest.endpoints,
+            Err(e) => eprintln!(
+                "Warning: failed to read the orchestrator endpoints from {}: {}",
+                location, e
+            ),
+        }
+    }
+    if endpoints.is_empty() {
+        endpoints = config.endpoints.clone();
+    }
+    if endpoints.is_empty() {
+        endpoints.push(DEFAULT_HOSTNAME.into());
+    }
+    endpoints
+        .iter()
+        .map(|endpoint| endpoint_url(endpoint, port))
+        .collect()
+}
+
+async fn load_manifest(location: &str) -> Result<Manifest, Box<dyn std::error::Error>> {
+    let contents = if location.starts_with("http://") || location.starts_with("https://") {
+        crate::proxy::http_client()
+            .get(location)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?
+            .to_vec()
+    } else {
+        let path = location.strip_prefix("file://").unwrap_or(location);
+        std::fs::read(expand_home(path))?
+    };
+    Ok(serde_json::from_slice(&contents)?)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FailoverSettings {
+    pub return_to_primary_after: Duration,
+    pub skip_failed_for: Duration,
+}
+
+impl FailoverSettings {
+    pub fn from_config(config: &FailoverConfig) -> Self {
+        Self {
+            return_to_primary_after: Duration::from_secs(config.return_to_primary_secs),
+            skip_failed_for: Duration::from_secs(config.skip_failed_secs),
+        }
+    }
+}
+
+/// Connects to the first of several endpoints that works
+pub struct FailoverConnector {
+    endpoints: Vec<Box<dyn Connector>>,
+    settings: FailoverSettings,
+    // The endpoint of the last connection
+    active: AtomicUsize,
+    state: Mutex<FailoverState>,
+}
+
+struct FailoverState {
+    // When each endpoint last failed to connect
+    failed_at: Vec<Option<Instant>>,
+    // When returning to a preferred endpoint was last tried, or when the prover failed over
+    returned_at: Instant,
+}
+
+impl FailoverConnector {
+    /// A connector for `endpoints`, in order of preference. There must be at least one.
+    pub fn new(endpoints: Vec<Box<dyn Connector>>, settings: FailoverSettings) -> Self {
+        assert!(!endpoints.is_empty(), "There must be at least one endpoint");
+        let failed_at = vec![None; endpoints.len()];
+        Self {
+            endpoints,
+            settings,
+            active: AtomicUsize::new(0),
+            state: Mutex::new(FailoverState {
+                failed_at,
+                returned_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// The endpoints in order of preference, except that those that recently failed come last
+    fn order(&self, now: Instant) -> Vec<usize> {
+        let state = self.state.lock();
+        let (failed, healthy): (Vec<usize>, Vec<usize>) =
+            (0..self.endpoints.len()).partition(|&index| {
+                state.failed_at[index]
+                    .is_some_and(|at| now.duration_since(at) < self.settings.skip_failed_for)
+            });
+        healthy.into_iter().chain(failed).collect()
+    }
+
+    /// Try each endpoint of `order` once
+    async fn connect_to_any(
+        &self,
+        order: impl IntoIterator<Item = usize>,
+    ) -> Result<Box<dyn Transport>, TransportError> {
+        let mut last_error = None;
+        for index in order {
+            let endpoint = &self.endpoints[index];
+            match endpoint.connect().await {
+                Ok(client) => {
+                    let previous = self.active.swap(index, Ordering::SeqCst);
+                    let mut state = self.state.lock();
+                    state.failed_at[index] = None;
+                    if previous != index {
+                        state.returned_at = Instant::now();
+                        println!("Switched to the orchestrator at {}", endpoint.address());
+                        metrics::increment(
+                            "nexus_orchestrator_failovers_total",
+                            &[("address", endpoint.address())],
+                        );
+                    }
+                    return Ok(client);
+                }
+                Err(e) => {
+                    self.state.lock().failed_at[index] = Some(Instant::now());
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| "No orchestrator endpoints to connect to".into()))
+    }
+}
+
+impl Connector for FailoverConnector {
+    fn connect(&self) -> BoxFuture<'_, Result<Box<dyn Transport>, TransportError>> {
+        Box::pin(self.connect_to_any(self.order(Instant::now())))
+    }
+
+    fn address(&self) -> &str {
+        self.endpoints[self.active.load(Ordering::SeqCst)].address()
+    }
+
+    fn connect_preferred(
+        &self,
+    ) -> Option<BoxFuture<'_, Result<Box<dyn Transport>, TransportError>>> {
+        let active = self.active.load(Ordering::SeqCst);
+        let mut state = self.state.lock();
+        if active == 0 || state.returned_at.elapsed() < self.settings.return_to_primary_after {
+            return None;
+        }
+        state.returned_at = Instant::now();
+        Some(Box::pin(self.connect_to_any(0..active)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::memory;
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    /// Tests the ways to give an endpoint
+    #[test]
+    fn test_endpoint_url() {
+        assert_eq!(
+            endpoint_url("orchestrator.example.com", 443).unwrap(),
+            "wss://orchestrator.example.com:443/prove"
+        );
+        assert_eq!(
+            endpoint_url("localhost:8080", 443).unwrap(),
+            "ws://localhost:8080/prove"
+        );
+        assert_eq!(
+            endpoint_url("wss://orchestrator.example.com/v2/prove", 443).unwrap(),
+            "wss://orchestrator.example.com/v2/prove"
+        );
+        assert!(endpoint_url("https://orchestrator.example.com", 443).is_err());
+        assert!(endpoint_url("localhost:http", 443).is_err());
+        assert!(endpoint_url(":8080", 443).is_err());
+
+        // IPv6 addresses take a port only in brackets
+        assert_eq!(
+            endpoint_url("[2001:db8::1]:8080", 443).unwrap(),
+            "ws://[2001:db8::1]:8080/prove"
+        );
+        assert_eq!(endpoint_url("[::1]", 443).unwrap(), "wss://[::1]:443/prove");
+        assert_eq!(
+            endpoint_url("2001:db8::1", 443).unwrap(),
+            "wss://[2001:db8::1]:443/prove"
+        );
+        assert!(endpoint_url("[2001:db8::1", 443).is_err());
+        assert!(endpoint_url("[localhost]:8080", 443).is_err());
+        assert!(endpoint_url("[::1]8080", 443).is_err());
+    }
+
+    async fn resolve(hostnames: &[&str], config: &ConnectionConfig) -> Vec<String> {
+        let hostnames: Vec<String> = hostnames.iter().map(|host| host.to_string()).collect();
+        resolve_endpoints(&hostnames, 443, config).await.unwrap()
+    }
+
+    /// Tests that arguments come before the discovery manifest, which comes before the
+    /// configuration file
+    #[tokio::test]
+    async fn test_resolve_endpoints() {
+        let temp_dir = TempDir::new().unwrap();
+        let manifest = temp_dir.path().join("endpoints.json");
+        std::fs::write(
+            &manifest,
+            r#"{"endpoints": ["eu.example.com", "us.example.com"]}"#,
+        )
+        .unwrap();
+        let mut config = ConnectionConfig {
+            endpoints: vec!["configured.example.com".into()],
+            discovery: Some(manifest.display().to_string()),
+            ..ConnectionConfig::default()
+        };
+
+        assert_eq!(
+            resolve(&["localhost:8080"], &config).await,
+            ["ws://localhost:8080/prove"]
+        );
+        assert_eq!(
+            resolve(&[], &config).await,
+            [
+                "wss://eu.example.com:443/prove",
+                "wss://us.example.com:443/prove"
+            ]
+        );
+
+        config.discovery = Some(temp_dir.path().join("missing.json").display().to_string());
+        assert_eq!(
+            resolve(&[], &config).await,
+            ["wss://configured.example.com:443/prove"]
+        );
+        config.endpoints.clear();
+        assert_eq!(
+            resolve(&[], &config).await,
+            ["wss://beta.orchestrator.nexus.xyz:443/prove"]
+        );
+    }
+
+    /// A connector for a primary and a secondary endpoint, and the endpoints
+    fn failover(
+        skip_failed_for: Duration,
+    ) -> (
+        FailoverConnector,
+        [Arc<memory::MemoryConnector>; 2],
+        Vec<memory::MemoryListener>,
+    ) {
+        let (primary, primary_listener) = memory::connector_at("memory://primary");
+        let (secondary, secondary_listener) = memory::connector_at("memory://secondary");
+        let endpoints = [Arc::new(primary), Arc::new(secondary)];
+        let connector = FailoverConnector::new(
+            endpoints
+                .iter()
+                .map(|endpoint| Box::new(endpoint.clone()) as Box<dyn Connector>)
+                .collect(),
+            FailoverSettings {
+                return_to_primary_after: Duration::ZERO,
+                skip_failed_for,
+            },
+        );
+        (
+            connector,
+            endpoints,
+            vec![primary_listener, secondary_listener],
+        )
+    }
+
+    /// Tests that the connector fails over to the next endpoint, and returns to the primary one
+    #[tokio::test]
+    async fn test_failover_and_return() {
+        let (connector, [primary, _], _listeners) = failover(Duration::from_secs(60));
+        assert!(connector.connect_preferred().is_none());
+
+        primary.refuse_next(1);
+        connector.connect().await.unwrap();
+        assert_eq!(connector.address(), "memory://secondary");
+
+        // The primary endpoint recently failed, so it is tried last
+        connector.connect().await.unwrap();
+        assert_eq!(connector.address(), "memory://secondary");
+        assert_eq!(primary.attempts(), 1);
+
+        // Returning is tried regardless
+        primary.refuse_next(1);
+        assert!(connector.connect_preferred().unwrap().await.is_err());
+        assert_eq!(connector.address(), "memory://secondary");
+        connector.connect_preferred().unwrap().await.unwrap();
+        assert_eq!(connector.address(), "memory://primary");
+        assert!(connector.connect_preferred().is_none());
+    }
+
+    /// Tests that connecting fails only once every endpoint failed
+    #[tokio::test]
+    async fn test_all_endpoints_fail() {
+        let (connector, [primary, secondary], _listeners) = failover(Duration::ZERO);
+        primary.refuse_next(2);
+        secondary.refuse_next(1);
+
+        assert!(connector.connect().await.is_err());
+        connector.connect().await.unwrap();
+        assert_eq!(connector.address(), "memory://secondary");
+        assert_eq!(primary.attempts(), 2);
+    }
+}
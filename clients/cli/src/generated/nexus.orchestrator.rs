@@ -30,10 +30,20 @@ pub struct ClientProgramProofRequest {
     /// Prover ID for CLI provers
     #[prost(string, optional, tag = "8")]
     pub cli_prover_id: ::core::option::Option<::prost::alloc::string::String>,
+    /// Identifies this update, so that the orchestrator can acknowledge it
+    /// and ignore it when it is sent again, e.g. after a reconnect.
+    /// Updates that are sent again keep their ID.
+    #[prost(string, tag = "9")]
+    pub message_id: ::prost::alloc::string::String,
 }
+/// Acknowledges a ClientProgramProofRequest once it has been processed.
 #[allow(clippy::derive_partial_eq_without_eq)]
-#[derive(Clone, Copy, PartialEq, ::prost::Message)]
-pub struct ClientProgramProofResponse {}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ClientProgramProofResponse {
+    /// The message_id of the acknowledged request.
+    #[prost(string, tag = "1")]
+    pub message_id: ::prost::alloc::string::String,
+}
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct ProofRequest {
@@ -0,0 +1,940 @@
+//! The proving loop
+//!
+//! A [`JobRunner`] proves one job after another, each a range of steps of a program's trace, and
+//! reports the progress to the orchestrator on a schedule. Between steps it saves checkpoints of
+//! the partial proof, and it reconnects, backs off or stops as the orchestrator asks when it
+//! closes the connection. The proving itself is behind [`StepProver`], so that the loop can run
+//! against the in-memory transport in tests.
+
+use crate::analytics::{track, Event};
+use crate::checkpoint::{self, ProofCheckpoint, ResumeMode, CHECKPOINT_FORMAT_VERSION};
+use crate::connection::{
+    connect_to_orchestrator_with_infinite_retry, reconnect, send_progress, CloseAction,
+};
+use crate::delivery::Outbox;
+use crate::generated::pb::{ClientProgramProofRequest, Progress};
+use crate::keepalive::{Keepalive, KeepaliveSettings};
+use crate::protocol::{self, Protocol};
+use crate::transport::{Connector, Transport, TransportError};
+use crate::utils;
+use crate::utils::retry::{Backoff, RetryPolicy};
+use crate::utils::updater::{PreparedUpdate, UpdateCoordinator};
+use std::borrow::Cow;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
+
+// How many steps of the trace a job proves
+const STEPS_PER_JOB: usize = 10;
+// How long to wait for the orchestrator to acknowledge the remaining progress before restarting
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Proves a program one step of its trace at a time
+pub trait StepProver {
+    /// Trace `program_name` on `input`, returning the number of steps in the trace
+    fn load(&mut self, program_name: &str, input: &[u8]) -> usize;
+    /// Start a new proof at step `start` of the trace
+    fn start(&mut self, start: usize);
+    /// Continue the partial proof of a checkpoint. Returns false if it can't be decoded.
+    fn restore(&mut self, proof: &[u8]) -> bool;
+    fn prove_step(&mut self);
+    /// The partial proof, for checkpoints
+    fn proof_bytes(&self) -> Result<Vec<u8>, String>;
+    /// The finished proof, compressed for the orchestrator
+    fn compressed_proof(&self) -> Vec<u8>;
+}
+
+pub struct JobSettings {
+    pub prover_id: String,
+    /// Where analytics are sent
+    pub ws_addr_string: String,
+    pub k: i32,
+    /// None to not checkpoint at all
+    pub checkpoint_path: Option<PathBuf>,
+    /// Save a checkpoint every this many steps (0 disables checkpointing)
+    pub checkpoint_interval: usize,
+    /// Whether to stop after the first job
+    pub just_once: bool,
+    /// Whether the auto-updater is running, so an outdated CLI can wait for an update
+    pub auto_update: bool,
+    /// How often to send the progress to the orchestrator
+    pub update_interval: Duration,
+}
+
+/// Why the runner stopped proving
+#[derive(Debug)]
+pub enum Finished {
+    /// The only job is done (--just-once)
+    Done,
+    /// An update was installed, and the CLI can restart into it now that a job is done
+    Update(PreparedUpdate),
+    /// The orchestrator closed the connection for good
+    Stopped(String),
+}
+
+/// The job being proven
+struct Job {
+    program_name: String,
+    input: Vec<u8>,
+    total_steps: usize,
+    start: usize,
+    end: usize,
+    steps_proven: i32,
+    /// Time spent proving the job before it was resumed
+    earlier_proving_duration: Duration,
+}
+
+impl Job {
+    /// The progress of the proof, for the streaming protocol
+    fn progress(&self) -> Progress {
+        let steps_to_prove = (self.end - self.start) as i32;
+        Progress {
+            completed_fraction: self.steps_proven as f32 / steps_to_prove.max(1) as f32,
+            steps_in_trace: self.total_steps as i32,
+            steps_to_prove,
+            steps_proven: self.steps_proven,
+        }
+    }
+}
+
+/// Proves jobs and reports their progress over a connection to the orchestrator
+pub struct JobRunner<'a> {
+    settings: JobSettings,
+    connector: &'a dyn Connector,
+    client: Keepalive,
+    backoff: Backoff,
+    outbox: Outbox,
+    update_coordinator: Arc<UpdateCoordinator>,
+    // Progress that has not been queued in the outbox yet
+    queued_steps_proven: i32,
+    queued_proof_duration_millis: i32,
+    last_update: Instant,
+    // A job interrupted by a crash or restart, to prove first
+    unfinished_job: Option<ProofCheckpoint>,
+    reported_ready: bool,
+}
+
+impl<'a> JobRunner<'a> {
+    /// Connect with the backoff of `retry_policy`, for as long as it takes
+    pub async fn connect(
+        settings: JobSettings,
+        connector: &'a dyn Connector,
+        retry_policy: RetryPolicy,
+        keepalive: KeepaliveSettings,
+        update_coordinator: Arc<UpdateCoordinator>,
+    ) -> JobRunner<'a> {
+        // The keepalive checks the connection between progress updates
+        let client = Keepalive::start(
+            connect_to_orchestrator_with_infinite_retry(
+                connector,
+                &settings.prover_id,
+                &retry_policy,
+            )
+            .await,
+            keepalive,
+        );
+        Self {
+            settings,
+            connector,
+            client,
+            backoff: Backoff::new(retry_policy),
+            outbox: Outbox::default(),
+            update_coordinator,
+            queued_steps_proven: 0,
+            queued_proof_duration_millis: 0,
+            last_update: Instant::now(),
+            unfinished_job: None,
+            reported_ready: false,
+        }
+    }
+
+    /// Look for a job that was interrupted by a crash or restart, and decide what to do with it
+    pub fn resume(&mut self, mode: ResumeMode) {
+        let Some(path) = self.settings.checkpoint_path.clone() else {
+            return;
+        };
+        let checkpoint = match checkpoint::load_checkpoint(&path) {
+            Ok(Some(checkpoint)) if checkpoint.prover_id == self.settings.prover_id => checkpoint,
+            Ok(_) => return,
+            Err(e) => {
+                eprintln!("Ignoring unreadable proof checkpoint: {}", e);
+                return;
+            }
+        };
+
+        // Progress that never reached the orchestrator is reported either way, unless discarded
+        let mode = match checkpoint.resume_conflict(self.settings.k) {
+            Some(reason) if mode != ResumeMode::Discard => {
+                println!("\t✓ The unfinished proof can't be resumed: {}.", reason);
+                ResumeMode::Report
+            }
+            _ => checkpoint::resolve_resume_mode(mode, &checkpoint),
+        };
+        match mode {
+            ResumeMode::Resume | ResumeMode::Ask => {
+                self.queued_steps_proven += checkpoint.queued_steps_proven;
+                self.queued_proof_duration_millis += checkpoint.queued_proof_duration_millis;
+                self.unfinished_job = Some(checkpoint);
+            }
+            ResumeMode::Report => {
+                println!(
+                    "\t✓ Will report the progress of the unfinished proof and start a new one."
+                );
+                self.queued_steps_proven += checkpoint.queued_steps_proven;
+                self.queued_proof_duration_millis += checkpoint.queued_proof_duration_millis;
+            }
+            ResumeMode::Discard => println!("\t✓ Discarded the unfinished proof."),
+        }
+        if self.unfinished_job.is_none() {
+            checkpoint::remove_checkpoint(&path);
+        }
+    }
+
+    /// Prove jobs until there are no more to prove, an update is waiting to be restarted into,
+    /// or the orchestrator stops the prover. Can be called again to carry on proving.
+    pub async fn run(&mut self, prover: &mut dyn StepProver) -> Finished {
+        loop {
+            let job = match self.prove_job(prover).await {
+                Ok(job) => job,
+                Err(stopped) => return Finished::Stopped(stopped),
+            };
+
+            // TODO(collinjackson): Consider verifying the proof before sending it
+            // proof.verify(&public_params, proof.step_num() as _).expect("error verifying execution")
+
+            if self.settings.just_once {
+                return Finished::Done;
+            }
+
+            // Between jobs is the only safe time to restart into an installed update
+            if let Some(update) = self.update_coordinator.pending() {
+                println!(
+                    "\nRestarting into version {} after flushing progress...",
+                    update.version
+                );
+                if self.queued_steps_proven > 0 {
+                    self.queue_update(&job);
+                }
+                // The new version starts with an empty outbox
+                self.flush().await;
+                return Finished::Update(update);
+            }
+            println!("\n\nWaiting for a new program to prove...\n");
+        }
+    }
+
+    /// Close the connection, once done proving
+    pub async fn close(&mut self) -> Result<(), TransportError> {
+        self.client
+            .close(Some(CloseFrame {
+                code: CloseCode::Normal,
+                reason: Cow::Borrowed("Finished proving."),
+            }))
+            .await
+    }
+
+    /// Prove the next job, or the unfinished one. Fails if the orchestrator stops the prover.
+    async fn prove_job(&mut self, prover: &mut dyn StepProver) -> Result<Job, String> {
+        let resumed_job = self.unfinished_job.take();
+
+        // Create the inputs for the program, or reuse those of the job being resumed
+        let (program_name, input) = match &resumed_job {
+            Some(checkpoint) => (checkpoint.program_name.clone(), checkpoint.input.clone()),
+            None => {
+                use rand::Rng; // Required for .gen() methods
+                let mut rng = rand::thread_rng();
+                track_new_exposures(&self.settings.prover_id, &self.settings.ws_addr_string);
+                (
+                    utils::prover::get_program_for_prover(&self.settings.prover_id),
+                    vec![5, rng.gen::<u8>(), rng.gen::<u8>()],
+                )
+            }
+        };
+
+        // TODO(collinjackson): Get outputs
+        let total_steps = prover.load(&program_name, &input);
+        let start = 0;
+        let end = (start + STEPS_PER_JOB).min(total_steps);
+        let mut job = Job {
+            program_name,
+            input,
+            total_steps,
+            start,
+            end,
+            steps_proven: 0,
+            earlier_proving_duration: Duration::ZERO,
+        };
+
+        // The trace is deterministic, so a checkpointed proof continues exactly where it stopped
+        let resumed = resumed_job.filter(|checkpoint| {
+            let restored = checkpoint.fits_trace(total_steps, start, end)
+                && checkpoint
+                    .proof_bytes()
+                    .is_ok_and(|proof| prover.restore(&proof));
+            if !restored {
+                eprintln!("Could not restore the checkpointed proof, starting it over.");
+            }
+            restored
+        });
+        let first_step = match resumed {
+            Some(checkpoint) => {
+                println!(
+                    "Resuming proof of {} at step {} of {}...",
+                    job.program_name, checkpoint.next_step, end
+                );
+                job.steps_proven = checkpoint.steps_proven;
+                job.earlier_proving_duration =
+                    Duration::from_millis(checkpoint.proving_duration_millis);
+                checkpoint.next_step
+            }
+            None => {
+                prover.start(start);
+                println!(
+                    "Program trace is {} steps. Proving {} steps starting at {}...",
+                    total_steps, STEPS_PER_JOB, start
+                );
+                start
+            }
+        };
+
+        let start_time = Instant::now();
+        let mut progress_time = start_time;
+        for step in first_step..end {
+            prover.prove_step();
+            job.steps_proven += 1;
+
+            // If this process was started by an update, it has now proven that it works
+            if !self.reported_ready {
+                utils::updater::rollback::report_ready();
+                self.reported_ready = true;
+            }
+
+            let progress_duration = progress_time.elapsed();
+            let proof_cycles_hertz =
+                self.settings.k as f64 * 1000.0 / progress_duration.as_millis() as f64;
+
+            //update the queued variables
+            self.queued_proof_duration_millis += progress_duration.as_millis() as i32;
+            self.queued_steps_proven += 1;
+
+            // Print the proof progress in green or blue depending on the step number
+            println!(
+                "\t✓ Proved step {} at {:.2} proof cycles/sec.",
+                step, proof_cycles_hertz
+            );
+
+            progress_time = Instant::now();
+
+            // Periodically persist the partial proof so a restart doesn't lose the work
+            let interval = self.settings.checkpoint_interval;
+            if interval > 0 && (step + 1 - first_step) % interval == 0 && step + 1 < end {
+                let proving_duration = job.earlier_proving_duration + start_time.elapsed();
+                self.save_checkpoint(prover, &job, step + 1, proving_duration);
+            }
+
+            //If it has been three minutes since the last orchestrator update, send the orchestator the update
+            if self.last_update.elapsed() >= self.settings.update_interval {
+                println!(
+                    "\tWill try sending update to orchestrator with interval queued_steps_proven: {}",
+                    self.queued_steps_proven
+                );
+                self.queue_update(&job);
+                self.report().await?;
+
+                //reset the timer regardless of success (to avoid spam)
+                self.last_update = Instant::now();
+            }
+
+            if step == end - 1 {
+                let total_duration = job.earlier_proving_duration + start_time.elapsed();
+                self.finish(prover, &job, total_duration).await;
+            }
+        }
+
+        // The job is complete, so there is nothing left to resume
+        if let Some(path) = &self.settings.checkpoint_path {
+            checkpoint::remove_checkpoint(path);
+        }
+        Ok(job)
+    }
+
+    fn save_checkpoint(
+        &self,
+        prover: &dyn StepProver,
+        job: &Job,
+        next_step: usize,
+        proving_duration: Duration,
+    ) {
+        let Some(path) = &self.settings.checkpoint_path else {
+            return;
+        };
+        let saved = prover.proof_bytes().and_then(|proof_bytes| {
+            let mut checkpoint = ProofCheckpoint {
+                format_version: CHECKPOINT_FORMAT_VERSION,
+                cli_version: checkpoint::CLI_VERSION.to_string(),
+                prover_id: self.settings.prover_id.clone(),
+                program_name: job.program_name.clone(),
+                input: job.input.clone(),
+                k: self.settings.k,
+                total_steps: job.total_steps,
+                start: job.start,
+                end: job.end,
+                next_step,
+                steps_proven: job.steps_proven,
+                queued_steps_proven: self.queued_steps_proven,
+                queued_proof_duration_millis: self.queued_proof_duration_millis,
+                proving_duration_millis: proving_duration.as_millis() as u64,
+                proof: String::new(),
+                saved_at: 0,
+            };
+            checkpoint.set_proof_bytes(&proof_bytes);
+            checkpoint::save_checkpoint(path, &checkpoint).map_err(|e| e.to_string())
+        });
+        if let Err(e) = saved {
+            eprintln!("Failed to save proof checkpoint: {}", e);
+        }
+    }
+
+    /// Move the queued progress into the outbox, which keeps it until the orchestrator
+    /// acknowledges it
+    fn queue_update(&mut self, job: &Job) {
+        let request = ClientProgramProofRequest {
+            steps_in_trace: job.total_steps as i32,
+            steps_proven: self.queued_steps_proven,
+            step_to_start: job.start as i32,
+            program_id: job.program_name.clone(),
+            client_id_token: None,
+            proof_duration_millis: self.queued_proof_duration_millis,
+            k: self.settings.k,
+            cli_prover_id: Some(self.settings.prover_id.clone()),
+            message_id: String::new(),
+        };
+        self.outbox.push(request, job.progress());
+        self.queued_steps_proven = 0;
+        self.queued_proof_duration_millis = 0;
+
+        // The outbox reports it from now on
+        if let Some(path) = &self.settings.checkpoint_path {
+            if let Err(e) = checkpoint::clear_queued_progress(path) {
+                eprintln!("Failed to update proof checkpoint: {}", e);
+            }
+        }
+    }
+
+    /// Send the progress, unless the keepalive found the connection dead. Fails with the reason
+    /// if the orchestrator closed the connection for good.
+    async fn report(&mut self) -> Result<(), String> {
+        let sent = match send_progress(
+            &mut self.client,
+            self.connector,
+            &self.settings.prover_id,
+            &mut self.backoff,
+            &mut self.outbox,
+        )
+        .await
+        {
+            Ok(sent) => sent,
+            // The updater installs the new version, and the prover restarts into it after this
+            // job. Meanwhile it keeps proving, and reconnects as after any other close.
+            Err(closed) if closed.action == CloseAction::Update && self.settings.auto_update => {
+                self.update_coordinator.request_check();
+                reconnect(
+                    &mut self.client,
+                    self.connector,
+                    &self.settings.prover_id,
+                    &mut self.backoff,
+                    &mut self.outbox,
+                )
+                .await;
+                false
+            }
+            Err(closed) if closed.action == CloseAction::Update => {
+                return Err(format!(
+                    "{}: this version of the CLI is no longer supported. Update the CLI, or run \
+                     it with --auto-update to stay up to date.",
+                    closed
+                ));
+            }
+            Err(closed) => return Err(closed.to_string()),
+        };
+        if sent {
+            if let Some(rtt) = self.client.quality().rtt {
+                println!("\tSent the update (round-trip time {} ms)", rtt.as_millis());
+            }
+        }
+        if self.outbox.unacknowledged() > 0 {
+            println!(
+                "\t{} updates are waiting for the orchestrator's acknowledgement",
+                self.outbox.unacknowledged()
+            );
+        }
+        Ok(())
+    }
+
+    /// Send the queued updates, and wait until the orchestrator has acknowledged them, for up to
+    /// [`FLUSH_TIMEOUT`]. Reconnects if the connection breaks meanwhile.
+    async fn flush(&mut self) {
+        let deadline = tokio::time::Instant::now() + FLUSH_TIMEOUT;
+        while self.outbox.unacknowledged() > 0 && tokio::time::Instant::now() < deadline {
+            let sent = send_progress(
+                &mut self.client,
+                self.connector,
+                &self.settings.prover_id,
+                &mut self.backoff,
+                &mut self.outbox,
+            )
+            .await;
+            match sent {
+                Ok(true) => {}
+                // Send again over the new connection
+                Ok(false) if self.client.dead().is_none() => continue,
+                Ok(false) | Err(_) => break,
+            }
+
+            // Until the connection breaks, which makes the updates go out again
+            while self.outbox.unacknowledged() > 0 {
+                match tokio::time::timeout_at(deadline, self.client.receive()).await {
+                    Ok(Some(Ok(message))) => self.outbox.acknowledge(&message),
+                    Ok(_) | Err(_) => break,
+                }
+            }
+        }
+        if self.outbox.unacknowledged() > 0 {
+            eprintln!(
+                "The orchestrator did not acknowledge {} progress updates before restarting",
+                self.outbox.unacknowledged()
+            );
+        }
+    }
+
+    /// Report a finished proof
+    async fn finish(&mut self, prover: &dyn StepProver, job: &Job, total_duration: Duration) {
+        let proof = prover.compressed_proof();
+
+        let total_minutes = total_duration.as_secs() as f64 / 60.0;
+        let cycles_proved = job.steps_proven * self.settings.k;
+        let proof_cycles_per_minute = cycles_proved as f64 / total_minutes;
+
+        // Send analytics about the proof event
+        track(
+            Event::Proof {
+                prover_id: self.settings.prover_id.clone(),
+                program_name: job.program_name.clone(),
+                steps_in_trace: job.total_steps,
+                steps_to_prove: STEPS_PER_JOB,
+                steps_proven: job.steps_proven,
+                cycles_proven: cycles_proved,
+                k: self.settings.k,
+                proof_duration_sec: total_duration.as_secs(),
+                proof_duration_millis: total_duration.as_millis() as u64,
+                proof_cycles_per_minute,
+            },
+            &self.settings.ws_addr_string,
+            false,
+        );
+
+        // Streaming orchestrators take the completed proof
+        if self.client.protocol() == Protocol::Streaming {
+            if let Err(e) = self.client.send(protocol::completed_proof(proof)).await {
+                eprintln!("Failed to send the proof to the orchestrator: {}", e);
+            }
+        }
+    }
+}
+
+/// Report the experiment arms this prover gets for the first time
+fn track_new_exposures(prover_id: &str, ws_addr_string: &str) {
+    let Some(path) = utils::experiment::exposures_path() else {
+        return;
+    };
+    let exposures = utils::experiment::registry().exposures(prover_id);
+    match utils::experiment::record_new_exposures(&path, prover_id, exposures) {
+        Ok(new_exposures) => {
+            for exposure in new_exposures {
+                track(
+                    Event::Exposure {
+                        prover_id: prover_id.to_string(),
+                        experiment: exposure.experiment,
+                        arm: exposure.arm,
+                        overridden: exposure.overridden,
+                    },
+                    ws_addr_string,
+                    false,
+                );
+            }
+        }
+        Err(e) => eprintln!("Failed to record experiment exposures: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::CLOSE_CLIENT_TOO_OLD;
+    use crate::generated::pb::ClientProgramProofResponse;
+    use crate::transport::memory;
+    use prost::Message as _;
+    use serial_test::serial;
+    use tempfile::TempDir;
+    use tokio_tungstenite::tungstenite::protocol::Message;
+
+    /// Pretends to prove a trace of `steps` steps, with the proof being the steps proven so far
+    struct FakeProver {
+        steps: usize,
+        proven: usize,
+    }
+
+    impl FakeProver {
+        fn new(steps: usize) -> Self {
+            Self { steps, proven: 0 }
+        }
+    }
+
+    impl StepProver for FakeProver {
+        fn load(&mut self, _program_name: &str, _input: &[u8]) -> usize {
+            self.steps
+        }
+
+        fn start(&mut self, start: usize) {
+            self.proven = start;
+        }
+
+        fn restore(&mut self, proof: &[u8]) -> bool {
+            match proof {
+                [proven] => {
+                    self.proven = *proven as usize;
+                    true
+                }
+                _ => false,
+            }
+        }
+
+        fn prove_step(&mut self) {
+            self.proven += 1;
+        }
+
+        fn proof_bytes(&self) -> Result<Vec<u8>, String> {
+            Ok(vec![self.proven as u8])
+        }
+
+        fn compressed_proof(&self) -> Vec<u8> {
+            vec![self.proven as u8]
+        }
+    }
+
+    // Keep the experiment exposures in a temporary directory instead of the real state directory
+    fn use_state_dir() -> TempDir {
+        let dir = TempDir::new().unwrap();
+        crate::state::configure(crate::state::StateDirs::resolve(Some(dir.path())));
+        dir
+    }
+
+    /// One job, reporting the progress after every step
+    fn settings() -> JobSettings {
+        JobSettings {
+            prover_id: "test-prover".into(),
+            ws_addr_string: "memory://orchestrator".into(),
+            k: 4,
+            checkpoint_path: None,
+            checkpoint_interval: 0,
+            just_once: true,
+            auto_update: false,
+            update_interval: Duration::ZERO,
+        }
+    }
+
+    /// A runner connected to the in-memory orchestrator, pinging only once at the start
+    async fn runner(connector: &memory::MemoryConnector, settings: JobSettings) -> JobRunner<'_> {
+        let retry_policy = RetryPolicy {
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(10),
+            max_attempts: Some(5),
+            reset_after: Duration::from_secs(60),
+            circuit_open_for: Duration::from_secs(600),
+        };
+        let keepalive = KeepaliveSettings {
+            interval: Duration::from_secs(600),
+            max_missed_pongs: 3,
+        };
+        JobRunner::connect(settings, connector, retry_policy, keepalive, Arc::default()).await
+    }
+
+    /// Close the connection from the orchestrator's end, and wait until the runner noticed
+    async fn close(
+        runner: &mut JobRunner<'_>,
+        orchestrator: &mut memory::MemoryTransport,
+        code: CloseCode,
+    ) {
+        orchestrator
+            .close(Some(CloseFrame {
+                code,
+                reason: "because".into(),
+            }))
+            .await
+            .unwrap();
+        runner.client.wait_until_dead().await;
+    }
+
+    /// The progress updates that have arrived, skipping the keepalive's pings
+    fn received(orchestrator: &mut memory::MemoryTransport) -> Vec<ClientProgramProofRequest> {
+        std::iter::from_fn(|| orchestrator.try_receive())
+            .filter_map(|message| match message {
+                Message::Binary(data) => ClientProgramProofRequest::decode(data.as_slice()).ok(),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Tests that the prover reconnects when the orchestrator closes the connection, and reports
+    /// the progress over the new connection
+    #[tokio::test]
+    #[serial]
+    async fn test_reconnect_after_close_frame() {
+        let _state_dir = use_state_dir();
+        let (connector, mut listener) = memory::connector();
+        let mut runner = runner(&connector, settings()).await;
+        let mut orchestrator = listener.accept().await.unwrap();
+        close(&mut runner, &mut orchestrator, CloseCode::Restart).await;
+
+        let mut prover = FakeProver::new(2);
+        assert!(matches!(runner.run(&mut prover).await, Finished::Done));
+        assert_eq!(prover.proven, 2);
+        assert_eq!(connector.attempts(), 2);
+
+        // The update of the first step waited for the new connection
+        let mut orchestrator = listener.try_accept().unwrap();
+        let steps_proven: Vec<i32> = received(&mut orchestrator)
+            .iter()
+            .map(|update| update.steps_proven)
+            .collect();
+        assert_eq!(steps_proven, [1, 1]);
+    }
+
+    /// Tests that a policy violation stops the prover instead of reconnecting
+    #[tokio::test]
+    #[serial]
+    async fn test_stop_after_close_frame() {
+        let _state_dir = use_state_dir();
+        let (connector, mut listener) = memory::connector();
+        let mut runner = runner(
+            &connector,
+            JobSettings {
+                just_once: false,
+                ..settings()
+            },
+        )
+        .await;
+        let mut orchestrator = listener.accept().await.unwrap();
+        close(&mut runner, &mut orchestrator, CloseCode::Policy).await;
+
+        let Finished::Stopped(error) = runner.run(&mut FakeProver::new(2)).await else {
+            panic!("Expected the prover to stop");
+        };
+        assert_eq!(
+            error,
+            "The orchestrator closed the connection (code 1008: because)"
+        );
+        assert_eq!(connector.attempts(), 1);
+    }
+
+    /// Tests that an outdated CLI asks the auto-updater for a new version and carries on over a
+    /// new connection, and that it stops without the auto-updater
+    #[tokio::test]
+    #[serial]
+    async fn test_update_after_close_frame() {
+        let _state_dir = use_state_dir();
+        let too_old = CloseCode::from(CLOSE_CLIENT_TOO_OLD);
+        let (connector, mut listener) = memory::connector();
+        let mut updating = runner(
+            &connector,
+            JobSettings {
+                auto_update: true,
+                ..settings()
+            },
+        )
+        .await;
+        let mut orchestrator = listener.accept().await.unwrap();
+        close(&mut updating, &mut orchestrator, too_old).await;
+
+        assert!(matches!(
+            updating.run(&mut FakeProver::new(2)).await,
+            Finished::Done
+        ));
+        assert!(updating.update_coordinator.wait_for_check(Duration::ZERO));
+        assert_eq!(connector.attempts(), 2);
+        let mut orchestrator = listener.try_accept().unwrap();
+        assert_eq!(received(&mut orchestrator).len(), 2);
+
+        let (connector, mut listener) = memory::connector();
+        let mut runner = runner(&connector, settings()).await;
+        let mut orchestrator = listener.accept().await.unwrap();
+        close(&mut runner, &mut orchestrator, too_old).await;
+
+        let Finished::Stopped(error) = runner.run(&mut FakeProver::new(2)).await else {
+            panic!("Expected the prover to stop");
+        };
+        assert!(error.contains("--auto-update"));
+        assert!(!runner.update_coordinator.wait_for_check(Duration::ZERO));
+        assert_eq!(connector.attempts(), 1);
+    }
+
+    /// Tests that the progress is acknowledged before restarting into an update
+    #[tokio::test]
+    #[serial]
+    async fn test_flush_before_update() {
+        let _state_dir = use_state_dir();
+        let (connector, mut listener) = memory::connector();
+        let mut runner = runner(
+            &connector,
+            JobSettings {
+                just_once: false,
+                // Only the flush reports anything
+                update_interval: Duration::from_secs(600),
+                ..settings()
+            },
+        )
+        .await;
+        let mut orchestrator = listener.accept().await.unwrap();
+        runner.update_coordinator.set_pending(PreparedUpdate {
+            version: "1.2.3".parse().unwrap(),
+            executable: None,
+            previous_version: "1.2.2".parse().unwrap(),
+            backup: None,
+        });
+
+        // An orchestrator that has acknowledged updates before
+        let acknowledgement = |message_id: &str| {
+            let response = ClientProgramProofResponse {
+                message_id: message_id.to_string(),
+            };
+            Message::Binary(response.encode_to_vec())
+        };
+        runner.outbox.acknowledge(&acknowledgement("earlier"));
+        let acknowledging = tokio::spawn(async move {
+            while let Some(Ok(message)) = orchestrator.receive().await {
+                let Message::Binary(data) = message else {
+                    continue;
+                };
+                let update = ClientProgramProofRequest::decode(data.as_slice()).unwrap();
+                orchestrator
+                    .send(acknowledgement(&update.message_id))
+                    .await
+                    .unwrap();
+                return update;
+            }
+            panic!("Expected a progress update");
+        });
+
+        let Finished::Update(update) = runner.run(&mut FakeProver::new(2)).await else {
+            panic!("Expected to restart into the update");
+        };
+        assert_eq!(update.version.to_string(), "1.2.3");
+        assert_eq!(runner.outbox.unacknowledged(), 0);
+        assert_eq!(acknowledging.await.unwrap().steps_proven, 2);
+    }
+
+    /// A checkpoint of the first of four steps proven, as written by `FakeProver`
+    fn unfinished_checkpoint() -> ProofCheckpoint {
+        let mut checkpoint = ProofCheckpoint {
+            format_version: CHECKPOINT_FORMAT_VERSION,
+            cli_version: checkpoint::CLI_VERSION.into(),
+            prover_id: "test-prover".into(),
+            program_name: "fast-fib".into(),
+            input: vec![5, 17, 42],
+            k: 4,
+            total_steps: 4,
+            start: 0,
+            end: 4,
+            next_step: 1,
+            steps_proven: 1,
+            queued_steps_proven: 0,
+            queued_proof_duration_millis: 0,
+            proving_duration_millis: 0,
+            proof: String::new(),
+            saved_at: 0,
+        };
+        checkpoint.set_proof_bytes(&[1]);
+        checkpoint
+    }
+
+    /// Tests that the proving time of a resumed job includes the time spent before the restart
+    #[tokio::test]
+    #[serial]
+    async fn test_resume_keeps_proving_duration() {
+        let state_dir = use_state_dir();
+        let checkpoint_path = state_dir.path().join("checkpoint.json");
+        let unfinished = ProofCheckpoint {
+            proving_duration_millis: 60_000,
+            ..unfinished_checkpoint()
+        };
+        checkpoint::save_checkpoint(&checkpoint_path, &unfinished).unwrap();
+
+        // Checkpoint every step, and get stopped after the first one
+        let (connector, mut listener) = memory::connector();
+        let mut runner = runner(
+            &connector,
+            JobSettings {
+                checkpoint_path: Some(checkpoint_path.clone()),
+                checkpoint_interval: 1,
+                ..settings()
+            },
+        )
+        .await;
+        runner.resume(ResumeMode::Resume);
+        let mut orchestrator = listener.accept().await.unwrap();
+        close(&mut runner, &mut orchestrator, CloseCode::Policy).await;
+        let mut prover = FakeProver::new(4);
+        assert!(matches!(
+            runner.run(&mut prover).await,
+            Finished::Stopped(_)
+        ));
+
+        let saved = checkpoint::load_checkpoint(&checkpoint_path)
+            .unwrap()
+            .expect("The stopped job should be checkpointed");
+        assert_eq!(saved.next_step, 2);
+        assert!(saved.proving_duration_millis >= 60_000);
+        // The progress of the step went to the outbox, so a restart doesn't report it again
+        assert_eq!(saved.queued_steps_proven, 0);
+    }
+
+    /// Tests that a checkpoint of another CLI version, or that doesn't fit the trace, is proven
+    /// over from the start instead of being resumed
+    #[tokio::test]
+    #[serial]
+    async fn test_unresumable_checkpoint_starts_over() {
+        let state_dir = use_state_dir();
+        let checkpoint_path = state_dir.path().join("checkpoint.json");
+        let other_version = ProofCheckpoint {
+            cli_version: "0.3.5".into(),
+            ..unfinished_checkpoint()
+        };
+        let past_the_end = ProofCheckpoint {
+            next_step: 4,
+            ..unfinished_checkpoint()
+        };
+
+        for mut unfinished in [other_version, past_the_end] {
+            // Restoring this proof would put the fake prover past the end of the trace
+            unfinished.set_proof_bytes(&[9]);
+            checkpoint::save_checkpoint(&checkpoint_path, &unfinished).unwrap();
+
+            let (connector, mut listener) = memory::connector();
+            let mut runner = runner(
+                &connector,
+                JobSettings {
+                    checkpoint_path: Some(checkpoint_path.clone()),
+                    ..settings()
+                },
+            )
+            .await;
+            runner.resume(ResumeMode::Resume);
+            let _orchestrator = listener.accept().await.unwrap();
+            let mut prover = FakeProver::new(4);
+            assert!(matches!(runner.run(&mut prover).await, Finished::Done));
+            assert_eq!(prover.proven, 4);
+            assert!(!checkpoint_path.exists());
+        }
+    }
+}
@@ -0,0 +1,360 @@
+//! Checking that the orchestrator connection is alive
+//!
+//! A [`Keepalive`] owns an open connection and runs a task next to the prover that pings the
+//! orchestrator on a schedule. Each ping carries a sequence number that the pong echoes, so the
+//! round-trip time can be measured and late pongs are told apart from current ones. Once too many
+//! pings in a row go unanswered, or the orchestrator closes the connection, the connection is
+//! dead, and the prover reconnects at its next progress update.
+
+use crate::config::KeepaliveConfig;
+use crate::connection::{Broken, Closed};
+use crate::metrics;
+use crate::protocol::Protocol;
+use crate::transport::{Transport, TransportError};
+use futures::future::BoxFuture;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time::{Instant, MissedTickBehavior};
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Message};
+
+const DEAD: &str = "The connection to the orchestrator is dead";
+
+#[derive(Debug, Clone, Copy)]
+pub struct KeepaliveSettings {
+    pub interval: Duration,
+    pub max_missed_pongs: u32,
+}
+
+impl KeepaliveSettings {
+    pub fn from_config(config: &KeepaliveConfig) -> Self {
+        Self {
+            interval: Duration::from_secs(config.interval_secs.max(1)),
+            max_missed_pongs: config.max_missed_pongs.max(1),
+        }
+    }
+}
+
+/// How well the connection is doing, as last measured
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Quality {
+    /// The round-trip time of the last answered ping
+    pub rtt: Option<Duration>,
+    /// Pings in a row that went unanswered
+    pub missed_pongs: u32,
+}
+
+enum Command {
+    Send(Message, oneshot::Sender<Result<(), TransportError>>),
+    Close(
+        Option<CloseFrame<'static>>,
+        oneshot::Sender<Result<(), TransportError>>,
+    ),
+}
+
+/// An open connection, checked by a keepalive task. Messages other than pongs are passed through.
+pub struct Keepalive {
+    settings: KeepaliveSettings,
+    protocol: Protocol,
+    commands: mpsc::UnboundedSender<Command>,
+    incoming: mpsc::UnboundedReceiver<Message>,
+    quality: Arc<Mutex<Quality>>,
+    dead: watch::Receiver<Option<Broken>>,
+}
+
+impl Keepalive {
+    /// Start checking `transport`, which the keepalive task owns from now on
+    pub fn start(transport: Box<dyn Transport>, settings: KeepaliveSettings) -> Self {
+        let (commands, command_receiver) = mpsc::unbounded_channel();
+        let (incoming_sender, incoming) = mpsc::unbounded_channel();
+        let (dead_sender, dead) = watch::channel(None);
+        let quality = Arc::new(Mutex::new(Quality::default()));
+        let protocol = transport.protocol();
+        let task = Task {
+            transport,
+            settings,
+            commands: command_receiver,
+            incoming: incoming_sender,
+            quality: quality.clone(),
+            pings: VecDeque::new(),
+            next_sequence: 0,
+        };
+        tokio::spawn(async move {
+            if let Some(broken) = task.run().await {
+                dead_sender.send_replace(Some(broken));
+            }
+        });
+        Self {
+            settings,
+            protocol,
+            commands,
+            incoming,
+            quality,
+            dead,
+        }
+    }
+
+    pub fn settings(&self) -> KeepaliveSettings {
+        self.settings
+    }
+
+    pub fn quality(&self) -> Quality {
+        *self.quality.lock()
+    }
+
+    /// A message that has already arrived, without waiting for one
+    pub fn try_receive(&mut self) -> Option<Message> {
+        self.incoming.try_recv().ok()
+    }
+
+    /// Why the connection died, if it has
+    pub fn dead(&self) -> Option<Broken> {
+        self.dead.borrow().clone()
+    }
+
+    /// Wait until the connection dies, e.g. to find out why sending failed. Returns None if the
+    /// connection was closed from this end instead.
+    pub async fn wait_until_dead(&mut self) -> Option<Broken> {
+        let dead = self.dead.wait_for(Option::is_some).await.ok()?;
+        dead.clone()
+    }
+
+    async fn command(
+        &self,
+        command: impl FnOnce(oneshot::Sender<Result<(), TransportError>>) -> Command,
+    ) -> Result<(), TransportError> {
+        let (reply, result) = oneshot::channel();
+        self.commands
+            .send(command(reply))
+            .map_err(|_| TransportError::from(DEAD))?;
+        result.await.map_err(|_| TransportError::from(DEAD))?
+    }
+}
+
+impl Transport for Keepalive {
+    fn send(&mut self, message: Message) -> BoxFuture<'_, Result<(), TransportError>> {
+        Box::pin(self.command(|reply| Command::Send(message, reply)))
+    }
+
+    fn receive(&mut self) -> BoxFuture<'_, Option<Result<Message, TransportError>>> {
+        Box::pin(async move { self.incoming.recv().await.map(Ok) })
+    }
+
+    fn close(
+        &mut self,
+        frame: Option<CloseFrame<'static>>,
+    ) -> BoxFuture<'_, Result<(), TransportError>> {
+        Box::pin(self.command(|reply| Command::Close(frame, reply)))
+    }
+
+    fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+}
+
+struct Task {
+    transport: Box<dyn Transport>,
+    settings: KeepaliveSettings,
+    commands: mpsc::UnboundedReceiver<Command>,
+    incoming: mpsc::UnboundedSender<Message>,
+    quality: Arc<Mutex<Quality>>,
+    // Unanswered pings, oldest first: their sequence numbers and when they were sent
+    pings: VecDeque<(u64, Instant)>,
+    next_sequence: u64,
+}
+
+impl Task {
+    /// Returns why the connection died, or None once it was closed or the [`Keepalive`] dropped
+    async fn run(mut self) -> Option<Broken> {
+        let mut ticks = tokio::time::interval(self.settings.interval);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = ticks.tick() => {
+                    if let Err(broken) = self.ping().await {
+                        return Some(broken);
+                    }
+                }
+                command = self.commands.recv() => match command {
+                    Some(Command::Send(message, reply)) => {
+                        if let Err(e) = self.transport.send(message).await {
+                            let broken = Broken::Failed("send", e.to_string());
+                            let _ = reply.send(Err(e));
+                            return Some(broken);
+                        }
+                        let _ = reply.send(Ok(()));
+                    }
+                    Some(Command::Close(frame, reply)) => {
+                        let _ = reply.send(self.transport.close(frame).await);
+                        return None;
+                    }
+                    None => return None,
+                },
+                message = self.transport.receive() => match message {
+                    Some(Ok(Message::Pong(payload))) => self.pong(&payload),
+                    Some(Ok(Message::Close(frame))) => {
+                        return Some(Broken::Closed(Closed::from_frame(frame.as_ref())));
+                    }
+                    Some(Ok(message)) => {
+                        let _ = self.incoming.send(message);
+                    }
+                    Some(Err(e)) => return Some(Broken::Failed("protocol", e.to_string())),
+                    None => {
+                        return Some(Broken::Failed(
+                            "closed",
+                            "The connection was closed without a close frame".into(),
+                        ));
+                    }
+                },
+            }
+        }
+    }
+
+    async fn ping(&mut self) -> Result<(), Broken> {
+        // The previous ping is still unanswered
+        if !self.pings.is_empty() {
+            let missed_pongs = {
+                let mut quality = self.quality.lock();
+                quality.missed_pongs += 1;
+                quality.missed_pongs
+            };
+            metrics::increment("nexus_orchestrator_missed_pongs_total", &[]);
+            metrics::set("nexus_orchestrator_missed_pongs", &[], missed_pongs as f64);
+            if missed_pongs >= self.settings.max_missed_pongs {
+                return Err(Broken::Failed(
+                    "timeout",
+                    format!("No pong received for the last {} pings", missed_pongs),
+                ));
+            }
+            eprintln!(
+                "No pong from the orchestrator for {} seconds, the connection may be degraded",
+                self.settings.interval.as_secs() * missed_pongs as u64
+            );
+        }
+
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        self.transport
+            .send(Message::Ping(sequence.to_be_bytes().to_vec()))
+            .await
+            .map_err(|e| Broken::Failed("send", e.to_string()))?;
+        self.pings.push_back((sequence, Instant::now()));
+        // Pongs this late are not coming anymore
+        if self.pings.len() > self.settings.max_missed_pongs as usize {
+            self.pings.pop_front();
+        }
+        metrics::increment("nexus_orchestrator_pings_total", &[]);
+        Ok(())
+    }
+
+    fn pong(&mut self, payload: &[u8]) {
+        // Unsolicited pongs, and pongs to forgotten pings, say nothing about the round trip
+        let Ok(sequence) = <[u8; 8]>::try_from(payload).map(u64::from_be_bytes) else {
+            return;
+        };
+        let Some(index) = self.pings.iter().position(|(ping, _)| *ping == sequence) else {
+            return;
+        };
+        let rtt = self.pings[index].1.elapsed();
+        // The orchestrator answers in order, so earlier pings won't be answered anymore
+        self.pings.drain(..=index);
+
+        let missed_pongs = {
+            let mut quality = self.quality.lock();
+            let missed_pongs = quality.missed_pongs;
+            *quality = Quality {
+                rtt: Some(rtt),
+                missed_pongs: 0,
+            };
+            missed_pongs
+        };
+        if missed_pongs > 0 {
+            println!(
+                "The connection to the orchestrator recovered (round-trip time {} ms)",
+                rtt.as_millis()
+            );
+        }
+        metrics::increment("nexus_orchestrator_pongs_total", &[]);
+        metrics::set("nexus_orchestrator_rtt_seconds", &[], rtt.as_secs_f64());
+        metrics::set("nexus_orchestrator_missed_pongs", &[], 0.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::memory;
+
+    fn settings(max_missed_pongs: u32) -> KeepaliveSettings {
+        KeepaliveSettings {
+            interval: Duration::from_millis(10),
+            max_missed_pongs,
+        }
+    }
+
+    /// Tests that answered pings measure the round-trip time, and that other messages go through
+    #[tokio::test]
+    async fn test_round_trip_time() -> Result<(), TransportError> {
+        let (client, mut orchestrator) = memory::pair();
+        let mut client = Keepalive::start(Box::new(client), settings(3));
+
+        // Answer the first ping, and say hello
+        let Some(Ok(Message::Ping(payload))) = orchestrator.receive().await else {
+            panic!("Expected a ping");
+        };
+        // An unsolicited pong doesn't count
+        orchestrator.send(Message::Pong(vec![1, 2])).await?;
+        orchestrator.send(Message::Pong(payload)).await?;
+        orchestrator.send(Message::Text("hello".into())).await?;
+
+        assert_eq!(
+            client.receive().await.unwrap()?,
+            Message::Text("hello".into())
+        );
+        let quality = client.quality();
+        assert!(quality.rtt.is_some());
+        assert_eq!(quality.missed_pongs, 0);
+
+        client.send(Message::Binary(vec![1, 2, 3])).await?;
+        client.close(None).await?;
+        while let Some(message) = orchestrator.receive().await {
+            match message? {
+                Message::Ping(_) | Message::Binary(_) => {}
+                message => assert_eq!(message, Message::Close(None)),
+            }
+        }
+        assert!(client.wait_until_dead().await.is_none());
+        Ok(())
+    }
+
+    /// Tests that the connection is dead once enough pings in a row went unanswered
+    #[tokio::test]
+    async fn test_missed_pongs() {
+        let (client, _orchestrator) = memory::pair();
+        let mut client = Keepalive::start(Box::new(client), settings(3));
+
+        let Some(Broken::Failed(kind, error)) = client.wait_until_dead().await else {
+            panic!("Expected a failure");
+        };
+        assert_eq!(kind, "timeout");
+        assert_eq!(error, "No pong received for the last 3 pings");
+        assert_eq!(client.quality().missed_pongs, 3);
+        assert!(client.send(Message::Binary(vec![1])).await.is_err());
+    }
+
+    /// Tests that a close frame from the orchestrator makes the connection dead
+    #[tokio::test]
+    async fn test_closed_by_orchestrator() {
+        let (client, mut orchestrator) = memory::pair();
+        let mut client = Keepalive::start(Box::new(client), settings(3));
+
+        orchestrator.close(None).await.unwrap();
+        let Some(Broken::Closed(closed)) = client.wait_until_dead().await else {
+            panic!("Expected a close frame");
+        };
+        assert_eq!(closed, Closed::from_frame(None));
+        assert!(client.dead().is_some());
+    }
+}
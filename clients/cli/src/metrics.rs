@@ -0,0 +1,153 @@
+//! Counters for operators
+//!
+//! The CLI counts what happens to its connection (close codes, protocol errors, reconnects),
+//! measures its quality (round-trip time, missed pongs), and writes the values to `metrics.prom`
+//! in the run directory, in the Prometheus text format, so that node_exporter's textfile
+//! collector or any other scraper can pick them up. The file is rewritten whenever a value
+//! changes, which is rare enough not to matter.
+
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+
+// Name and labels of a counter
+type Key = (&'static str, Vec<(&'static str, String)>);
+
+static METRICS: Mutex<Metrics> = Mutex::new(Metrics {
+    path: None,
+    counters: BTreeMap::new(),
+    gauges: BTreeMap::new(),
+});
+
+struct Metrics {
+    path: Option<PathBuf>,
+    counters: BTreeMap<Key, u64>,
+    gauges: BTreeMap<Key, f64>,
+}
+
+fn key(name: &'static str, labels: &[(&'static str, &str)]) -> Key {
+    let labels = labels
+        .iter()
+        .map(|(label, value)| (*label, value.to_string()))
+        .collect();
+    (name, labels)
+}
+
+impl Metrics {
+    fn increment(&mut self, name: &'static str, labels: &[(&'static str, &str)]) {
+        *self.counters.entry(key(name, labels)).or_default() += 1;
+    }
+
+    fn set(&mut self, name: &'static str, labels: &[(&'static str, &str)], value: f64) {
+        self.gauges.insert(key(name, labels), value);
+    }
+
+    fn render(&self) -> String {
+        let mut text = String::new();
+        render_values(&mut text, "counter", &self.counters);
+        render_values(&mut text, "gauge", &self.gauges);
+        text
+    }
+}
+
+fn render_values<T: std::fmt::Display>(text: &mut String, kind: &str, values: &BTreeMap<Key, T>) {
+    let mut previous = None;
+    for ((name, labels), value) in values {
+        if previous != Some(name) {
+            let _ = writeln!(text, "# TYPE {} {}", name, kind);
+            previous = Some(name);
+        }
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(label, value)| format!("{}=\"{}\"", label, escape(value)))
+            .collect();
+        if labels.is_empty() {
+            let _ = writeln!(text, "{} {}", name, value);
+        } else {
+            let _ = writeln!(text, "{}{{{}}} {}", name, labels.join(","), value);
+        }
+    }
+}
+
+/// Write the metrics to this file from now on
+pub fn configure(path: Option<PathBuf>) {
+    let mut metrics = METRICS.lock();
+    metrics.path = path;
+    write(&metrics);
+}
+
+/// Count one event, e.g. `increment("nexus_orchestrator_closes_total", &[("code", "1000")])`
+pub fn increment(name: &'static str, labels: &[(&'static str, &str)]) {
+    let mut metrics = METRICS.lock();
+    metrics.increment(name, labels);
+    write(&metrics);
+}
+
+/// Set a value that goes up and down, e.g. `set("nexus_orchestrator_rtt_seconds", &[], 0.05)`
+pub fn set(name: &'static str, labels: &[(&'static str, &str)], value: f64) {
+    let mut metrics = METRICS.lock();
+    metrics.set(name, labels, value);
+    write(&metrics);
+}
+
+fn write(metrics: &Metrics) {
+    if let Some(path) = &metrics.path {
+        if let Err(e) = write_atomically(path, &metrics.render()) {
+            eprintln!(
+                "Warning: failed to write metrics to {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+}
+
+// Scrapers must never see a half-written file
+fn write_atomically(path: &Path, text: &str) -> std::io::Result<()> {
+    let temp_path = path.with_extension("prom.tmp");
+    std::fs::write(&temp_path, text)?;
+    std::fs::rename(&temp_path, path)
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that counters and gauges are rendered in the Prometheus text format
+    #[test]
+    fn test_render() {
+        let mut metrics = Metrics {
+            path: None,
+            counters: BTreeMap::new(),
+            gauges: BTreeMap::new(),
+        };
+        metrics.increment("nexus_reconnects_total", &[]);
+        metrics.increment(
+            "nexus_connection_closes_total",
+            &[("code", "1008"), ("reason", "banned \"forever\"")],
+        );
+        metrics.increment("nexus_connection_closes_total", &[("code", "1000")]);
+        metrics.increment("nexus_connection_closes_total", &[("code", "1000")]);
+        metrics.set("nexus_rtt_seconds", &[], 0.5);
+        metrics.set("nexus_rtt_seconds", &[], 0.25);
+
+        assert_eq!(
+            metrics.render(),
+            "# TYPE nexus_connection_closes_total counter\n\
+             nexus_connection_closes_total{code=\"1000\"} 2\n\
+             nexus_connection_closes_total{code=\"1008\",reason=\"banned \\\"forever\\\"\"} 1\n\
+             # TYPE nexus_reconnects_total counter\n\
+             nexus_reconnects_total 1\n\
+             # TYPE nexus_rtt_seconds gauge\n\
+             nexus_rtt_seconds 0.25\n"
+        );
+    }
+}
@@ -0,0 +1,197 @@
+//! Negotiating the protocol spoken with the orchestrator
+//!
+//! The orchestrator understands two protocols over the websocket:
+//!
+//! * version 1, the legacy protocol: the prover sends `ClientProgramProofRequest` progress
+//!   updates, which the orchestrator acknowledges
+//! * version 2, the streaming protocol: the prover registers with a `ProverRequest`, then streams
+//!   `ProverRequest`s with the progress of the current proof and the completed proofs
+//!
+//! In the handshake, the CLI offers the versions it is willing to speak in order of preference,
+//! and the orchestrator answers with the one it picked. Orchestrators that predate the
+//! negotiation don't answer, and speak the legacy protocol. `--protocol` restricts the offer to
+//! one version, so that clients can be upgraded ahead of or behind the orchestrators.
+
+use crate::generated::pb::{
+    proof, prover_request, Network, Progress, Proof, ProverRequest, ProverRequestRegistration,
+    ProverResponse, ProverType,
+};
+use prost::Message as _;
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+/// A protocol version both sides can speak
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Legacy,
+    Streaming,
+}
+
+impl Protocol {
+    pub fn version(&self) -> u32 {
+        match self {
+            Protocol::Legacy => 1,
+            Protocol::Streaming => 2,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Protocol::Legacy => "legacy",
+            Protocol::Streaming => "streaming",
+        }
+    }
+}
+
+/// Which protocols to offer in the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ProtocolMode {
+    /// Prefer the streaming protocol, and fall back to the legacy one
+    Auto,
+    /// Only speak the legacy protocol
+    Legacy,
+    /// Only speak the streaming protocol, refusing orchestrators that don't
+    Streaming,
+}
+
+impl ProtocolMode {
+    /// The protocols to offer, in order of preference
+    pub fn offered(&self) -> &'static [Protocol] {
+        match self {
+            ProtocolMode::Auto => &[Protocol::Streaming, Protocol::Legacy],
+            ProtocolMode::Legacy => &[Protocol::Legacy],
+            ProtocolMode::Streaming => &[Protocol::Streaming],
+        }
+    }
+
+    /// The offer as sent in the handshake, e.g. "2, 1"
+    pub fn offer(&self) -> String {
+        let versions: Vec<String> = self
+            .offered()
+            .iter()
+            .map(|protocol| protocol.version().to_string())
+            .collect();
+        versions.join(", ")
+    }
+
+    /// The protocol the orchestrator picked, given the version in its handshake response
+    pub fn negotiate(&self, picked: Option<&str>) -> Result<Protocol, String> {
+        match picked.map(str::trim) {
+            // The orchestrator predates the negotiation
+            None if self.offered().contains(&Protocol::Legacy) => Ok(Protocol::Legacy),
+            None => Err(
+                "The orchestrator only speaks the legacy protocol, and --protocol \
+                         streaming was given"
+                    .into(),
+            ),
+            Some(version) => self
+                .offered()
+                .iter()
+                .copied()
+                .find(|protocol| protocol.version().to_string() == version)
+                .ok_or_else(|| {
+                    format!(
+                        "The orchestrator picked protocol version {}, which was not offered ({})",
+                        version,
+                        self.offer()
+                    )
+                }),
+        }
+    }
+}
+
+fn prover_request(contents: prover_request::Contents) -> Message {
+    let request = ProverRequest {
+        contents: Some(contents),
+    };
+    Message::Binary(request.encode_to_vec())
+}
+
+/// The first message of a streaming connection, introducing the prover
+pub fn registration(prover_id: &str) -> Message {
+    prover_request(prover_request::Contents::Registration(
+        ProverRequestRegistration {
+            prover_type: ProverType::Volunteer as i32,
+            prover_id: prover_id.to_string(),
+            estimated_proof_cycles_hertz: None,
+            network: Network::Unspecified as i32,
+        },
+    ))
+}
+
+/// The progress of the current proof, for the streaming protocol
+pub fn progress(progress: Progress) -> Message {
+    prover_request(prover_request::Contents::Progress(progress))
+}
+
+/// A completed proof, for the streaming protocol
+pub fn completed_proof(nova_bytes: Vec<u8>) -> Message {
+    prover_request(prover_request::Contents::Proof(Proof {
+        proof: Some(proof::Proof::NovaBytes(nova_bytes)),
+    }))
+}
+
+/// Handle a message of a streaming orchestrator. Assigned work is not taken on yet: the prover
+/// keeps choosing its own programs.
+pub fn handle_streaming_message(message: &Message) {
+    let Message::Binary(data) = message else {
+        return;
+    };
+    if let Ok(ProverResponse {
+        to_prove: Some(_), ..
+    }) = ProverResponse::decode(data.as_slice())
+    {
+        println!("\tThe orchestrator assigned a program, which this version doesn't prove yet");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that the orchestrator's pick is accepted only if it was offered
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(ProtocolMode::Auto.offer(), "2, 1");
+        assert_eq!(
+            ProtocolMode::Auto.negotiate(Some("2")),
+            Ok(Protocol::Streaming)
+        );
+        assert_eq!(
+            ProtocolMode::Auto.negotiate(Some(" 1")),
+            Ok(Protocol::Legacy)
+        );
+        assert_eq!(ProtocolMode::Auto.negotiate(None), Ok(Protocol::Legacy));
+        assert!(ProtocolMode::Auto.negotiate(Some("3")).is_err());
+
+        assert_eq!(ProtocolMode::Legacy.offer(), "1");
+        assert_eq!(ProtocolMode::Legacy.negotiate(None), Ok(Protocol::Legacy));
+        assert!(ProtocolMode::Legacy.negotiate(Some("2")).is_err());
+
+        // Forcing the streaming protocol refuses orchestrators that predate it
+        assert_eq!(ProtocolMode::Streaming.offer(), "2");
+        assert_eq!(
+            ProtocolMode::Streaming.negotiate(Some("2")),
+            Ok(Protocol::Streaming)
+        );
+        assert_eq!(
+            ProtocolMode::Streaming.negotiate(None),
+            Err(
+                "The orchestrator only speaks the legacy protocol, and --protocol streaming \
+                 was given"
+                    .into()
+            )
+        );
+    }
+
+    /// Tests that the registration introduces the prover
+    #[test]
+    fn test_registration() {
+        let request =
+            ProverRequest::decode(&registration("happy-prover-42").into_data()[..]).unwrap();
+        let Some(prover_request::Contents::Registration(registration)) = request.contents else {
+            panic!("Expected a registration");
+        };
+        assert_eq!(registration.prover_id, "happy-prover-42");
+        assert_eq!(registration.prover_type, ProverType::Volunteer as i32);
+    }
+}
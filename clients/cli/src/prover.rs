@@ -1,41 +1,48 @@
 // Copyright (c) 2024 Nexus. All rights reserved.
 
 mod analytics;
+mod checkpoint;
 mod config;
 mod connection;
+mod delivery;
+mod failover;
 mod generated;
+mod jobs;
+mod keepalive;
+mod metrics;
+mod protocol;
 mod prover_id_manager;
+mod proxy;
+mod state;
+mod tls;
+mod transport;
 mod updater;
 pub mod utils;
 mod websocket;
 
-use crate::analytics::track;
-
-use std::borrow::Cow;
-
-use crate::connection::{
-    connect_to_orchestrator_with_infinite_retry, connect_to_orchestrator_with_limited_retry,
-};
-
-use clap::Parser;
+use crate::analytics::{track, AnalyticsSettings, Event};
+use crate::checkpoint::ResumeMode;
+use crate::connection::ConnectionSettings;
+use crate::failover::{FailoverConnector, FailoverSettings};
+use crate::jobs::{Finished, JobRunner, JobSettings, StepProver};
+use crate::keepalive::KeepaliveSettings;
+use crate::protocol::ProtocolMode;
+use crate::proxy::ProxySettings;
+use crate::tls::TlsSettings;
+use crate::transport::Connector;
+use crate::utils::retry::RetryPolicy;
+use crate::websocket::WebSocketConnector;
+
+use clap::{Parser, Subcommand};
 use colored::Colorize;
-use futures::{SinkExt, StreamExt};
 
-use generated::pb::ClientProgramProofRequest;
-use prost::Message as _;
 use serde_json::json;
-use std::time::Instant;
-// Network connection types for WebSocket communication
-
-// WebSocket protocol types for message handling
-use tokio_tungstenite::tungstenite::protocol::{
-    frame::coding::CloseCode, // Status codes for connection closure (e.g., 1000 for normal)
-    CloseFrame,               // Frame sent when closing connection (includes code and reason)
-    Message,                  // Different types of WebSocket messages (Binary, Text, Ping, etc.)
-};
+use std::sync::Arc;
+use std::time::Duration;
 use tracing_subscriber::fmt::format::FmtSpan;
 use tracing_subscriber::EnvFilter;
 
+use ark_serialize::CanonicalDeserialize;
 use nexus_core::{
     nvm::{
         interactive::{parse_elf, trace},
@@ -46,29 +53,36 @@ use nexus_core::{
         init_circuit_trace, key::CanonicalSerialize, pp::gen_vm_pp, prove_seq_step, types::*,
     },
 };
+use semver::Version;
 use std::fs;
 use std::fs::File;
 use std::io::Read;
+use std::path::PathBuf;
 use base64::Engine;
 use chrono::Local;
 use zstd::stream::Encoder;
 
-use crate::utils::updater::AutoUpdaterMode;
+use crate::state::{StateDirs, StateLock};
+use crate::utils::experiment::ExperimentRegistry;
+use crate::utils::updater::policy::UpdatePolicy;
+use crate::utils::updater::{AutoUpdaterMode, LaunchSpec, UpdateCoordinator, UpdaterConfig};
 use base64::prelude::BASE64_URL_SAFE;
 // The interval at which to send updates to the orchestrator
 const PROOF_PROGRESS_UPDATE_INTERVAL_IN_SECONDS: u64 = 180; // 3 minutes
 
 #[derive(Parser, Debug)]
 struct Args {
-    /// Hostname at which Orchestrator can be reached
-    #[arg(default_value_t = String::from("beta.orchestrator.nexus.xyz"))]
-    hostname: String,
+    /// Hostnames (or host:port pairs, or websocket URLs) at which Orchestrators can be reached,
+    /// in order of preference. Defaults to the endpoints in the configuration file, or to
+    /// beta.orchestrator.nexus.xyz
+    #[arg(value_delimiter = ',')]
+    hostnames: Vec<String>,
 
     // 运行标识 用于鉴别不同的用户 比如 1，2，3，方便批量运行
     #[arg(short, long, default_value_t = String::from("1"))]
     run_id: String,
 
-    /// Port over which to communicate with Orchestrator
+    /// Port over which to communicate with Orchestrators given without one
     #[arg(short, long, default_value_t = 443u16)]
     port: u16,
 
@@ -79,6 +93,192 @@ struct Args {
     /// Mode for the auto updater (production/test)
     #[arg(short, long, value_enum, default_value_t = AutoUpdaterMode::Production)]
     updater_mode: AutoUpdaterMode,
+
+    /// Check for new versions in the background and restart into them between jobs
+    #[arg(long, default_value_t = false)]
+    auto_update: bool,
+
+    /// Save a checkpoint of the proof in progress every N steps (0 disables checkpointing)
+    #[arg(long, default_value_t = 2)]
+    checkpoint_interval: usize,
+
+    /// What to do with an unfinished proof found on startup
+    #[arg(long, value_enum, default_value_t = ResumeMode::Ask)]
+    resume: ResumeMode,
+
+    /// Which protocol to speak with the orchestrator: negotiated in the handshake, or forced
+    #[arg(long, value_enum, default_value_t = ProtocolMode::Auto)]
+    protocol: ProtocolMode,
+
+    /// Don't send any analytics (the DO_NOT_TRACK environment variable is honored as well)
+    #[arg(long, global = true)]
+    no_analytics: bool,
+
+    /// Keep configuration, state and caches in this directory instead of the platform's defaults
+    #[arg(long, global = true, value_name = "DIR")]
+    state_dir: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Inspect the analytics sent by the CLI
+    Analytics {
+        #[command(subcommand)]
+        action: AnalyticsCommand,
+    },
+    /// Inspect and override the experiments that decide which program is proven
+    Experiments {
+        #[command(subcommand)]
+        action: ExperimentsCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum AnalyticsCommand {
+    /// Print exactly what would be sent, without sending anything
+    Preview,
+    /// Print the JSON schema of the analytics events
+    Schema,
+}
+
+#[derive(Subcommand, Debug)]
+enum ExperimentsCommand {
+    /// List the configured experiments and the overrides pinned on this machine
+    List,
+    /// Show how a prover ID is bucketed into each experiment and which program it gets
+    Explain { prover_id: String },
+    /// Pin an experiment arm on this machine for testing, e.g. NEX-1=cancer-diagnostic
+    /// (use the arm "control" to stay out of the experiment)
+    Override {
+        #[arg(value_name = "NAME=ARM", required_unless_present = "remove")]
+        assignment: Option<String>,
+        /// Remove the override of this experiment instead
+        #[arg(long, value_name = "NAME", conflicts_with = "assignment")]
+        remove: Option<String>,
+    },
+}
+
+fn run_command(
+    command: &Command,
+    args: &Args,
+    ws_addr_string: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        Command::Analytics {
+            action: AnalyticsCommand::Preview,
+        } => {
+            let prover_id = prover_id_manager::read_prover_id_custom(&args.run_id)
+                .ok()
+                .flatten()
+                .unwrap_or_else(|| String::from("<prover id>"));
+            analytics::print_preview(ws_addr_string, &prover_id);
+        }
+        Command::Analytics {
+            action: AnalyticsCommand::Schema,
+        } => {
+            let schema = analytics::events::schema_document();
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&schema).unwrap_or_default()
+            );
+        }
+        Command::Experiments { action } => run_experiments_command(action)?,
+    }
+    Ok(())
+}
+
+fn run_experiments_command(action: &ExperimentsCommand) -> Result<(), Box<dyn std::error::Error>> {
+    let registry = utils::experiment::registry();
+    match action {
+        ExperimentsCommand::List => {
+            for experiment in registry.experiments() {
+                let programs: Vec<String> = experiment
+                    .programs
+                    .iter()
+                    .map(|(program, weight)| format!("{} (weight {})", program, weight))
+                    .collect();
+                println!(
+                    "{}: {} per 1000 provers, layer {}, programs: {}",
+                    experiment.name.bold(),
+                    experiment.target_enrollment_per_1000,
+                    experiment.layer.as_deref().unwrap_or("none"),
+                    programs.join(", ")
+                );
+            }
+            println!("Default program: {}", registry.default_program());
+            for (name, arm) in registry.overrides() {
+                println!(
+                    "{}",
+                    format!("Overridden on this machine: {}={}", name, arm).yellow()
+                );
+            }
+        }
+        ExperimentsCommand::Explain { prover_id } => {
+            for experiment in registry.experiments() {
+                let buckets = experiment.enrolled_buckets();
+                let bucket = experiment.enrollment_bucket(prover_id);
+                let outcome = if experiment.is_enrolled(prover_id) {
+                    format!("enrolled, program {}", experiment.program_for(prover_id))
+                } else {
+                    "not enrolled".to_string()
+                };
+                println!(
+                    "{}: bucket {} (enrolled buckets {}..{}): {}",
+                    experiment.name.bold(),
+                    bucket,
+                    buckets.start,
+                    buckets.end,
+                    outcome
+                );
+                if let Some(arm) = registry.overrides().get(&experiment.name) {
+                    println!(
+                        "{}",
+                        format!("  overridden on this machine: {}", arm).yellow()
+                    );
+                }
+            }
+
+            let assignment = registry.assign(prover_id);
+            println!(
+                "Program for {}: {} ({})",
+                prover_id,
+                assignment.program.bold(),
+                match (&assignment.experiment, assignment.overridden) {
+                    (Some(name), true) => format!("pinned by an override of {}", name),
+                    (Some(name), false) => format!("decided by {}", name),
+                    (None, _) => "default program".to_string(),
+                }
+            );
+        }
+        ExperimentsCommand::Override { assignment, remove } => {
+            let path = utils::experiment::overrides_path()
+                .ok_or("Could not determine the home directory")?;
+            let mut overrides = utils::experiment::load_overrides(&path)?;
+            if let Some(name) = remove {
+                if overrides.remove(name).is_none() {
+                    println!("Experiment {} was not overridden.", name);
+                    return Ok(());
+                }
+                println!("Removed the override of {}.", name);
+            } else if let Some(assignment) = assignment {
+                let (name, arm) = assignment
+                    .split_once('=')
+                    .ok_or("Expected an override of the form NAME=ARM")?;
+                // Validate against a copy so a bad override is never saved
+                registry.clone().set_override(name, arm)?;
+                overrides.insert(name.to_string(), arm.to_string());
+                println!(
+                    "Pinned {} to {} on this machine. Analytics events will report the override.",
+                    name, arm
+                );
+            }
+            utils::experiment::save_overrides(&path, &overrides)?;
+        }
+    }
+    Ok(())
 }
 
 fn get_file_as_byte_vec(filename: &str) -> Vec<u8> {
@@ -90,6 +290,86 @@ fn get_file_as_byte_vec(filename: &str) -> Vec<u8> {
     buffer
 }
 
+/// Proves programs with Nova, one step of the trace at a time
+struct NovaProver {
+    pp: SeqPP,
+    k: usize,
+    trace: Option<Tr>,
+    proof: Option<IVCProof>,
+}
+
+impl NovaProver {
+    fn new(pp: SeqPP, k: usize) -> Self {
+        Self {
+            pp,
+            k,
+            trace: None,
+            proof: None,
+        }
+    }
+
+    fn circuit_trace(&self) -> &Tr {
+        self.trace.as_ref().expect("no program loaded")
+    }
+}
+
+impl StepProver for NovaProver {
+    fn load(&mut self, program_name: &str, input: &[u8]) -> usize {
+        let program_file_path = &format!("src/generated/{}", program_name);
+        let mut vm: NexusVM<MerkleTrie> =
+            parse_elf(get_file_as_byte_vec(program_file_path).as_ref())
+                .expect("error loading and parsing RISC-V instruction");
+        vm.syscalls.set_input(input);
+
+        let completed_trace = trace(&mut vm, self.k, false).expect("error generating trace");
+        let tr = init_circuit_trace(completed_trace).expect("error initializing circuit trace");
+        let total_steps = tr.steps();
+        self.trace = Some(tr);
+        total_steps
+    }
+
+    fn start(&mut self, start: usize) {
+        let z_st = self
+            .circuit_trace()
+            .input(start)
+            .expect("error starting circuit trace");
+        self.proof = Some(IVCProof::new(&z_st));
+    }
+
+    fn restore(&mut self, proof: &[u8]) -> bool {
+        self.proof = IVCProof::deserialize_compressed(proof).ok();
+        self.proof.is_some()
+    }
+
+    fn prove_step(&mut self) {
+        let tr = self.trace.as_ref().expect("no program loaded");
+        self.proof =
+            Some(prove_seq_step(self.proof.take(), &self.pp, tr).expect("error proving step"));
+    }
+
+    fn proof_bytes(&self) -> Result<Vec<u8>, String> {
+        let mut proof_bytes = Vec::new();
+        self.proof
+            .as_ref()
+            .ok_or("no proof started")?
+            .serialize_compressed(&mut proof_bytes)
+            .map_err(|e| e.to_string())?;
+        Ok(proof_bytes)
+    }
+
+    fn compressed_proof(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let mut writer = Box::new(&mut buf);
+        let mut encoder = Encoder::new(&mut writer, 0).expect("failed to create encoder");
+        self.proof
+            .as_ref()
+            .expect("no proof started")
+            .serialize_compressed(&mut encoder)
+            .expect("failed to compress proof");
+        encoder.finish().expect("failed to finish encoder");
+        buf
+    }
+}
 
 fn generate_firebase_client() -> String {
     // 获取当前日期，格式为 YYYY-MM-DD
@@ -110,6 +390,85 @@ fn generate_firebase_client() -> String {
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Captured before anything else, so an auto-update restarts the CLI exactly as it was started
+    let launch = LaunchSpec::capture();
+    let args = Args::parse();
+
+    let state_dirs = StateDirs::resolve(args.state_dir.as_deref());
+    if let Some(dirs) = &state_dirs {
+        state::migrate_legacy_files(
+            dirs,
+            home::home_dir()
+                .filter(|path| !path.as_os_str().is_empty())
+                .as_deref(),
+            &launch.current_dir,
+        );
+    }
+    state::configure(state_dirs.clone());
+
+    let cli_config = config::load_cli_config()?;
+    // Before anything makes HTTP requests
+    let proxy_settings =
+        ProxySettings::from_config(&cli_config.connection, |name| std::env::var(name).ok())
+            .map_err(|e| format!("Invalid proxy settings: {}", e))?;
+    proxy::configure(proxy_settings.clone());
+
+    let endpoints =
+        failover::resolve_endpoints(&args.hostnames, args.port, &cli_config.connection).await?;
+    // The primary endpoint, which also decides where analytics are sent
+    let ws_addr_string = endpoints[0].clone();
+
+    let analytics_settings = AnalyticsSettings::resolve(&cli_config.analytics, args.no_analytics)
+        .map_err(|e| format!("Invalid analytics settings: {}", e))?;
+    analytics::configure(
+        analytics_settings,
+        analytics::sinks::build_sinks(&cli_config.analytics.sinks, &ws_addr_string),
+    );
+
+    let mut experiments =
+        ExperimentRegistry::from_config(&cli_config.experiments).unwrap_or_else(|e| {
+            eprintln!(
+                "Warning: ignoring invalid experiments in the configuration file: {}",
+                e
+            );
+            ExperimentRegistry::default()
+        });
+    let overrides = utils::experiment::overrides_path()
+        .map(|path| utils::experiment::load_overrides(&path))
+        .transpose()
+        .unwrap_or_else(|e| {
+            eprintln!("Warning: ignoring invalid experiment overrides: {}", e);
+            None
+        })
+        .unwrap_or_default();
+    for (name, arm) in &overrides {
+        if let Err(e) = experiments.set_override(name, arm) {
+            eprintln!(
+                "Warning: ignoring experiment override {}={}: {}",
+                name, arm, e
+            );
+        }
+    }
+    utils::experiment::configure(experiments);
+
+    if let Some(command) = &args.command {
+        return run_command(command, &args, &ws_addr_string);
+    }
+
+    // Only one process at a time may use the state of a run
+    let run_dir = state_dirs.map(|dirs| dirs.run_dir(&args.run_id));
+    let state_lock = run_dir.as_deref().map(StateLock::acquire).transpose()?;
+
+    // For scrapers such as node_exporter's textfile collector
+    metrics::configure(run_dir.as_ref().map(|run_dir| run_dir.join("metrics.prom")));
+
+    // Events that could not be delivered are kept here until the next run
+    analytics::start(
+        run_dir
+            .as_ref()
+            .map(|run_dir| run_dir.join("analytics-spool.jsonl")),
+    );
+
     // Print the banner at startup
     utils::cli_branding::print_banner();
 
@@ -124,15 +483,6 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .with_span_events(FmtSpan::CLOSE)
         .init();
 
-    let args = Args::parse();
-
-    let ws_addr_string = format!(
-        "{}://{}:{}/prove",
-        if args.port == 443 { "wss" } else { "ws" },
-        args.hostname,
-        args.port
-    );
-
     let k = 4;
     // TODO(collinjackson): Get parameters from a file or URL.
     let pp = gen_vm_pp::<C1, seq::SetupParams<(G1, G2, C1, C2, RO, SC)>>(k as usize, &())
@@ -145,21 +495,97 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         prover_id.bright_cyan()
     );
 
+    // Updates are installed in the background and applied between jobs
+    let mut updater_config = UpdaterConfig::new(args.updater_mode, launch);
+    if let Some(run_dir) = &run_dir {
+        updater_config.pid_file = run_dir.join("prover.pid");
+    }
+    updater_config.policy = UpdatePolicy::from_config(&cli_config.updates).unwrap_or_else(|e| {
+        eprintln!(
+            "Warning: ignoring the invalid update policy in the configuration file: {}",
+            e
+        );
+        UpdatePolicy::default()
+    });
+    if let Some(source) = &cli_config.updates.source {
+        updater_config.version_source = utils::updater::source::from_config(source);
+    }
+    updater_config.release_manifest_url = cli_config.updates.release_manifest_url.clone();
+    let update_coordinator = Arc::new(UpdateCoordinator::default());
+    if args.auto_update {
+        let (notify_prover_id, notify_ws_addr_string) = (prover_id.clone(), ws_addr_string.clone());
+        let on_update_available = move |current: &Version, available: &Version| {
+            track(
+                Event::UpdateAvailable {
+                    prover_id: notify_prover_id.clone(),
+                    current_version: current.to_string(),
+                    available_version: available.to_string(),
+                },
+                &notify_ws_addr_string,
+                false,
+            )
+        };
+        if let Err(e) = updater::spawn_auto_update_thread(
+            &updater_config,
+            update_coordinator.clone(),
+            on_update_available,
+        ) {
+            eprintln!("Failed to start the auto-updater: {}", e);
+        }
+    }
+
     println!(
         "\n===== {}...\n",
         "Connecting to Nexus Network".bold().underline()
     );
 
     track(
-        "connect".into(),
-        format!("Connecting to {}...", &ws_addr_string),
+        Event::Connect {
+            prover_id: prover_id.clone(),
+        },
         &ws_addr_string,
-        json!({"prover_id": prover_id}),
         false,
     );
 
-    // Connect to the Orchestrator with exponential backoff
-    let mut client = connect_to_orchestrator_with_infinite_retry(&ws_addr_string, &prover_id).await;
+    // Connect to the Orchestrator with exponential backoff, failing over between the endpoints
+    let connection_settings = ConnectionSettings {
+        tls: TlsSettings::from_config(&cli_config.connection.tls)
+            .map_err(|e| format!("Invalid TLS settings in the configuration file: {}", e))?,
+        proxy: proxy_settings,
+        prover_id: Some(prover_id.clone()),
+        auth_token: cli_config.connection.auth_token.clone(),
+        protocol: args.protocol,
+    };
+    let connector = FailoverConnector::new(
+        endpoints
+            .iter()
+            .map(|url| {
+                Box::new(WebSocketConnector {
+                    url: url.clone(),
+                    settings: connection_settings.clone(),
+                }) as Box<dyn Connector>
+            })
+            .collect(),
+        FailoverSettings::from_config(&cli_config.connection.failover),
+    );
+    let job_settings = JobSettings {
+        prover_id: prover_id.clone(),
+        ws_addr_string: ws_addr_string.clone(),
+        k,
+        checkpoint_path: checkpoint::checkpoint_path(&args.run_id),
+        checkpoint_interval: args.checkpoint_interval,
+        just_once: args.just_once,
+        auto_update: args.auto_update,
+        update_interval: Duration::from_secs(PROOF_PROGRESS_UPDATE_INTERVAL_IN_SECONDS),
+    };
+    let mut runner = JobRunner::connect(
+        job_settings,
+        &connector,
+        RetryPolicy::from_config(&cli_config.connection.retry),
+        KeepaliveSettings::from_config(&cli_config.connection.keepalive),
+        update_coordinator.clone(),
+    )
+    .await;
 
     println!(
         "\n{}",
@@ -167,255 +593,96 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     );
 
     track(
-        "register".into(),
-        format!("Your current prover identifier is {}.", prover_id),
+        Event::Register {
+            prover_id: prover_id.clone(),
+        },
         &ws_addr_string,
-        json!({"ws_addr_string": ws_addr_string, "prover_id": prover_id}),
         false,
     );
 
-    let mut queued_proof_duration_millis = 0;
-    let mut queued_steps_proven: i32 = 0;
-    let mut timer_since_last_orchestrator_update = Instant::now();
+    // Look for a job that was interrupted by a crash or restart
+    runner.resume(args.resume);
 
     println!(
         "\n===== {}...\n",
         "Starting proof generation for programs".bold().underline()
     );
 
+    let mut prover = NovaProver::new(pp, k as usize);
+    let mut restarted = false;
+    // Set when the orchestrator closes the connection for good
+    let mut stopped = None;
     loop {
-        // Create the inputs for the program
-        use rand::Rng; // Required for .gen() methods
-        let mut rng = rand::thread_rng();
-        let input = vec![5, rng.gen::<u8>(), rng.gen::<u8>()];
-
-        let program_name = utils::prover::get_program_for_prover(&prover_id);
-        let program_file_path = &format!("src/generated/{}", program_name);
-
-        let mut vm: NexusVM<MerkleTrie> =
-            parse_elf(get_file_as_byte_vec(program_file_path).as_ref())
-                .expect("error loading and parsing RISC-V instruction");
-        vm.syscalls.set_input(&input);
-
-        // TODO(collinjackson): Get outputs
-        let completed_trace = trace(&mut vm, k as usize, false).expect("error generating trace");
-        let tr = init_circuit_trace(completed_trace).expect("error initializing circuit trace");
-
-        let total_steps = tr.steps();
-        let start = 0;
-        let steps_to_prove = 10;
-        let mut end: usize = start + steps_to_prove;
-        if end > total_steps {
-            end = total_steps
-        }
-
-        let z_st = tr.input(start).expect("error starting circuit trace");
-        let mut proof = IVCProof::new(&z_st);
-
-        let mut steps_proven = 0;
-
-        println!(
-            "Program trace is {} steps. Proving {} steps starting at {}...",
-            total_steps, steps_to_prove, start
-        );
-
-        let start_time = Instant::now();
-        let mut progress_time = start_time;
-        for step in start..end {
-            proof = prove_seq_step(Some(proof), &pp, &tr).expect("error proving step");
-            steps_proven += 1;
-
-            let progress_duration = progress_time.elapsed();
-            let proof_cycles_hertz = k as f64 * 1000.0 / progress_duration.as_millis() as f64;
-
-            //update the queued variables
-            queued_proof_duration_millis += progress_duration.as_millis() as i32;
-            queued_steps_proven += steps_proven;
-
-            let progress = ClientProgramProofRequest {
-                steps_in_trace: total_steps as i32,
-                steps_proven: queued_steps_proven,
-                step_to_start: start as i32,
-                program_id: program_name.clone(),
-                client_id_token: None,
-                proof_duration_millis: queued_proof_duration_millis,
-                k,
-                cli_prover_id: Some(prover_id.clone()),
-            };
-
-            // Print the proof progress in green or blue depending on the step number
-            println!(
-                "\t✓ Proved step {} at {:.2} proof cycles/sec.",
-                step, proof_cycles_hertz
-            );
-
-            progress_time = Instant::now();
-
-            //If it has been three minutes since the last orchestrator update, send the orchestator the update
-            if timer_since_last_orchestrator_update.elapsed().as_secs()
-                > PROOF_PROGRESS_UPDATE_INTERVAL_IN_SECONDS
-            {
-                println!(
-                    "\tWill try sending update to orchestrator with interval queued_steps_proven: {}",
-                    queued_steps_proven
-                );
-
-                // Send ping to the websocket connection and wait for pong
-                match client.send(Message::Ping(vec![])).await {
-                    //The ping was succesfully sent...
+        match runner.run(&mut prover).await {
+            Finished::Done => break,
+            Finished::Stopped(error) => {
+                stopped = Some(error);
+                break;
+            }
+            Finished::Update(update) => {
+                // Waiting for the new process to be ready takes minutes, during which the
+                // keepalive has to go on running on the other workers
+                let restarted_into = tokio::task::block_in_place(|| {
+                    utils::updater::restart_cli_process_with_new_version(&update, &updater_config)
+                });
+                match restarted_into {
                     Ok(_) => {
-                        //...wait for pong response from websocket with timeout...
-                        match tokio::time::timeout(std::time::Duration::from_secs(5), client.next())
-                            .await
-                        {
-                            //... and the pong was received
-                            Ok(Some(Ok(Message::Pong(_)))) => {
-                                // Connection is verified working
-                                match client.send(Message::Binary(progress.encode_to_vec())).await {
-                                    Ok(_) => {
-                                        // println!("\t\tSuccesfully sent progress to orchestrator\n");
-                                        // println!("{:#?}", progress);
-
-                                        // Reset the queued values only after successful send
-                                        queued_steps_proven = 0;
-                                        queued_proof_duration_millis = 0;
-                                    }
-                                    Err(_) => {
-                                        client = match connect_to_orchestrator_with_limited_retry(
-                                            &ws_addr_string,
-                                            &prover_id,
-                                        )
-                                        .await
-                                        {
-                                            Ok(new_client) => new_client,
-                                            Err(_) => {
-                                                // Continue using the existing client and try again next update
-                                                client
-                                            }
-                                        };
-
-                                        // Don't reset queued values on failure
-                                    }
-                                }
-                            }
-                            //... and the pong was not received
-                            _ => {
-                                // println!(
-                                //     "\t\tNo pong from websockets connection received. Will reconnect to orchestrator..."
-                                // );
-                                client = match connect_to_orchestrator_with_limited_retry(
-                                    &ws_addr_string,
-                                    &prover_id,
-                                )
-                                .await
-                                {
-                                    Ok(new_client) => new_client,
-                                    Err(_) => {
-                                        // Continue using the existing client and try again next update
-                                        client
-                                    }
-                                };
-                            }
-                        }
+                        restarted = true;
+                        break;
                     }
-                    //The ping failed to send...
-                    Err(_) => {
-                        // println!(
-                        //     "\t\tPing failed, will attempt to reconnect to orchestrator: {:?}",
-                        //     e
-                        // );
-                        client = match connect_to_orchestrator_with_limited_retry(
-                            &ws_addr_string,
-                            &prover_id,
-                        )
-                        .await
-                        {
-                            Ok(new_client) => new_client,
-                            Err(_) => {
-                                // Continue using the existing client and try again next update
-                                client
-                            }
-                        };
+                    Err(e) => {
+                        eprintln!(
+                            "Failed to restart into version {}, continuing with this one: {}",
+                            update.version, e
+                        );
+                        update_coordinator.clear_pending();
+                        // The new process may have taken over the lock before it was stopped
+                        if let Some(Err(e)) = state_lock.as_ref().map(StateLock::reclaim) {
+                            eprintln!("Failed to take back the state lock: {}", e);
+                        }
                     }
                 }
-
-                //reset the timer regardless of success (to avoid spam)
-                timer_since_last_orchestrator_update = Instant::now()
-            }
-
-            if step == end - 1 {
-                let mut buf = Vec::new();
-                let mut writer = Box::new(&mut buf);
-                let mut encoder = Encoder::new(&mut writer, 0).expect("failed to create encoder");
-                proof
-                    .serialize_compressed(&mut encoder)
-                    .expect("failed to compress proof");
-                encoder.finish().expect("failed to finish encoder");
-
-                let total_duration = start_time.elapsed();
-                let total_minutes = total_duration.as_secs() as f64 / 60.0;
-                let cycles_proved = steps_proven * k;
-                let proof_cycles_per_minute = cycles_proved as f64 / total_minutes;
-
-                // Send analytics about the proof event
-                track(
-                    "proof".into(),
-                    "Proof generated".into(),
-                    &ws_addr_string,
-                    json!({
-                        "steps_in_trace": total_steps,
-                        "steps_to_prove": steps_to_prove,
-                        "steps_proven": steps_proven,
-                        "cycles_proven": cycles_proved,
-                        "k": k,
-                        "proof_duration_sec": total_duration.as_secs(),
-                        "proof_duration_millis": total_duration.as_millis(),
-                        "proof_cycles_per_minute": proof_cycles_per_minute,
-                        "program_name": program_name,
-                    }),
-                    false,
-                );
             }
         }
-        // TODO(collinjackson): Consider verifying the proof before sending it
-        // proof.verify(&public_params, proof.step_num() as _).expect("error verifying execution")
+    }
 
-        if args.just_once {
-            break;
-        } else {
-            println!("\n\nWaiting for a new program to prove...\n");
-        }
+    // The orchestrator has already closed the connection if it stopped the prover
+    let close_result = if stopped.is_some() {
+        Ok(())
+    } else {
+        runner.close().await
+    };
+    match &close_result {
+        Err(e) => track(
+            Event::CloseError {
+                prover_id: prover_id.clone(),
+                program_name: utils::prover::get_program_for_prover(&prover_id),
+                error: e.to_string(),
+            },
+            &ws_addr_string,
+            true,
+        ),
+        Ok(_) => track(
+            Event::Disconnect {
+                prover_id: prover_id.clone(),
+                program_name: utils::prover::get_program_for_prover(&prover_id),
+            },
+            &ws_addr_string,
+            true,
+        ),
     }
 
-    client
-        .close(Some(CloseFrame {
-            code: CloseCode::Normal,
-            reason: Cow::Borrowed("Finished proving."),
-        }))
-        .await
-        .map_err(|e| {
-            track(
-                "close_error".into(),
-                "Failed to close WebSocket connection".into(),
-                &ws_addr_string,
-                json!({
-                    "prover_id": &prover_id,
-                    "program_name": utils::prover::get_program_for_prover(&prover_id),
-                    "error": e.to_string(),
-                }),
-                true,
-            );
-            format!("Failed to close WebSocket connection: {}", e)
-        })?;
-    track(
-        "disconnect".into(),
-        "Sent proof and closed connection...".into(),
-        &ws_addr_string,
-        json!({
-            "prover_id": prover_id,
-            "program_name": utils::prover::get_program_for_prover(&prover_id),
-        }),
-        true,
-    );
+    // Deliver (or spool) the remaining analytics before exiting
+    analytics::shutdown().await;
+
+    // The new version has taken over, so a failed close doesn't matter anymore
+    if restarted {
+        return Ok(());
+    }
+
+    if let Some(error) = stopped {
+        return Err(error.into());
+    }
+    close_result.map_err(|e| format!("Failed to close WebSocket connection: {}", e))?;
     Ok(())
 }
@@ -1,29 +1,28 @@
+use crate::generate_firebase_client;
 use colored::Colorize;
+use fireauth::RequestCustomData;
 use rand::RngCore;
 use random_word::Lang;
 use std::{fs, path::Path, path::PathBuf};
-use fireauth::RequestCustomData;
-use crate::generate_firebase_client;
 
 /// Gets an existing prover ID from the filesystem or generates a new one
 /// This is the main entry point for getting a prover ID
 pub fn get_or_generate_prover_id() -> String {
     let default_prover_id = generate_default_id();
 
-    let home_path = match get_home_directory() {
+    let state_dir = match get_state_directory() {
         Ok(path) => path,
         Err(_) => return default_prover_id,
     };
 
-    let nexus_dir = home_path.join(".nexus");
-    let prover_id_path = nexus_dir.join("prover-id");
+    let prover_id_path = state_dir.join("prover-id");
 
-    // 1. If the .nexus directory doesn't exist, we need to create it
-    if !nexus_dir.exists() {
-        return handle_first_time_setup(&nexus_dir, &prover_id_path, &default_prover_id);
+    // 1. If the state directory doesn't exist, we need to create it
+    if !state_dir.exists() {
+        return handle_first_time_setup(&state_dir, &prover_id_path, &default_prover_id);
     }
 
-    // 2. If the .nexus directory exists, we need to read the prover-id file
+    // 2. If the state directory exists, we need to read the prover-id file
     match read_existing_prover_id(&prover_id_path) {
         // 2.1 Happy path - we successfully read the prover-id file
         Ok(id) => {
@@ -54,26 +53,26 @@ fn generate_default_id() -> String {
     )
 }
 
-fn get_home_directory() -> Result<PathBuf, &'static str> {
-    match home::home_dir() {
-        Some(path) if !path.as_os_str().is_empty() => Ok(path),
-        _ => {
-            println!("Could not determine home directory");
-            Err("No home directory found")
+fn get_state_directory() -> Result<PathBuf, &'static str> {
+    match crate::state::current() {
+        Some(dirs) => Ok(dirs.state),
+        None => {
+            println!("Could not determine the state directory");
+            Err("No state directory found")
         }
     }
 }
 
 fn handle_first_time_setup(
-    nexus_dir: &Path,
+    state_dir: &Path,
     prover_id_path: &Path,
     default_prover_id: &str,
 ) -> String {
-    println!("Attempting to create .nexus directory");
-    if let Err(e) = fs::create_dir(nexus_dir) {
+    println!("Attempting to create the state directory");
+    if let Err(e) = fs::create_dir_all(state_dir) {
         eprintln!(
             "{}: {}",
-            "Warning: Failed to create .nexus directory"
+            "Warning: Failed to create the state directory"
                 .to_string()
                 .yellow(),
             e
@@ -143,6 +142,30 @@ fn handle_read_error(e: std::io::Error, path: &Path, default_id: &str) {
     }
 }
 
+/// Where the custom prover ID of a run is saved: `runs/<run_id>/prover-id.json` in the state
+/// directory
+fn prover_id_custom_path(run_id: &str) -> PathBuf {
+    match crate::state::current() {
+        Some(dirs) => dirs.run_dir(run_id).join("prover-id.json"),
+        None => PathBuf::from(format!("prover_id_{}.txt", run_id)),
+    }
+}
+
+/// Reads the custom prover ID saved for a given run ID, without registering a new one.
+///
+/// # Returns
+/// `Ok(None)` if no prover ID has been saved for this run yet
+pub fn read_prover_id_custom(run_id: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let file_path = prover_id_custom_path(run_id);
+    match fs::read_to_string(&file_path) {
+        Ok(content) => {
+            // Parse the JSON content and extract local_id
+            let user_info: fireauth::api::User = serde_json::from_str(&content)?;
+            Ok(Some(user_info.local_id))
+        }
+        Err(_) => Ok(None),
+    }
+}
 
 /// Retrieves or generates a custom prover ID for a given run ID.
 ///
@@ -151,14 +174,14 @@ fn handle_read_error(e: std::io::Error, path: &Path, default_id: &str) {
 ///
 /// # Returns
 /// A Result containing either the prover ID string or an error
-pub async fn get_or_generate_prover_id_custom(run_id: &str) -> Result<String, Box<dyn std::error::Error>>{
-    // Check if prover_id_1.txt exists in the current folder, where 1 is the run_id.
-    let file_path = format!("prover_id_{}.txt", run_id);
+pub async fn get_or_generate_prover_id_custom(
+    run_id: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    // Check if runs/1/prover-id.json exists in the state directory, where 1 is the run_id.
+    let file_path = prover_id_custom_path(run_id);
     // Try to read existing prover ID from JSON file
-    if let Ok(content) = fs::read_to_string(&file_path) {
-        // Parse the JSON content and extract local_id
-        let user_info: fireauth::api::User = serde_json::from_str(&content)?;
-        return Ok(user_info.local_id);
+    if let Some(prover_id) = read_prover_id_custom(run_id)? {
+        return Ok(prover_id);
     }
     // If it doesn't exist, generate a default prover_id and save it to file
     let firebase_client = generate_firebase_client();
@@ -181,11 +204,18 @@ pub async fn get_or_generate_prover_id_custom(run_id: &str) -> Result<String, Bo
 
     let mut combined = serde_json::to_value(&user)?;
     let user_info_value = serde_json::to_value(&user_info)?;
-    if let (serde_json::Value::Object(ref mut combined_map), serde_json::Value::Object(user_info_map)) = (combined, user_info_value) {
+    if let (
+        serde_json::Value::Object(ref mut combined_map),
+        serde_json::Value::Object(user_info_map),
+    ) = (combined, user_info_value)
+    {
         // 合并字段，user_info 的值会覆盖 user 中的重复字段
         combined_map.extend(user_info_map);
         // 序列化并保存
         let json_string = serde_json::to_string_pretty(&combined_map)?;
+        if let Some(parent) = file_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
         fs::write(file_path, json_string).unwrap();
     }
 
@@ -199,9 +229,16 @@ mod tests {
     use std::env;
     use tempfile::TempDir; // This is needed to run tests serially, to remove flakiness
 
+    // Keep the state in `dir` instead of the real state directory
+    fn use_state_dir(dir: &Path) -> PathBuf {
+        let state_dir = dir.join("state");
+        crate::state::configure(crate::state::StateDirs::resolve(Some(&state_dir)));
+        state_dir
+    }
+
     /// Tests the behavior for a first-time user with no existing configuration.
     /// This simulates a new user scenario where:
-    /// 1. No .nexus directory exists yet
+    /// 1. No state directory exists yet
     /// 2. No prover-id file exists yet
     /// 3. The program needs to create both directory and file
     #[test]
@@ -213,14 +250,11 @@ mod tests {
             println!("Directory at start: {:?}", path);
             assert!(path.exists(), "Directory should exist during test");
 
-            // Setup - create temporary home directory
-            let original_home = env::var("HOME").ok();
-            env::set_var("HOME", temp_dir.path());
-            std::thread::sleep(std::time::Duration::from_millis(10)); // Give env time to update
+            // Setup - use a temporary state directory
+            let state_dir = use_state_dir(temp_dir.path());
 
-            // Verify .nexus directory doesn't exist yet
-            let nexus_dir = temp_dir.path().join(".nexus");
-            assert!(!nexus_dir.exists(), "Nexus directory should not exist yet");
+            // Verify the state directory doesn't exist yet
+            assert!(!state_dir.exists(), "State directory should not exist yet");
 
             // Get prover ID - should create directory and file
             let id1 = get_or_generate_prover_id();
@@ -236,10 +270,10 @@ mod tests {
 
             // Verify directory and file were created
             assert!(
-                nexus_dir.exists(),
-                "Nexus directory should have been created"
+                state_dir.exists(),
+                "State directory should have been created"
             );
-            let id_path = nexus_dir.join("prover-id");
+            let id_path = state_dir.join("prover-id");
             assert!(id_path.exists(), "Prover ID file should have been created");
 
             // Verify saved ID matches what we got
@@ -252,11 +286,7 @@ mod tests {
             assert_eq!(id2, id1, "Second call should return same ID");
 
             // Cleanup
-            match original_home {
-                None => env::remove_var("HOME"),
-                Some(home) => env::set_var("HOME", home),
-            }
-            std::thread::sleep(std::time::Duration::from_millis(10)); // Give env time to update
+            crate::state::configure(None);
 
             path // Return the path for checking later
         }; // temp_dir is dropped here, cleaning up
@@ -268,29 +298,26 @@ mod tests {
 
     /// Tests that the function can properly read an existing prover ID configuration.
     /// This simulates a scenario where:
-    /// 1. User already has a .nexus directory
+    /// 1. User already has a state directory
     /// 2. User already has a prover-id file with valid content
     /// 3. Function should read and use the existing ID without modification
     #[test]
     #[serial]
     fn test_read_existing_prover_id() {
-        // Setup - create temporary home directory
-        let original_home = env::var("HOME").ok();
+        // Setup - use a temporary state directory
         let temp_dir = TempDir::new().unwrap();
         println!("Created temp dir: {:?}", temp_dir.path());
-        env::set_var("HOME", temp_dir.path());
-        std::thread::sleep(std::time::Duration::from_millis(10));
+        let state_dir = use_state_dir(temp_dir.path());
 
         // Create pre-existing configuration
-        let nexus_dir = temp_dir.path().join(".nexus");
-        fs::create_dir(&nexus_dir).expect("Failed to create .nexus directory");
+        fs::create_dir(&state_dir).expect("Failed to create the state directory");
 
         let pre_existing_id = "happy-prover-42";
-        let id_path = nexus_dir.join("prover-id");
+        let id_path = state_dir.join("prover-id");
         fs::write(&id_path, pre_existing_id).expect("Failed to create prover-id file");
 
         // Verify our setup worked
-        assert!(nexus_dir.exists(), "Setup: .nexus directory should exist");
+        assert!(state_dir.exists(), "Setup: state directory should exist");
         assert!(id_path.exists(), "Setup: prover-id file should exist");
         assert_eq!(
             fs::read_to_string(&id_path).expect("Setup: should be able to read file"),
@@ -306,7 +333,7 @@ mod tests {
         );
 
         // Verify nothing was modified
-        assert!(nexus_dir.exists(), "Directory should still exist");
+        assert!(state_dir.exists(), "Directory should still exist");
         assert!(id_path.exists(), "File should still exist");
         assert_eq!(
             fs::read_to_string(&id_path).expect("Should still be able to read file"),
@@ -315,11 +342,7 @@ mod tests {
         );
 
         // Cleanup
-        match original_home {
-            None => env::remove_var("HOME"),
-            Some(home) => env::set_var("HOME", home),
-        }
-        std::thread::sleep(std::time::Duration::from_millis(10));
+        crate::state::configure(None);
     }
 
     /// Tests handling of corrupted prover-id file
@@ -327,17 +350,17 @@ mod tests {
     #[serial]
     fn test_corrupted_prover_id_file() {
         let temp_dir = TempDir::new().unwrap();
-        env::set_var("HOME", temp_dir.path());
+        let state_dir = use_state_dir(temp_dir.path());
 
-        // Create .nexus directory and corrupted prover-id file
-        let nexus_dir = temp_dir.path().join(".nexus");
-        fs::create_dir(&nexus_dir).unwrap();
+        // Create the state directory and corrupted prover-id file
+        fs::create_dir(&state_dir).unwrap();
 
-        let id_path = nexus_dir.join("prover-id");
+        let id_path = state_dir.join("prover-id");
         fs::write(&id_path, vec![0xFF, 0xFE, 0xFF]).unwrap(); // Invalid UTF-8
 
         let id = get_or_generate_prover_id();
         assert!(id.contains('-'), "Should generate new valid ID");
+        crate::state::configure(None);
     }
 
     /// Tests handling of permission denied scenarios
@@ -348,22 +371,22 @@ mod tests {
         use std::os::unix::fs::PermissionsExt;
 
         let temp_dir = TempDir::new().unwrap();
-        env::set_var("HOME", temp_dir.path());
+        let state_dir = use_state_dir(temp_dir.path());
 
-        // Create .nexus directory with read-only permissions
-        let nexus_dir = temp_dir.path().join(".nexus");
-        fs::create_dir(&nexus_dir).unwrap();
+        // Create the state directory with read-only permissions
+        fs::create_dir(&state_dir).unwrap();
 
-        let metadata = fs::metadata(&nexus_dir).unwrap();
+        let metadata = fs::metadata(&state_dir).unwrap();
         let mut perms = metadata.permissions();
         perms.set_mode(0o444); // read-only
-        fs::set_permissions(&nexus_dir, perms).unwrap();
+        fs::set_permissions(&state_dir, perms).unwrap();
 
         let id = get_or_generate_prover_id();
         assert!(
             id.contains('-'),
             "Should generate new ID when permissions denied"
         );
+        crate::state::configure(None);
     }
 
     /// Tests that IDs are properly formatted
@@ -397,16 +420,16 @@ mod tests {
     #[serial]
     fn test_empty_prover_id_file() {
         let temp_dir = TempDir::new().unwrap();
-        env::set_var("HOME", temp_dir.path());
+        let state_dir = use_state_dir(temp_dir.path());
 
-        let nexus_dir = temp_dir.path().join(".nexus");
-        fs::create_dir(&nexus_dir).unwrap();
+        fs::create_dir(&state_dir).unwrap();
 
-        let id_path = nexus_dir.join("prover-id");
+        let id_path = state_dir.join("prover-id");
         fs::write(&id_path, "").unwrap();
 
         let id = get_or_generate_prover_id();
         assert!(id.contains('-'), "Should generate new ID for empty file");
+        crate::state::configure(None);
     }
 
     /// Tests behavior when home directory is not available
@@ -0,0 +1,456 @@
+//! Connecting through an HTTP proxy
+//!
+//! Like curl, the CLI honors the `HTTPS_PROXY` (for wss:// and https://), `HTTP_PROXY` (for ws://
+//! and http://) and `NO_PROXY` environment variables, in upper or lower case. The `proxy` and
+//! `no_proxy` settings of the `connection` section of the configuration file take precedence
+//! over them. Websocket connections go through the proxy with a CONNECT tunnel, HTTP requests
+//! (analytics and updates) through clients built by [`http_client`] and
+//! [`blocking_http_client`], and the git and cargo commands of the updater get the same settings
+//! in their environment.
+
+use base64::Engine;
+use reqwest::Url;
+use std::sync::RwLock;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::config::ConnectionConfig;
+use crate::transport::TransportError;
+
+// Longer proxy responses to a CONNECT request are not going to be a success
+const MAX_RESPONSE_HEAD: usize = 16 * 1024;
+
+static CONFIGURED: RwLock<Option<ProxySettings>> = RwLock::new(None);
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProxySettings {
+    /// For ws:// and http:// URLs
+    http: Option<Url>,
+    /// For wss:// and https:// URLs
+    https: Option<Url>,
+    /// Hosts to connect to directly, e.g. "localhost" or ".internal.example.com"
+    no_proxy: Vec<String>,
+}
+
+impl ProxySettings {
+    /// The settings of the configuration file, or else of the environment variables `env` reads
+    pub fn from_config(
+        config: &ConnectionConfig,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let env = |name: &str| {
+            env(&name.to_lowercase())
+                .or_else(|| env(&name.to_uppercase()))
+                .filter(|value| !value.trim().is_empty())
+        };
+        let (http, https) = match &config.proxy {
+            Some(proxy) => (Some(proxy.clone()), Some(proxy.clone())),
+            None => (env("http_proxy"), env("https_proxy")),
+        };
+        let no_proxy = config.no_proxy.clone().or_else(|| env("no_proxy"));
+
+        Ok(Self {
+            http: http.as_deref().map(parse_proxy_url).transpose()?,
+            https: https.as_deref().map(parse_proxy_url).transpose()?,
+            no_proxy: no_proxy
+                .iter()
+                .flat_map(|hosts| hosts.split(','))
+                .map(|host| host.trim().to_lowercase())
+                .filter(|host| !host.is_empty())
+                .collect(),
+        })
+    }
+
+    /// The proxy to use for a host, if any
+    pub fn proxy_for(&self, host: &str, secure: bool) -> Option<&Url> {
+        let host = host.to_lowercase();
+        let bypassed = self.no_proxy.iter().any(|pattern| {
+            let domain = pattern.trim_start_matches('.');
+            pattern == "*"
+                || host == domain
+                || host
+                    .strip_suffix(domain)
+                    .is_some_and(|subdomain| subdomain.ends_with('.'))
+        });
+        if bypassed {
+            return None;
+        }
+        if secure {
+            self.https.as_ref()
+        } else {
+            self.http.as_ref()
+        }
+    }
+
+    /// Open a TCP connection to `host`, tunneled through the proxy if there is one for it
+    pub async fn connect(
+        &self,
+        host: &str,
+        port: u16,
+        secure: bool,
+    ) -> Result<TcpStream, TransportError> {
+        match self.proxy_for(host, secure) {
+            Some(proxy) => tunnel(proxy, host, port).await,
+            None => Ok(TcpStream::connect((host, port)).await?),
+        }
+    }
+
+    fn reqwest_proxies(&self) -> Result<Vec<reqwest::Proxy>, reqwest::Error> {
+        let no_proxy = reqwest::NoProxy::from_string(&self.no_proxy.join(","));
+        let mut proxies = Vec::new();
+        if let Some(proxy) = &self.http {
+            proxies.push(reqwest::Proxy::http(proxy.as_str())?.no_proxy(no_proxy.clone()));
+        }
+        if let Some(proxy) = &self.https {
+            proxies.push(reqwest::Proxy::https(proxy.as_str())?.no_proxy(no_proxy));
+        }
+        Ok(proxies)
+    }
+
+    pub fn http_client(&self) -> reqwest::Client {
+        self.reqwest_proxies()
+            .and_then(|proxies| {
+                proxies
+                    .into_iter()
+                    .fold(reqwest::Client::builder().no_proxy(), |builder, proxy| {
+                        builder.proxy(proxy)
+                    })
+                    .build()
+            })
+            .unwrap_or_else(|e| {
+                eprintln!("Warning: failed to set up the HTTP proxy: {}", e);
+                reqwest::Client::new()
+            })
+    }
+
+    pub fn blocking_http_client(&self) -> Result<reqwest::blocking::Client, reqwest::Error> {
+        self.reqwest_proxies()?
+            .into_iter()
+            .fold(
+                reqwest::blocking::Client::builder().no_proxy(),
+                |builder, proxy| builder.proxy(proxy),
+            )
+            .build()
+    }
+
+    /// The environment variables for child processes such as git to use the same proxies
+    pub fn child_env(&self) -> Vec<(&'static str, String)> {
+        let mut env = Vec::new();
+        if let Some(proxy) = &self.http {
+            env.push(("http_proxy", proxy.to_string()));
+        }
+        if let Some(proxy) = &self.https {
+            env.push(("https_proxy", proxy.to_string()));
+            env.push(("HTTPS_PROXY", proxy.to_string()));
+        }
+        if !self.no_proxy.is_empty() {
+            env.push(("no_proxy", self.no_proxy.join(",")));
+            env.push(("NO_PROXY", self.no_proxy.join(",")));
+        }
+        env
+    }
+}
+
+/// Use these settings for the rest of the process
+pub fn configure(settings: ProxySettings) {
+    *CONFIGURED.write().unwrap_or_else(|e| e.into_inner()) = Some(settings);
+}
+
+/// The configured settings, or those of the environment if none were configured
+pub fn current() -> ProxySettings {
+    if let Some(settings) = CONFIGURED.read().unwrap_or_else(|e| e.into_inner()).clone() {
+        return settings;
+    }
+    ProxySettings::from_config(&ConnectionConfig::default(), |name| {
+        std::env::var(name).ok()
+    })
+    .unwrap_or_else(|e| {
+        eprintln!("Warning: ignoring invalid proxy settings: {}", e);
+        ProxySettings::default()
+    })
+}
+
+/// An HTTP client using the current proxy settings
+pub fn http_client() -> reqwest::Client {
+    current().http_client()
+}
+
+/// A blocking HTTP client using the current proxy settings
+pub fn blocking_http_client() -> Result<reqwest::blocking::Client, reqwest::Error> {
+    current().blocking_http_client()
+}
+
+fn parse_proxy_url(proxy: &str) -> Result<Url, Box<dyn std::error::Error>> {
+    // Like curl, a proxy without a scheme is an HTTP proxy
+    let url = if proxy.contains("://") {
+        Url::parse(proxy)
+    } else {
+        Url::parse(&format!("http://{}", proxy))
+    }
+    .map_err(|e| format!("Invalid proxy {}: {}", proxy, e))?;
+    if url.scheme() != "http" {
+        return Err(format!(
+            "Unsupported proxy {}: only http:// proxies are supported",
+            proxy
+        )
+        .into());
+    }
+    if url.host_str().is_none() {
+        return Err(format!("Invalid proxy {}: no host", proxy).into());
+    }
+    Ok(url)
+}
+
+/// Open a tunnel to `host` through an HTTP proxy with a CONNECT request
+async fn tunnel(proxy: &Url, host: &str, port: u16) -> Result<TcpStream, TransportError> {
+    let proxy_host = proxy.host_str().unwrap_or_default();
+    let proxy_port = proxy.port_or_known_default().unwrap_or(80);
+    let mut stream = TcpStream::connect((proxy_host.trim_matches(['[', ']']), proxy_port))
+        .await
+        .map_err(|e| format!("Failed to connect to proxy {}: {}", proxy_host, e))?;
+
+    let authority = if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    };
+    let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", authority, authority);
+    if !proxy.username().is_empty() {
+        let credentials = format!(
+            "{}:{}",
+            percent_decode(proxy.username()),
+            percent_decode(proxy.password().unwrap_or_default())
+        );
+        request.push_str(&format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            base64::engine::general_purpose::STANDARD.encode(credentials)
+        ));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read the response head a byte at a time, so nothing of the tunneled connection is consumed
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_RESPONSE_HEAD {
+            return Err(format!("Proxy {} sent an invalid response", proxy_host).into());
+        }
+        head.push(
+            stream
+                .read_u8()
+                .await
+                .map_err(|e| format!("Proxy {} closed the connection: {}", proxy_host, e))?,
+        );
+    }
+    let head = String::from_utf8_lossy(&head);
+    let status_line = head.lines().next().unwrap_or_default();
+    let status = status_line.split_whitespace().nth(1);
+    if !status.is_some_and(|status| status.starts_with('2')) {
+        return Err(format!(
+            "Proxy {} refused to connect to {}: {}",
+            proxy_host, authority, status_line
+        )
+        .into());
+    }
+    Ok(stream)
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use tokio::net::TcpListener;
+
+    fn settings(config: ConnectionConfig, env: &[(&str, &str)]) -> ProxySettings {
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        ProxySettings::from_config(&config, |name| env.get(name).cloned()).unwrap()
+    }
+
+    /// A proxy stand-in for one connection. It answers with `response`, and after a successful
+    /// CONNECT tunnels the connection to the requested host. Returns its URL and, once done, the
+    /// request head it received.
+    async fn stand_in(response: &'static str) -> (String, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://user:p%40ss@{}", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (mut client, _) = listener.accept().await.unwrap();
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                head.push(client.read_u8().await.unwrap());
+            }
+            let head = String::from_utf8(head).unwrap();
+            client.write_all(response.as_bytes()).await.unwrap();
+
+            if let Some(target) = head.strip_prefix("CONNECT ") {
+                if response.starts_with("HTTP/1.1 200") {
+                    let target = target.split_whitespace().next().unwrap();
+                    let mut upstream = TcpStream::connect(target).await.unwrap();
+                    let _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+                }
+            }
+            head
+        });
+        (url, handle)
+    }
+
+    /// Tests that the configuration file takes precedence over the environment, and NO_PROXY
+    #[test]
+    fn test_settings() {
+        let env = [
+            ("HTTPS_PROXY", "proxy.example.com:3128"),
+            ("no_proxy", "localhost, .internal.example.com"),
+        ];
+        let from_env = settings(ConnectionConfig::default(), &env);
+        let proxy = Url::parse("http://proxy.example.com:3128").unwrap();
+        assert_eq!(
+            from_env.proxy_for("orchestrator.nexus.xyz", true),
+            Some(&proxy)
+        );
+        assert_eq!(from_env.proxy_for("orchestrator.nexus.xyz", false), None);
+        assert_eq!(from_env.proxy_for("LOCALHOST", true), None);
+        assert_eq!(from_env.proxy_for("a.internal.example.com", true), None);
+        assert_eq!(from_env.proxy_for("internal.example.com", true), None);
+        assert_eq!(
+            from_env.proxy_for("notinternal.example.com", true),
+            Some(&proxy)
+        );
+
+        let explicit = settings(
+            ConnectionConfig {
+                proxy: Some("http://10.0.0.1:8080".into()),
+                no_proxy: Some("*".into()),
+                ..ConnectionConfig::default()
+            },
+            &env,
+        );
+        assert_eq!(explicit.proxy_for("orchestrator.nexus.xyz", true), None);
+        let explicit = settings(
+            ConnectionConfig {
+                proxy: Some("http://10.0.0.1:8080".into()),
+                ..ConnectionConfig::default()
+            },
+            &env,
+        );
+        assert_eq!(
+            explicit
+                .proxy_for("orchestrator.nexus.xyz", false)
+                .map(Url::as_str),
+            Some("http://10.0.0.1:8080/")
+        );
+        // Without a no_proxy setting, NO_PROXY still applies
+        assert_eq!(explicit.proxy_for("localhost", false), None);
+
+        let invalid = ConnectionConfig {
+            proxy: Some("socks5://10.0.0.1:1080".into()),
+            ..ConnectionConfig::default()
+        };
+        assert!(ProxySettings::from_config(&invalid, |_| None).is_err());
+    }
+
+    /// Tests that connections are tunneled through the proxy, with its credentials
+    #[tokio::test]
+    async fn test_connect_through_proxy() {
+        // A server echoing one message
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_port = target.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = target.accept().await.unwrap();
+            let mut buf = [0; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        });
+
+        let (proxy, proxy_handle) = stand_in("HTTP/1.1 200 Connection established\r\n\r\n").await;
+        let settings = settings(
+            ConnectionConfig {
+                proxy: Some(proxy),
+                ..ConnectionConfig::default()
+            },
+            &[],
+        );
+        let mut stream = settings
+            .connect("127.0.0.1", target_port, true)
+            .await
+            .unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        drop(stream);
+
+        let head = proxy_handle.await.unwrap();
+        assert!(head.starts_with(&format!("CONNECT 127.0.0.1:{} HTTP/1.1\r\n", target_port)));
+        // user:p@ss
+        assert!(head.contains("Proxy-Authorization: Basic dXNlcjpwQHNz\r\n"));
+    }
+
+    /// Tests that a refused CONNECT is reported with the proxy's answer
+    #[tokio::test]
+    async fn test_proxy_refuses_tunnel() {
+        let (proxy, _proxy_handle) =
+            stand_in("HTTP/1.1 407 Proxy Authentication Required\r\n\r\n").await;
+        let settings = settings(
+            ConnectionConfig {
+                proxy: Some(proxy),
+                ..ConnectionConfig::default()
+            },
+            &[],
+        );
+        let error = settings
+            .connect("orchestrator.nexus.xyz", 443, true)
+            .await
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("407 Proxy Authentication Required"));
+    }
+
+    /// Tests that HTTP clients send their requests to the configured proxy
+    #[tokio::test]
+    async fn test_http_client_uses_proxy() {
+        let (proxy, proxy_handle) =
+            stand_in("HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n").await;
+        let settings = settings(
+            ConnectionConfig {
+                proxy: Some(proxy),
+                ..ConnectionConfig::default()
+            },
+            &[],
+        );
+        let response = settings
+            .http_client()
+            .get("http://collector.example.com/events")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NO_CONTENT);
+        let head = proxy_handle.await.unwrap();
+        assert!(head.starts_with("GET http://collector.example.com/events HTTP/1.1\r\n"));
+    }
+}
@@ -0,0 +1,391 @@
+//! Where the CLI keeps its files
+//!
+//! Everything the CLI writes lives in three directories, following the XDG base directory
+//! specification (and the platform conventions elsewhere):
+//! - configuration, e.g. `~/.config/nexus`: `config.json` and experiment overrides
+//! - state, e.g. `~/.local/state/nexus`: the prover ID, the installed version, the updater's
+//!   state, the salt of analytics pseudonyms and, under `runs/<run_id>`, everything specific to
+//!   one run (its prover ID, checkpoint, analytics spool, PID and lock file)
+//! - cache, e.g. `~/.cache/nexus`: the source checkout used to build updates
+//!
+//! With `--state-dir DIR`, all three are DIR (with the cache in DIR/cache) instead. Files left in
+//! the working directory or in `~/.nexus` by earlier versions are moved over once, on startup.
+//!
+//! A lock file in the run directory keeps two processes from using the same run's state. The
+//! only process allowed to take over a held lock is a new version started by the auto-updater,
+//! which the holder names in the environment of the new process. The lock file holds the PID of
+//! the holder, and is only read and written under `flock`, so that processes started at the same
+//! moment can't both find it free.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+/// Set by the auto-updater for the new process: the PID of the process handing over its lock
+pub const LOCK_HANDOVER_ENV: &str = "NEXUS_STATE_LOCK_HANDOVER";
+
+const MIGRATION_MARKER: &str = ".migrated";
+
+static CONFIGURED: RwLock<Option<StateDirs>> = RwLock::new(None);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StateDirs {
+    pub config: PathBuf,
+    pub state: PathBuf,
+    pub cache: PathBuf,
+}
+
+impl StateDirs {
+    /// The directories in `state_dir`, or the platform's default directories without it
+    pub fn resolve(state_dir: Option<&Path>) -> Option<Self> {
+        if let Some(state_dir) = state_dir {
+            return Some(Self {
+                config: state_dir.to_path_buf(),
+                state: state_dir.to_path_buf(),
+                cache: state_dir.join("cache"),
+            });
+        }
+
+        Some(Self {
+            config: dirs::config_dir()?.join("nexus"),
+            // Only Linux has a state directory
+            state: dirs::state_dir()
+                .or_else(dirs::data_local_dir)?
+                .join("nexus"),
+            cache: dirs::cache_dir()?.join("nexus"),
+        })
+    }
+
+    /// Everything specific to one run, e.g. ~/.local/state/nexus/runs/1
+    pub fn run_dir(&self, run_id: &str) -> PathBuf {
+        self.state.join("runs").join(run_id)
+    }
+}
+
+/// Use these directories for the rest of the process, e.g. the ones given with `--state-dir`
+pub fn configure(dirs: Option<StateDirs>) {
+    *CONFIGURED.write().unwrap_or_else(|e| e.into_inner()) = dirs;
+}
+
+/// The configured directories, or the default ones if none were configured. None if there is no
+/// home directory to put them in.
+pub fn current() -> Option<StateDirs> {
+    CONFIGURED
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+        .or_else(|| StateDirs::resolve(None))
+}
+
+/// Move the files of earlier versions, from `~/.nexus` and the working directory, into their new
+/// places. Only runs once per state directory, and never overwrites a file.
+pub fn migrate_legacy_files(dirs: &StateDirs, legacy_home: Option<&Path>, working_dir: &Path) {
+    let marker = dirs.state.join(MIGRATION_MARKER);
+    if marker.exists() {
+        return;
+    }
+
+    if let Some(legacy) = legacy_home.map(|home| home.join(".nexus")) {
+        move_legacy_file(&legacy.join("prover-id"), &dirs.state.join("prover-id"));
+        move_legacy_file(&legacy.join("network-api"), &dirs.cache.join("network-api"));
+    }
+
+    move_legacy_file(
+        &working_dir.join(".current_version"),
+        &dirs.state.join("current-version"),
+    );
+    // prover_id_<run_id>.txt
+    for (run_id, from) in legacy_run_files(working_dir, "prover_id_", ".txt") {
+        move_legacy_file(&from, &dirs.run_dir(&run_id).join("prover-id.json"));
+    }
+    // The PID of a process that has long exited
+    let _ = fs::remove_file(working_dir.join(".prover.pid"));
+
+    if let Err(e) = fs::create_dir_all(&dirs.state).and_then(|_| fs::write(&marker, "")) {
+        eprintln!("Warning: failed to record the state migration: {}", e);
+    }
+}
+
+/// Files named `<prefix><run_id><suffix>` in `dir`, by run ID
+fn legacy_run_files(dir: &Path, prefix: &str, suffix: &str) -> Vec<(String, PathBuf)> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            let run_id = name.strip_prefix(prefix)?.strip_suffix(suffix)?;
+            (!run_id.is_empty()).then(|| (run_id.to_string(), entry.path()))
+        })
+        .collect()
+}
+
+fn move_legacy_file(from: &Path, to: &Path) {
+    if !from.exists() || to.exists() {
+        return;
+    }
+    let result = to
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::rename(from, to))
+        // Renaming fails across file systems, but files can still be copied
+        .or_else(|e| {
+            if from.is_file() {
+                fs::copy(from, to).and_then(|_| fs::remove_file(from))
+            } else {
+                Err(e)
+            }
+        });
+    match result {
+        Ok(()) => println!("Moved {} to {}", from.display(), to.display()),
+        Err(e) => eprintln!(
+            "Warning: failed to move {} to {}: {}",
+            from.display(),
+            to.display(),
+            e
+        ),
+    }
+}
+
+/// A lock on the state of a run, released when dropped
+#[derive(Debug)]
+pub struct StateLock {
+    path: PathBuf,
+}
+
+impl StateLock {
+    pub fn acquire(run_dir: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        fs::create_dir_all(run_dir)?;
+        let path = run_dir.join("lock");
+
+        let handover = std::env::var(LOCK_HANDOVER_ENV)
+            .ok()
+            .and_then(|pid| pid.parse::<u32>().ok());
+        let holder = with_locked_file(&path, |file| {
+            let holder = read_pid(file).filter(|&holder| {
+                holder != std::process::id() && Some(holder) != handover && is_running(holder)
+            });
+            if holder.is_none() {
+                write_pid(file, Some(std::process::id()))?;
+            }
+            Ok(holder)
+        })?;
+        if let Some(holder) = holder {
+            return Err(format!(
+                "{} is already used by process {}. Use a different --run-id or --state-dir.",
+                run_dir.display(),
+                holder
+            )
+            .into());
+        }
+        Ok(Self { path })
+    }
+
+    /// Take the lock back from a process it was handed over to, e.g. an update that was rolled
+    /// back
+    pub fn reclaim(&self) -> std::io::Result<()> {
+        with_locked_file(&self.path, |file| write_pid(file, Some(std::process::id())))
+    }
+}
+
+impl Drop for StateLock {
+    fn drop(&mut self) {
+        // A process the lock was handed over to keeps it. The file stays, because another process
+        // may be waiting for its flock.
+        let _ = with_locked_file(&self.path, |file| {
+            if read_pid(file) == Some(std::process::id()) {
+                write_pid(file, None)?;
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Run `f` on the lock file while holding an exclusive flock on it, which closing the file
+/// releases
+fn with_locked_file<T>(
+    path: &Path,
+    f: impl FnOnce(&mut File) -> std::io::Result<T>,
+) -> std::io::Result<T> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    f(&mut file)
+}
+
+fn read_pid(file: &mut File) -> Option<u32> {
+    let mut contents = String::new();
+    file.rewind().ok()?;
+    file.read_to_string(&mut contents).ok()?;
+    contents.trim().parse().ok()
+}
+
+fn write_pid(file: &mut File, pid: Option<u32>) -> std::io::Result<()> {
+    file.set_len(0)?;
+    file.rewind()?;
+    if let Some(pid) = pid {
+        write!(file, "{}", pid)?;
+    }
+    file.sync_all()
+}
+
+fn is_running(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // Signal 0 only checks whether the process exists (EPERM: it does, but isn't ours)
+    let exists = unsafe { libc::kill(pid, 0) } == 0;
+    exists || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// Tests that files of earlier versions are moved into the new layout, once
+    #[test]
+    fn test_migrate_legacy_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let home = temp_dir.path().join("home");
+        let working_dir = temp_dir.path().join("work");
+        let dirs = StateDirs::resolve(Some(&temp_dir.path().join("state"))).unwrap();
+        fs::create_dir_all(home.join(".nexus/network-api")).unwrap();
+        fs::create_dir_all(&working_dir).unwrap();
+        fs::write(home.join(".nexus/prover-id"), "happy-prover-42").unwrap();
+        fs::write(working_dir.join(".current_version"), "0.3.6").unwrap();
+        fs::write(working_dir.join("prover_id_1.txt"), "{}").unwrap();
+        fs::write(working_dir.join(".prover.pid"), "1").unwrap();
+
+        migrate_legacy_files(&dirs, Some(&home), &working_dir);
+
+        let run_dir = dirs.run_dir("1");
+        assert_eq!(
+            fs::read_to_string(dirs.state.join("prover-id")).unwrap(),
+            "happy-prover-42"
+        );
+        assert!(dirs.cache.join("network-api").is_dir());
+        assert!(run_dir.join("prover-id.json").exists());
+        assert_eq!(
+            fs::read_to_string(dirs.state.join("current-version")).unwrap(),
+            "0.3.6"
+        );
+        assert!(!working_dir.join(".current_version").exists());
+        assert!(!working_dir.join(".prover.pid").exists());
+        assert!(!home.join(".nexus/prover-id").exists());
+
+        // Files appearing after the migration are left alone
+        fs::write(working_dir.join(".current_version"), "0.1.0").unwrap();
+        migrate_legacy_files(&dirs, Some(&home), &working_dir);
+        assert!(working_dir.join(".current_version").exists());
+    }
+
+    /// Tests that a run's state can't be locked twice, and that stale locks are taken over
+    #[test]
+    fn test_state_lock() {
+        let temp_dir = TempDir::new().unwrap();
+        let run_dir = temp_dir.path().join("runs/1");
+
+        // Held by a running process
+        let mut holder = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+        fs::create_dir_all(&run_dir).unwrap();
+        fs::write(run_dir.join("lock"), holder.id().to_string()).unwrap();
+        assert!(StateLock::acquire(&run_dir).is_err());
+
+        // Left behind by a process that exited
+        holder.kill().unwrap();
+        holder.wait().unwrap();
+        let lock = StateLock::acquire(&run_dir).expect("Stale lock should be taken over");
+        assert_eq!(
+            fs::read_to_string(run_dir.join("lock")).unwrap(),
+            std::process::id().to_string()
+        );
+
+        drop(lock);
+        assert_eq!(fs::read_to_string(run_dir.join("lock")).unwrap(), "");
+        drop(StateLock::acquire(&run_dir).expect("Released lock should be free"));
+    }
+
+    /// Tests that of two processes acquiring the same lock at the same moment, only one gets it
+    #[test]
+    fn test_state_lock_race() {
+        use std::io::BufRead;
+        use std::process::{Command, Stdio};
+
+        let temp_dir = TempDir::new().unwrap();
+        let run_dir = temp_dir.path().join("runs/1");
+        fs::create_dir_all(&run_dir).unwrap();
+
+        for _ in 0..10 {
+            let mut contenders: Vec<_> = (0..2)
+                .map(|_| {
+                    Command::new(std::env::current_exe().unwrap())
+                        .args(["--exact", "state::tests::hold_state_lock"])
+                        .args(["--ignored", "--nocapture"])
+                        .env("NEXUS_TEST_RUN_DIR", &run_dir)
+                        .stdin(Stdio::piped())
+                        .stdout(Stdio::piped())
+                        .spawn()
+                        .unwrap()
+                })
+                .collect();
+            let mut outputs: Vec<_> = contenders
+                .iter_mut()
+                .map(|contender| {
+                    std::io::BufReader::new(contender.stdout.take().unwrap())
+                        .lines()
+                        .map(|line| line.unwrap())
+                })
+                .collect();
+            let outcomes: Vec<String> = outputs
+                .iter_mut()
+                .map(|lines| {
+                    // After libtest's "test state::tests::hold_state_lock ... "
+                    lines
+                        .find(|line| line.contains("acquired") || line.contains("refused"))
+                        .unwrap()
+                })
+                .collect();
+            // Both have tried, so they can let go
+            for (mut contender, lines) in contenders.into_iter().zip(outputs) {
+                drop(contender.stdin.take());
+                lines.for_each(drop);
+                contender.wait().unwrap();
+            }
+
+            let acquired = outcomes
+                .iter()
+                .filter(|outcome| outcome.contains("acquired"))
+                .count();
+            assert_eq!(acquired, 1, "{:?}", outcomes);
+        }
+    }
+
+    /// Not a test of its own: a contender of test_state_lock_race, which holds the lock until its
+    /// stdin is closed
+    #[test]
+    #[ignore]
+    fn hold_state_lock() {
+        let Ok(run_dir) = std::env::var("NEXUS_TEST_RUN_DIR") else {
+            return;
+        };
+        let lock = StateLock::acquire(Path::new(&run_dir));
+        match &lock {
+            Ok(_) => println!("acquired"),
+            Err(e) => println!("refused: {}", e),
+        }
+        let _ = std::io::stdin().read_to_end(&mut Vec::new());
+    }
+}
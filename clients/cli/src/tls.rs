@@ -0,0 +1,313 @@
+//! TLS for the orchestrator connection
+//!
+//! On top of the certificates the system trusts, the `connection.tls` section of the
+//! configuration file can add CA certificates (for TLS-inspecting proxies and local orchestrators
+//! with self-signed certificates), a client certificate, and pinned public keys. With pins, the
+//! orchestrator's certificate must also have one of the pinned keys, even if a trusted CA
+//! signed it. Pins are SHA-256 hashes of the certificate's SubjectPublicKeyInfo, in the format of
+//! curl's `--pinnedpubkey`:
+//!
+//! ```sh
+//! openssl x509 -in orchestrator.pem -pubkey -noout | openssl pkey -pubin -outform der \
+//!     | openssl dgst -sha256 -binary | openssl base64
+//! ```
+
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_native_tls::native_tls::{Certificate, Identity};
+use tokio_native_tls::{native_tls, TlsConnector, TlsStream};
+
+use crate::config::{expand_home, TlsConfig};
+use crate::transport::TransportError;
+
+const PIN_PREFIX: &str = "sha256/";
+
+#[derive(Clone)]
+pub struct TlsSettings {
+    connector: TlsConnector,
+    pinned_keys: Vec<[u8; 32]>,
+}
+
+impl TlsSettings {
+    pub fn from_config(config: &TlsConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut builder = native_tls::TlsConnector::builder();
+
+        if let Some(path) = &config.ca_bundle {
+            let pem = read_file("CA bundle", path)?;
+            let certificates = Certificate::stack_from_pem(&pem)
+                .map_err(|e| format!("Invalid CA bundle {}: {}", path, e))?;
+            if certificates.is_empty() {
+                return Err(format!("The CA bundle {} has no certificates", path).into());
+            }
+            for certificate in certificates {
+                builder.add_root_certificate(certificate);
+            }
+        }
+
+        match (&config.client_certificate, &config.client_key) {
+            (Some(certificate_path), Some(key_path)) => {
+                let identity = Identity::from_pkcs8(
+                    &read_file("client certificate", certificate_path)?,
+                    &read_file("client key", key_path)?,
+                )
+                .map_err(|e| {
+                    format!(
+                        "Invalid client certificate {} or key {} (expected PEM files with a \
+                         PKCS#8 key): {}",
+                        certificate_path, key_path, e
+                    )
+                })?;
+                builder.identity(identity);
+            }
+            (None, None) => {}
+            _ => {
+                return Err(
+                    "A client certificate needs both client_certificate and client_key".into(),
+                )
+            }
+        }
+
+        let pinned_keys = config
+            .pinned_keys
+            .iter()
+            .map(|pin| parse_pin(pin))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            connector: TlsConnector::from(builder.build()?),
+            pinned_keys,
+        })
+    }
+
+    /// Run the TLS handshake with `host` over `stream`, and check the certificate's pins
+    pub async fn connect<S>(&self, host: &str, stream: S) -> Result<TlsStream<S>, TransportError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let stream = self.connector.connect(host, stream).await.map_err(|e| {
+            format!(
+                "TLS handshake with {} failed: {}. If a proxy or a local orchestrator uses its \
+                 own certificates, add its CA certificate with connection.tls.ca_bundle in the \
+                 configuration file.",
+                host, e
+            )
+        })?;
+
+        if !self.pinned_keys.is_empty() {
+            let certificate = stream
+                .get_ref()
+                .peer_certificate()?
+                .ok_or_else(|| format!("{} sent no certificate", host))?;
+            let key = public_key_hash(&certificate.to_der()?)
+                .ok_or_else(|| format!("Could not read the public key of {}", host))?;
+            if !self.pinned_keys.contains(&key) {
+                return Err(format!(
+                    "The public key of {} ({}) is not one of connection.tls.pinned_keys",
+                    host,
+                    format_pin(&key)
+                )
+                .into());
+            }
+        }
+        Ok(stream)
+    }
+}
+
+fn read_file(what: &str, path: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    std::fs::read(expand_home(path))
+        .map_err(|e| format!("Failed to read the {} {}: {}", what, path, e).into())
+}
+
+fn parse_pin(pin: &str) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+    let invalid = || format!("Invalid pinned key {}, expected sha256/<base64 hash>", pin);
+    let hash = pin.strip_prefix(PIN_PREFIX).ok_or_else(invalid)?;
+    let hash = base64::engine::general_purpose::STANDARD
+        .decode(hash)
+        .map_err(|_| invalid())?;
+    Ok(hash.try_into().map_err(|_| invalid())?)
+}
+
+fn format_pin(hash: &[u8; 32]) -> String {
+    format!(
+        "{}{}",
+        PIN_PREFIX,
+        base64::engine::general_purpose::STANDARD.encode(hash)
+    )
+}
+
+/// The SHA-256 hash of the SubjectPublicKeyInfo of a DER certificate
+fn public_key_hash(certificate: &[u8]) -> Option<[u8; 32]> {
+    // Certificate ::= SEQUENCE { tbsCertificate SEQUENCE { [0] version OPTIONAL, serialNumber,
+    //     signature, issuer, validity, subject, subjectPublicKeyInfo, ... }, ... }
+    let certificate = DerElement::parse(certificate)?;
+    let tbs_certificate = DerElement::parse(certificate.contents)?;
+    let mut fields = tbs_certificate.contents;
+    if fields.first() == Some(&0xa0) {
+        fields = DerElement::parse(fields)?.rest;
+    }
+    for _ in 0..5 {
+        fields = DerElement::parse(fields)?.rest;
+    }
+    let public_key_info = DerElement::parse(fields)?;
+    (public_key_info.tag == 0x30).then(|| Sha256::digest(public_key_info.encoded).into())
+}
+
+struct DerElement<'a> {
+    tag: u8,
+    /// The whole element, including its tag and length
+    encoded: &'a [u8],
+    contents: &'a [u8],
+    /// Whatever follows the element
+    rest: &'a [u8],
+}
+
+impl<'a> DerElement<'a> {
+    /// The first element of `der`
+    fn parse(der: &'a [u8]) -> Option<Self> {
+        let (&tag, rest) = der.split_first()?;
+        let (&length, rest) = rest.split_first()?;
+        let (length, rest) = if length < 0x80 {
+            (length as usize, rest)
+        } else {
+            // Long form: the low bits are the number of length bytes
+            let count = (length & 0x7f) as usize;
+            if count == 0 || count > 4 || rest.len() < count {
+                return None;
+            }
+            let (bytes, rest) = rest.split_at(count);
+            let length = bytes
+                .iter()
+                .fold(0usize, |length, &byte| (length << 8) | byte as usize);
+            (length, rest)
+        };
+        if rest.len() < length {
+            return None;
+        }
+        let header = der.len() - rest.len();
+        let (contents, rest) = rest.split_at(length);
+        Some(Self {
+            tag,
+            encoded: &der[..header + length],
+            contents,
+            rest,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use tokio::net::{TcpListener, TcpStream};
+
+    // openssl x509 -in tests/tls/orchestrator.pem -pubkey -noout | openssl pkey -pubin -outform der
+    //     | openssl dgst -sha256 -binary | openssl base64
+    const ORCHESTRATOR_PIN: &str = "sha256/og2cjaazbNjdmU6up6jVrZZMMKhVlgkfzhwndxtC7EI=";
+
+    fn fixture(name: &str) -> String {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/tls")
+            .join(name)
+            .display()
+            .to_string()
+    }
+
+    /// A TLS server with the self-signed test certificate, completing one handshake
+    async fn serve_once() -> (u16, tokio::task::JoinHandle<()>) {
+        let identity = Identity::from_pkcs8(
+            &std::fs::read(fixture("orchestrator.pem")).unwrap(),
+            &std::fs::read(fixture("orchestrator-key.pem")).unwrap(),
+        )
+        .unwrap();
+        let acceptor =
+            tokio_native_tls::TlsAcceptor::from(native_tls::TlsAcceptor::new(identity).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            // The client may reject the certificate
+            let _ = acceptor.accept(stream).await;
+        });
+        (port, handle)
+    }
+
+    async fn connect(config: &TlsConfig) -> Result<(), TransportError> {
+        let (port, server) = serve_once().await;
+        let settings = TlsSettings::from_config(config).unwrap();
+        let stream = TcpStream::connect(("127.0.0.1", port)).await?;
+        let result = settings.connect("localhost", stream).await.map(|_| ());
+        server.await.unwrap();
+        result
+    }
+
+    /// Tests that the public key hash matches the one computed by openssl
+    #[test]
+    fn test_public_key_hash() {
+        let pem = std::fs::read(fixture("orchestrator.pem")).unwrap();
+        let der = Certificate::from_pem(&pem).unwrap().to_der().unwrap();
+        assert_eq!(
+            format_pin(&public_key_hash(&der).unwrap()),
+            ORCHESTRATOR_PIN
+        );
+        assert_eq!(public_key_hash(&der[..40]), None);
+    }
+
+    /// Tests that an extra CA makes a self-signed orchestrator trusted, and that pins are enforced
+    #[tokio::test]
+    async fn test_ca_bundle_and_pinning() {
+        let error = connect(&TlsConfig::default()).await.unwrap_err();
+        assert!(error.to_string().contains("connection.tls.ca_bundle"));
+
+        let trusted = TlsConfig {
+            ca_bundle: Some(fixture("orchestrator.pem")),
+            ..TlsConfig::default()
+        };
+        connect(&trusted).await.unwrap();
+        connect(&TlsConfig {
+            pinned_keys: vec![ORCHESTRATOR_PIN.into()],
+            ..trusted.clone()
+        })
+        .await
+        .unwrap();
+
+        let other_pin = format!("sha256/{}", "A".repeat(43) + "=");
+        let error = connect(&TlsConfig {
+            pinned_keys: vec![other_pin],
+            ..trusted
+        })
+        .await
+        .unwrap_err();
+        assert!(error.to_string().contains(ORCHESTRATOR_PIN));
+    }
+
+    /// Tests that invalid settings are reported when loading them
+    #[test]
+    fn test_invalid_settings() {
+        let error =
+            |config: TlsConfig| TlsSettings::from_config(&config).err().unwrap().to_string();
+
+        assert!(error(TlsConfig {
+            ca_bundle: Some(fixture("missing.pem")),
+            ..TlsConfig::default()
+        })
+        .contains("Failed to read the CA bundle"));
+        assert!(error(TlsConfig {
+            client_certificate: Some(fixture("orchestrator.pem")),
+            ..TlsConfig::default()
+        })
+        .contains("client_key"));
+        assert!(error(TlsConfig {
+            pinned_keys: vec!["md5/abc".into()],
+            ..TlsConfig::default()
+        })
+        .contains("Invalid pinned key"));
+
+        let client_certificate = TlsConfig {
+            client_certificate: Some(fixture("orchestrator.pem")),
+            client_key: Some(fixture("orchestrator-key.pem")),
+            ..TlsConfig::default()
+        };
+        assert!(TlsSettings::from_config(&client_certificate).is_ok());
+    }
+}
@@ -0,0 +1,213 @@
+//! How the prover talks to the orchestrator
+//!
+//! A [`Connector`] opens connections to the orchestrator, and a [`Transport`] is one open
+//! connection, exchanging websocket messages. In production both are the websocket connection in
+//! [`crate::websocket`]. The tests use an in-memory implementation instead, where the test plays
+//! the orchestrator, so that progress reporting and reconnecting can be tested without sockets
+//! or timing.
+
+use crate::protocol::Protocol;
+use futures::future::BoxFuture;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Message};
+
+pub type TransportError = Box<dyn std::error::Error + Send + Sync>;
+
+pub trait Transport: Send {
+    fn send(&mut self, message: Message) -> BoxFuture<'_, Result<(), TransportError>>;
+
+    /// The next message from the orchestrator, or None once the connection is closed
+    fn receive(&mut self) -> BoxFuture<'_, Option<Result<Message, TransportError>>>;
+
+    fn close(
+        &mut self,
+        frame: Option<CloseFrame<'static>>,
+    ) -> BoxFuture<'_, Result<(), TransportError>>;
+
+    /// The protocol negotiated in the handshake
+    fn protocol(&self) -> Protocol {
+        Protocol::Legacy
+    }
+}
+
+pub trait Connector: Send + Sync {
+    fn connect(&self) -> BoxFuture<'_, Result<Box<dyn Transport>, TransportError>>;
+
+    /// Where the connections go, which also decides where analytics are sent
+    fn address(&self) -> &str;
+
+    /// Connect to a better address than the current connection's, e.g. to the primary
+    /// orchestrator after failing over to another one. None if there is nothing better to try.
+    fn connect_preferred(
+        &self,
+    ) -> Option<BoxFuture<'_, Result<Box<dyn Transport>, TransportError>>> {
+        None
+    }
+}
+
+/// Connections over channels, with the test at the other end playing the orchestrator
+#[cfg(test)]
+pub(crate) mod memory {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use tokio::sync::mpsc;
+
+    /// One end of an in-memory connection
+    pub(crate) struct MemoryTransport {
+        outgoing: Option<mpsc::UnboundedSender<Message>>,
+        incoming: mpsc::UnboundedReceiver<Message>,
+        protocol: Protocol,
+    }
+
+    /// Two connected ends: what one sends, the other receives
+    pub(crate) fn pair() -> (MemoryTransport, MemoryTransport) {
+        let (a_to_b, b_from_a) = mpsc::unbounded_channel();
+        let (b_to_a, a_from_b) = mpsc::unbounded_channel();
+        (
+            MemoryTransport {
+                outgoing: Some(a_to_b),
+                incoming: a_from_b,
+                protocol: Protocol::Legacy,
+            },
+            MemoryTransport {
+                outgoing: Some(b_to_a),
+                incoming: b_from_a,
+                protocol: Protocol::Legacy,
+            },
+        )
+    }
+
+    impl MemoryTransport {
+        /// A message that has already arrived, without waiting for one
+        pub fn try_receive(&mut self) -> Option<Message> {
+            self.incoming.try_recv().ok()
+        }
+
+        /// Pretend that the handshake negotiated `protocol`
+        pub fn set_protocol(&mut self, protocol: Protocol) {
+            self.protocol = protocol;
+        }
+    }
+
+    impl Transport for MemoryTransport {
+        fn send(&mut self, message: Message) -> BoxFuture<'_, Result<(), TransportError>> {
+            Box::pin(async move {
+                let outgoing = self.outgoing.as_ref().ok_or("Connection is closed")?;
+                outgoing
+                    .send(message)
+                    .map_err(|_| "Connection was closed by the other end".into())
+            })
+        }
+
+        fn receive(&mut self) -> BoxFuture<'_, Option<Result<Message, TransportError>>> {
+            Box::pin(async move { self.incoming.recv().await.map(Ok) })
+        }
+
+        fn close(
+            &mut self,
+            frame: Option<CloseFrame<'static>>,
+        ) -> BoxFuture<'_, Result<(), TransportError>> {
+            Box::pin(async move {
+                let result = self.send(Message::Close(frame)).await;
+                // Dropping the sender ends the other end's messages
+                self.outgoing = None;
+                result
+            })
+        }
+
+        fn protocol(&self) -> Protocol {
+            self.protocol
+        }
+    }
+
+    /// Hands out connections whose other ends come out of the matching [`MemoryListener`]
+    pub(crate) struct MemoryConnector {
+        address: String,
+        accepted: mpsc::UnboundedSender<MemoryTransport>,
+        refusals: AtomicU32,
+        attempts: AtomicU32,
+    }
+
+    pub(crate) struct MemoryListener {
+        accepted: mpsc::UnboundedReceiver<MemoryTransport>,
+    }
+
+    pub(crate) fn connector() -> (MemoryConnector, MemoryListener) {
+        connector_at("memory://orchestrator")
+    }
+
+    pub(crate) fn connector_at(address: &str) -> (MemoryConnector, MemoryListener) {
+        let (accepted, incoming) = mpsc::unbounded_channel();
+        (
+            MemoryConnector {
+                address: address.to_string(),
+                accepted,
+                refusals: AtomicU32::new(0),
+                attempts: AtomicU32::new(0),
+            },
+            MemoryListener { accepted: incoming },
+        )
+    }
+
+    impl MemoryConnector {
+        /// Make the next `count` connection attempts fail
+        pub fn refuse_next(&self, count: u32) {
+            self.refusals.store(count, Ordering::SeqCst);
+        }
+
+        /// Connection attempts so far, including refused ones
+        pub fn attempts(&self) -> u32 {
+            self.attempts.load(Ordering::SeqCst)
+        }
+    }
+
+    impl Connector for MemoryConnector {
+        fn connect(&self) -> BoxFuture<'_, Result<Box<dyn Transport>, TransportError>> {
+            Box::pin(async move {
+                self.attempts.fetch_add(1, Ordering::SeqCst);
+                let refused = self
+                    .refusals
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |refusals| {
+                        refusals.checked_sub(1)
+                    })
+                    .is_ok();
+                if refused {
+                    return Err("Connection refused".into());
+                }
+
+                let (client, server) = pair();
+                self.accepted
+                    .send(server)
+                    .map_err(|_| TransportError::from("Nobody is listening"))?;
+                Ok(Box::new(client) as Box<dyn Transport>)
+            })
+        }
+
+        fn address(&self) -> &str {
+            &self.address
+        }
+    }
+
+    /// So that a test can still reach a connector it handed over
+    impl Connector for Arc<MemoryConnector> {
+        fn connect(&self) -> BoxFuture<'_, Result<Box<dyn Transport>, TransportError>> {
+            self.as_ref().connect()
+        }
+
+        fn address(&self) -> &str {
+            self.as_ref().address()
+        }
+    }
+
+    impl MemoryListener {
+        /// The orchestrator's end of the next connection
+        pub async fn accept(&mut self) -> Option<MemoryTransport> {
+            self.accepted.recv().await
+        }
+
+        /// The orchestrator's end of a connection that was already made, without waiting for one
+        pub fn try_accept(&mut self) -> Option<MemoryTransport> {
+            self.accepted.try_recv().ok()
+        }
+    }
+}
@@ -2,16 +2,20 @@
 //!
 //! This module handles automatic updates by running a background thread that:
 //! - Periodically checks for new versions
-//! - Downloads and applies updates when available
-//! - Restarts the CLI with the new version
+//! - Downloads and installs updates when available, or only reports them in notify-only mode
+//! - Hands installed updates to the prover, which restarts into them between jobs
 //!
 //! The updater runs in a separate thread to avoid blocking the main CLI operations,
 //! allowing users to continue using the CLI while update checks happen in the background.
+//! It never restarts the process itself: that would kill a proof in progress.
 
+use semver::Version;
 use std::sync::Arc;
 use std::{thread, time::Duration};
 
-use crate::utils::updater::{UpdaterConfig, VersionManager, VersionStatus, BLUE, RESET};
+use crate::utils::updater::{
+    rollback, UpdateCoordinator, UpdaterConfig, VersionManager, VersionStatus, BLUE, RESET,
+};
 
 // We spawn a separate thread for periodic update checks because the auto-updater runs in an infinite loop
 // that would otherwise block the main CLI process. By running in a background thread:
@@ -20,10 +24,12 @@ use crate::utils::updater::{UpdaterConfig, VersionManager, VersionStatus, BLUE,
 // 2. The main thread remains free to handle its primary responsibility (proving transactions)
 // 3. Users don't have to wait for update checks to complete before using the CLI
 
-// TODO(dprats): remove dead code allowing and use this function in the prover
-#[allow(dead_code)]
 pub fn spawn_auto_update_thread(
     updater_config: &UpdaterConfig,
+    coordinator: Arc<UpdateCoordinator>,
+    // Called with the current and the available version when an update is found in notify-only
+    // mode, once per available version
+    on_update_available: impl Fn(&Version, &Version) + Send + 'static,
 ) -> Result<(), Box<dyn std::error::Error>> {
     println!(
         "{}[auto-updater]{} Starting periodic CLI updates...",
@@ -31,14 +37,15 @@ pub fn spawn_auto_update_thread(
     );
 
     // Create a thread-safe version manager that can be shared across threads
-    let version_manager: Arc<VersionManager> = Arc::new(
-        VersionManager::new(updater_config.clone()).expect("Failed to initialize version manager"),
-    );
+    let version_manager: Arc<VersionManager> =
+        Arc::new(VersionManager::new(updater_config.clone())?);
 
     // Create a reference for the new thread (original stays with main thread)
     let version_manager_thread: Arc<VersionManager> = version_manager.clone();
 
     let update_interval = updater_config.update_interval;
+    let notify_only = updater_config.policy.notify_only;
+    let updater_dir = updater_config.updater_dir.clone();
 
     // Spawn the update checker thread
     thread::spawn(move || {
@@ -47,18 +54,57 @@ pub fn spawn_auto_update_thread(
             BLUE, RESET
         );
 
-        // Infinite loop to check for updates
+        // Check for updates; while an installed update waits for the prover to restart into it
+        // after its job, there is nothing to check
+        let mut installed = None;
+        let mut notified = None;
         loop {
+            if coordinator.has_pending() {
+                coordinator.wait_for_check(Duration::from_secs(update_interval));
+                continue;
+            }
+            // The prover dropped the installed update, so it was rolled back
+            if let Some(update) = installed.take() {
+                version_manager_thread.rolled_back(&update);
+            }
+
             match version_manager_thread.as_ref().update_version_status() {
                 // Got the latest version info with no error....
                 Ok(version_info) => match version_info {
-                    // ... there is an update available, try to apply it
+                    // ... there is an update available, but we only report it
+                    VersionStatus::UpdateAvailable(new_version) if notify_only => {
+                        println!(
+                            "{}[auto-updater]{} Version {} is available (notify-only mode, not installing it)",
+                            BLUE, RESET, new_version
+                        );
+                        if notified.as_ref() != Some(&new_version) {
+                            let current_version = version_manager_thread.current_version();
+                            rollback::log_update(
+                                &updater_dir,
+                                &format!(
+                                    "{} is available (running {}), not installing it in notify-only mode",
+                                    new_version, current_version
+                                ),
+                            );
+                            on_update_available(&current_version, &new_version);
+                            notified = Some(new_version);
+                        }
+                    }
+                    // ... there is an update available, try to install it
                     VersionStatus::UpdateAvailable(new_version) => {
-                        if let Err(e) = version_manager_thread.apply_update(&new_version) {
-                            println!(
+                        match version_manager_thread.prepare_update(&new_version) {
+                            Ok(update) => {
+                                println!(
+                                    "{}[auto-updater]{} Installed version {}, restarting after the current job",
+                                    BLUE, RESET, update.version
+                                );
+                                coordinator.set_pending(update.clone());
+                                installed = Some(update);
+                            }
+                            Err(e) => println!(
                                 "{}[auto-updater]{} Failed to update CLI: {}",
                                 BLUE, RESET, e
-                            )
+                            ),
                         }
                     }
                     // ... No update needed
@@ -79,7 +125,7 @@ pub fn spawn_auto_update_thread(
                 "{}[auto-updater]{} Next update check in {} seconds...\n",
                 BLUE, RESET, update_interval
             );
-            thread::sleep(Duration::from_secs(update_interval));
+            coordinator.wait_for_check(Duration::from_secs(update_interval));
         }
     });
 
@@ -1,26 +1,541 @@
+use crate::config::{ExperimentConfig, ExperimentsConfig};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+// Provers are split into this many buckets, the unit of enrollment
+pub const BUCKETS: u64 = 1000;
+
+// The arm of an override that keeps the prover out of the experiment
+pub const CONTROL_ARM: &str = "control";
+
+// The experiments in effect for this process, configured once at startup
+static REGISTRY: OnceLock<ExperimentRegistry> = OnceLock::new();
+
+// Deterministic bucket in 0..modulus for a seed, computed the same way as the web implementation
+pub fn bucket(seed: &str, modulus: u64) -> u64 {
+    // Create a deterministic hash from the seed
+    let hash = md5::compute(seed);
+    let hex_string = format!("{:x}", hash); // Convert hash to a hexadecimal string
+    let mut sum: u64 = 0;
+    // Sum each 8-character segment of the hex string
+    // It needs to be much more than 1000 to get an even distribution modulo 1000.
+    for i in (0..hex_string.len()).step_by(8) {
+        let slice = &hex_string[i..i + 8];
+        sum += u64::from_str_radix(slice, 16).unwrap(); // Parse as base-16
+    }
+    sum % modulus
+}
+
+#[derive(Debug, Clone)]
 pub struct Experiment {
-    name: &'static str,
-    target_enrollment_per_1000: u64,
+    pub name: String,
+    pub target_enrollment_per_1000: u64,
+    pub layer: Option<String>,
+    // Programs and their weights
+    pub programs: Vec<(String, u64)>,
+    // First bucket of this experiment within its layer
+    layer_offset: u64,
 }
 
 impl Experiment {
-    pub const CANCER_DIAGNOSTIC: Self = Self {
-        name: "NEX-1",
-        target_enrollment_per_1000: 10,
-    };
+    // The bucket that decides whether a prover is enrolled: shared by all experiments of a layer
+    pub fn enrollment_bucket(&self, prover_id: &str) -> u64 {
+        let salt = self.layer.as_deref().unwrap_or(&self.name);
+        bucket(&format!("{}|{}", prover_id, salt), BUCKETS)
+    }
+
+    // The enrollment buckets that belong to this experiment
+    pub fn enrolled_buckets(&self) -> std::ops::Range<u64> {
+        self.layer_offset..self.layer_offset + self.target_enrollment_per_1000
+    }
 
     pub fn is_enrolled(&self, prover_id: &str) -> bool {
-        // Create a deterministic hash from the prover_id
-        let hash = md5::compute(format!("{}|{}", prover_id, self.name));
-        let hex_string = format!("{:x}", hash); // Convert hash to a hexadecimal string
-        let mut sum: u64 = 0;
-        // Sum each 8-character segment of the hex string
-        // It needs to be much more than 1000 to get an even distribution modulo 1000.
-        for i in (0..hex_string.len()).step_by(8) {
-            let slice = &hex_string[i..i + 8];
-            sum += u64::from_str_radix(slice, 16).unwrap(); // Parse as base-16
-        }
-        // Return true if sum modulo 1000 is less than target
-        (sum % 1000) < self.target_enrollment_per_1000
+        self.enrolled_buckets()
+            .contains(&self.enrollment_bucket(prover_id))
+    }
+
+    // The program an enrolled prover is assigned to, picked in proportion to the weights
+    pub fn program_for(&self, prover_id: &str) -> &str {
+        if let [(program, _)] = self.programs.as_slice() {
+            return program;
+        }
+
+        let total_weight: u64 = self.programs.iter().map(|(_, weight)| weight).sum();
+        let mut remaining = bucket(
+            &format!("{}|{}|program", prover_id, self.name),
+            total_weight,
+        );
+        for (program, weight) in &self.programs {
+            if remaining < *weight {
+                return program;
+            }
+            remaining -= weight;
+        }
+        // Unreachable: the bucket is always below the total weight
+        &self.programs[0].0
+    }
+}
+
+// The outcome of bucketing a prover
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assignment {
+    pub program: String,
+    // The experiment that decided the program, if any
+    pub experiment: Option<String>,
+    // Whether the arm was pinned locally with `experiments override`
+    pub overridden: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct ExperimentRegistry {
+    experiments: Vec<Experiment>,
+    default_program: String,
+    // Arms pinned on this machine, by experiment name
+    overrides: BTreeMap<String, String>,
+}
+
+impl Default for ExperimentRegistry {
+    fn default() -> Self {
+        Self::from_config(&ExperimentsConfig::default())
+            .expect("the built-in experiments should be valid")
+    }
+}
+
+impl ExperimentRegistry {
+    pub fn from_config(config: &ExperimentsConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        if config.default_program.is_empty() {
+            return Err("the default program must not be empty".into());
+        }
+
+        let mut names = HashSet::new();
+        let mut layer_usage: BTreeMap<&str, u64> = BTreeMap::new();
+        let mut experiments = Vec::new();
+        for ExperimentConfig {
+            name,
+            enrollment_per_1000,
+            layer,
+            programs,
+        } in &config.registry
+        {
+            if !names.insert(name.as_str()) {
+                return Err(format!("experiment {} is defined more than once", name).into());
+            }
+            if *enrollment_per_1000 > BUCKETS {
+                return Err(format!(
+                    "experiment {} enrolls more than {} provers per {}",
+                    name, BUCKETS, BUCKETS
+                )
+                .into());
+            }
+            if programs.iter().any(|p| p.program.is_empty()) {
+                return Err(format!("experiment {} has a program without a name", name).into());
+            }
+            if programs.iter().map(|p| p.weight).sum::<u64>() == 0 {
+                return Err(format!("experiment {} has no program with a weight", name).into());
+            }
+
+            let layer_offset = match layer {
+                Some(layer) => {
+                    let used = layer_usage.entry(layer).or_insert(0);
+                    let offset = *used;
+                    *used += enrollment_per_1000;
+                    if *used > BUCKETS {
+                        return Err(format!(
+                            "the experiments of layer {} enroll more than {} provers per {}",
+                            layer, BUCKETS, BUCKETS
+                        )
+                        .into());
+                    }
+                    offset
+                }
+                None => 0,
+            };
+
+            experiments.push(Experiment {
+                name: name.clone(),
+                target_enrollment_per_1000: *enrollment_per_1000,
+                layer: layer.clone(),
+                programs: programs
+                    .iter()
+                    .filter(|p| p.weight > 0)
+                    .map(|p| (p.program.clone(), p.weight))
+                    .collect(),
+                layer_offset,
+            });
+        }
+
+        Ok(Self {
+            experiments,
+            default_program: config.default_program.clone(),
+            overrides: BTreeMap::new(),
+        })
+    }
+
+    // Pin an experiment to one of its programs, or to `CONTROL_ARM` to stay out of it
+    pub fn set_override(
+        &mut self,
+        name: &str,
+        arm: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let experiment = self
+            .experiments
+            .iter()
+            .find(|e| e.name == name)
+            .ok_or_else(|| format!("there is no experiment named {}", name))?;
+        if arm != CONTROL_ARM && !experiment.programs.iter().any(|(p, _)| p == arm) {
+            let arms: Vec<&str> = experiment
+                .programs
+                .iter()
+                .map(|(p, _)| p.as_str())
+                .chain([CONTROL_ARM])
+                .collect();
+            return Err(format!(
+                "{} is not an arm of experiment {} (expected one of: {})",
+                arm,
+                name,
+                arms.join(", ")
+            )
+            .into());
+        }
+
+        self.overrides.insert(name.to_string(), arm.to_string());
+        Ok(())
+    }
+
+    pub fn overrides(&self) -> &BTreeMap<String, String> {
+        &self.overrides
+    }
+
+    // The overrides in effect, e.g. "NEX-1=cancer-diagnostic", or None if there are none
+    pub fn override_summary(&self) -> Option<String> {
+        if self.overrides.is_empty() {
+            return None;
+        }
+        let overrides: Vec<String> = self
+            .overrides
+            .iter()
+            .map(|(name, arm)| format!("{}={}", name, arm))
+            .collect();
+        Some(overrides.join(","))
+    }
+
+    pub fn experiments(&self) -> &[Experiment] {
+        &self.experiments
+    }
+
+    pub fn default_program(&self) -> &str {
+        &self.default_program
+    }
+
+    // The experiments the prover is evaluated in, in order, up to the first one it is enrolled in
+    pub fn exposures(&self, prover_id: &str) -> Vec<Exposure> {
+        let mut exposures = Vec::new();
+        for experiment in &self.experiments {
+            let (arm, overridden) = match self.overrides.get(&experiment.name) {
+                Some(arm) => (arm.clone(), true),
+                None if experiment.is_enrolled(prover_id) => {
+                    (experiment.program_for(prover_id).to_string(), false)
+                }
+                None => (CONTROL_ARM.to_string(), false),
+            };
+            let enrolled = arm != CONTROL_ARM;
+            exposures.push(Exposure {
+                experiment: experiment.name.clone(),
+                arm,
+                overridden,
+            });
+            if enrolled {
+                break;
+            }
+        }
+        exposures
+    }
+
+    // The first experiment the prover is enrolled in decides its program
+    pub fn assign(&self, prover_id: &str) -> Assignment {
+        let enrolled = self
+            .exposures(prover_id)
+            .into_iter()
+            .find(|exposure| exposure.arm != CONTROL_ARM);
+        match enrolled {
+            Some(exposure) => Assignment {
+                program: exposure.arm,
+                experiment: Some(exposure.experiment),
+                overridden: exposure.overridden,
+            },
+            None => Assignment {
+                program: self.default_program.clone(),
+                experiment: None,
+                overridden: false,
+            },
+        }
+    }
+}
+
+// An experiment a prover was evaluated in, and the arm it got
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exposure {
+    pub experiment: String,
+    // A program, or `CONTROL_ARM` if the prover is not enrolled
+    pub arm: String,
+    pub overridden: bool,
+}
+
+// Where exposures already reported are remembered, e.g.
+// ~/.local/state/nexus/experiment-exposures.json
+pub fn exposures_path() -> Option<PathBuf> {
+    crate::state::current().map(|dirs| dirs.state.join("experiment-exposures.json"))
+}
+
+// Keep only the exposures that were never recorded for this prover, and record them.
+// An exposure is new when the prover gets an arm of an experiment for the first time.
+pub fn record_new_exposures(
+    path: &Path,
+    prover_id: &str,
+    exposures: Vec<Exposure>,
+) -> Result<Vec<Exposure>, Box<dyn std::error::Error>> {
+    let mut recorded: BTreeSet<String> = match fs::read_to_string(path) {
+        Ok(content) => serde_json::from_str(&content)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeSet::new(),
+        Err(e) => return Err(e.into()),
+    };
+
+    let new_exposures: Vec<Exposure> = exposures
+        .into_iter()
+        .filter(|exposure| {
+            recorded.insert(format!(
+                "{}|{}|{}",
+                prover_id, exposure.experiment, exposure.arm
+            ))
+        })
+        .collect();
+
+    if !new_exposures.is_empty() {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, serde_json::to_string(&recorded)?)?;
+    }
+    Ok(new_exposures)
+}
+
+// Where overrides are kept, e.g. ~/.config/nexus/experiment-overrides.json
+pub fn overrides_path() -> Option<PathBuf> {
+    crate::state::current().map(|dirs| dirs.config.join("experiment-overrides.json"))
+}
+
+// Load the overrides pinned on this machine, by experiment name
+pub fn load_overrides(path: &Path) -> Result<BTreeMap<String, String>, Box<dyn std::error::Error>> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(serde_json::from_str(&content)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn save_overrides(
+    path: &Path,
+    overrides: &BTreeMap<String, String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(overrides)?)?;
+    Ok(())
+}
+
+// Set the experiments used by every later call to `registry`
+pub fn configure(registry: ExperimentRegistry) {
+    if REGISTRY.set(registry).is_err() {
+        eprintln!("Warning: experiments were already configured");
+    }
+}
+
+pub fn registry() -> &'static ExperimentRegistry {
+    REGISTRY.get_or_init(ExperimentRegistry::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ProgramWeight;
+    use serde::Deserialize;
+    use tempfile::TempDir;
+
+    // Shared with the web implementation, which must produce exactly the same results
+    const GOLDEN_VECTORS: &str = include_str!("../../tests/experiment-golden-vectors.json");
+
+    #[derive(Deserialize)]
+    struct GoldenVectors {
+        buckets: Vec<BucketVector>,
+        registries: Vec<RegistryVectors>,
+    }
+
+    #[derive(Deserialize)]
+    struct BucketVector {
+        seed: String,
+        modulus: u64,
+        bucket: u64,
+    }
+
+    #[derive(Deserialize)]
+    struct RegistryVectors {
+        name: String,
+        experiments: ExperimentsConfig,
+        assignments: Vec<AssignmentVector>,
+    }
+
+    #[derive(Deserialize)]
+    struct AssignmentVector {
+        prover_id: String,
+        program: String,
+        experiment: Option<String>,
+    }
+
+    fn experiment(name: &str, enrollment: u64, layer: Option<&str>) -> ExperimentConfig {
+        ExperimentConfig {
+            name: name.into(),
+            enrollment_per_1000: enrollment,
+            layer: layer.map(Into::into),
+            programs: vec![
+                ProgramWeight {
+                    program: "fast-fib".into(),
+                    weight: 1,
+                },
+                ProgramWeight {
+                    program: "cancer-diagnostic".into(),
+                    weight: 3,
+                },
+            ],
+        }
+    }
+
+    /// Tests that bucketing and program assignment match the shared golden vectors
+    #[test]
+    fn test_golden_vectors() {
+        let vectors: GoldenVectors = serde_json::from_str(GOLDEN_VECTORS).unwrap();
+
+        for vector in &vectors.buckets {
+            assert_eq!(
+                bucket(&vector.seed, vector.modulus),
+                vector.bucket,
+                "bucket of {:?}",
+                vector.seed
+            );
+        }
+
+        for registry_vectors in &vectors.registries {
+            let registry = ExperimentRegistry::from_config(&registry_vectors.experiments).unwrap();
+            for vector in &registry_vectors.assignments {
+                let assignment = registry.assign(&vector.prover_id);
+                assert_eq!(
+                    (&assignment.program, &assignment.experiment),
+                    (&vector.program, &vector.experiment),
+                    "assignment of {:?} in the {} registry",
+                    vector.prover_id,
+                    registry_vectors.name
+                );
+            }
+        }
+    }
+
+    /// Tests that an exposure is only reported the first time a prover gets an arm
+    #[test]
+    fn test_exposures_are_recorded_once() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("experiment-exposures.json");
+        let mut registry = ExperimentRegistry::default();
+
+        let exposures = registry.exposures("53");
+        let new = record_new_exposures(&path, "53", exposures.clone()).unwrap();
+        assert_eq!(new, exposures);
+        assert_eq!(new[0].arm, "cancer-diagnostic");
+        assert!(record_new_exposures(&path, "53", exposures)
+            .unwrap()
+            .is_empty());
+
+        // A different arm is a new exposure
+        registry.set_override("NEX-1", CONTROL_ARM).unwrap();
+        let new = record_new_exposures(&path, "53", registry.exposures("53")).unwrap();
+        assert_eq!(new.len(), 1);
+        assert!(new[0].overridden);
+    }
+
+    /// Tests that experiments sharing a layer never enroll the same prover
+    #[test]
+    fn test_layered_experiments_are_mutually_exclusive() {
+        let config = ExperimentsConfig {
+            default_program: "fast-fib".into(),
+            registry: vec![
+                experiment("A", 300, Some("programs")),
+                experiment("B", 300, Some("programs")),
+            ],
+        };
+        let registry = ExperimentRegistry::from_config(&config).unwrap();
+        let [a, b] = registry.experiments() else {
+            panic!("Expected two experiments");
+        };
+
+        let mut enrolled = [0, 0];
+        for id in 0..2000 {
+            let id = id.to_string();
+            assert!(!(a.is_enrolled(&id) && b.is_enrolled(&id)));
+            enrolled[0] += a.is_enrolled(&id) as usize;
+            enrolled[1] += b.is_enrolled(&id) as usize;
+        }
+        assert!((450..750).contains(&enrolled[0]), "{:?}", enrolled);
+        assert!((450..750).contains(&enrolled[1]), "{:?}", enrolled);
+    }
+
+    /// Tests that enrolled provers are assigned programs in proportion to their weights
+    #[test]
+    fn test_weighted_program_assignment() {
+        let config = ExperimentsConfig {
+            default_program: "fast-fib".into(),
+            registry: vec![experiment("A", 1000, None)],
+        };
+        let registry = ExperimentRegistry::from_config(&config).unwrap();
+
+        let cancer_diagnostic = (0..2000)
+            .map(|id| registry.assign(&id.to_string()))
+            .filter(|assignment| assignment.program == "cancer-diagnostic")
+            .count();
+        assert!((1350..1650).contains(&cancer_diagnostic));
+    }
+
+    /// Tests that overrides pin an arm (or the control group) regardless of bucketing
+    #[test]
+    fn test_overrides_pin_arm() {
+        let mut registry = ExperimentRegistry::default();
+        // "53" is enrolled in NEX-1 and "52" is not
+        registry.set_override("NEX-1", CONTROL_ARM).unwrap();
+        assert_eq!(registry.assign("53").program, "fast-fib");
+
+        registry.set_override("NEX-1", "cancer-diagnostic").unwrap();
+        let assignment = registry.assign("52");
+        assert_eq!(assignment.program, "cancer-diagnostic");
+        assert!(assignment.overridden);
+        assert_eq!(
+            registry.override_summary().as_deref(),
+            Some("NEX-1=cancer-diagnostic")
+        );
+
+        assert!(registry.set_override("NEX-1", "fast-fib").is_err());
+        assert!(registry.set_override("NEX-404", CONTROL_ARM).is_err());
+    }
+
+    /// Tests that a layer enrolling more than every prover is rejected
+    #[test]
+    fn test_overfull_layer_is_rejected() {
+        let config = ExperimentsConfig {
+            default_program: "fast-fib".into(),
+            registry: vec![
+                experiment("A", 600, Some("programs")),
+                experiment("B", 600, Some("programs")),
+            ],
+        };
+        assert!(ExperimentRegistry::from_config(&config).is_err());
     }
 }
@@ -1,4 +1,5 @@
 pub mod cli_branding;
 pub mod experiment;
 pub mod prover;
+pub mod retry;
 pub mod updater;
@@ -0,0 +1,230 @@
+//! Retrying with exponential backoff
+//!
+//! When the orchestrator has an outage, every prover loses its connection at the same moment. If
+//! they all retried on the same schedule they would reconnect in lockstep and knock it over
+//! again, so delays use "full jitter": each one is random between zero and an exponentially
+//! growing cap.
+//!
+//! A [`Backoff`] follows one connection over its lifetime. Failures keep growing the delay until
+//! the connection has been up for a while again. After the maximum number of attempts the circuit
+//! opens: further attempts fail immediately until it closes again.
+
+use rand::Rng;
+use std::time::{Duration, Instant};
+
+use crate::config::RetryConfig;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Cap of the delay before the first retry, doubled for every further one
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// How many attempts to make before giving up, or None to retry forever
+    pub max_attempts: Option<u32>,
+    /// How long a connection has to stay up before its earlier failures are forgotten
+    pub reset_after: Duration,
+    /// How long to fail immediately after giving up
+    pub circuit_open_for: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::from_config(&RetryConfig::default())
+    }
+}
+
+impl RetryPolicy {
+    pub fn from_config(config: &RetryConfig) -> Self {
+        Self {
+            initial_delay: Duration::from_millis(config.initial_delay_ms),
+            max_delay: Duration::from_secs(config.max_delay_secs),
+            max_attempts: config.max_attempts,
+            reset_after: Duration::from_secs(config.reset_after_secs),
+            circuit_open_for: Duration::from_secs(config.circuit_open_secs),
+        }
+    }
+
+    /// The longest delay before retry number `retry` (starting at 1)
+    pub fn delay_cap(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+        self.initial_delay
+            .checked_mul(factor)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
+    }
+
+    /// A random delay between zero and the cap before retry number `retry`
+    pub fn delay(&self, retry: u32, rng: &mut impl Rng) -> Duration {
+        self.delay_cap(retry).mul_f64(rng.gen::<f64>())
+    }
+}
+
+#[derive(Debug)]
+pub struct Backoff {
+    policy: RetryPolicy,
+    failures: u32,
+    connected_at: Option<Instant>,
+    open_until: Option<Instant>,
+}
+
+impl Backoff {
+    pub fn new(policy: RetryPolicy) -> Self {
+        Self {
+            policy,
+            failures: 0,
+            connected_at: None,
+            open_until: None,
+        }
+    }
+
+    pub fn policy(&self) -> &RetryPolicy {
+        &self.policy
+    }
+
+    /// Consecutive failed attempts so far
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Record a successful attempt
+    pub fn succeeded(&mut self) {
+        self.succeeded_at(Instant::now());
+    }
+
+    fn succeeded_at(&mut self, now: Instant) {
+        self.connected_at = Some(now);
+        self.open_until = None;
+    }
+
+    /// Record a failed attempt. Returns how long to wait before the next one, or None to give up.
+    pub fn failed(&mut self) -> Option<Duration> {
+        self.failed_at(Instant::now(), &mut rand::thread_rng())
+    }
+
+    fn failed_at(&mut self, now: Instant, rng: &mut impl Rng) -> Option<Duration> {
+        if let Some(connected_at) = self.connected_at.take() {
+            if now.duration_since(connected_at) >= self.policy.reset_after {
+                self.failures = 0;
+            }
+        }
+
+        self.failures += 1;
+        if self
+            .policy
+            .max_attempts
+            .is_some_and(|max_attempts| self.failures >= max_attempts)
+        {
+            // Give up, and start over once the circuit closes
+            self.failures = 0;
+            self.open_until = Some(now + self.policy.circuit_open_for);
+            return None;
+        }
+        Some(self.policy.delay(self.failures, rng))
+    }
+
+    /// Fail attempts immediately for a while, e.g. when the other end says it is overloaded
+    pub fn defer(&mut self, duration: Duration) {
+        let open_until = Instant::now() + duration;
+        self.open_until = Some(
+            self.open_until
+                .map_or(open_until, |until| until.max(open_until)),
+        );
+    }
+
+    /// How much longer attempts should fail immediately, if the circuit is open
+    pub fn open_for(&self) -> Option<Duration> {
+        self.open_for_at(Instant::now())
+    }
+
+    fn open_for_at(&self, now: Instant) -> Option<Duration> {
+        self.open_until
+            .filter(|open_until| *open_until > now)
+            .map(|open_until| open_until - now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn policy(max_attempts: Option<u32>) -> RetryPolicy {
+        RetryPolicy {
+            initial_delay: Duration::from_secs(2),
+            max_delay: Duration::from_secs(64),
+            max_attempts,
+            reset_after: Duration::from_secs(60),
+            circuit_open_for: Duration::from_secs(600),
+        }
+    }
+
+    /// Tests that delays double from the initial delay up to the maximum, with full jitter
+    #[test]
+    fn test_delays_grow_exponentially_with_jitter() {
+        let policy = policy(None);
+        let caps: Vec<u64> = (1..=8)
+            .map(|retry| policy.delay_cap(retry).as_secs())
+            .collect();
+        assert_eq!(caps, [2, 4, 8, 16, 32, 64, 64, 64]);
+        assert_eq!(policy.delay_cap(u32::MAX), Duration::from_secs(64));
+
+        let mut rng = StdRng::seed_from_u64(7);
+        let delays: Vec<Duration> = (0..100).map(|_| policy.delay(3, &mut rng)).collect();
+        assert!(delays.iter().all(|delay| *delay <= Duration::from_secs(8)));
+        // Jittered, not in lockstep
+        assert!(delays.iter().any(|delay| *delay < Duration::from_secs(4)));
+        assert!(delays.iter().any(|delay| *delay > Duration::from_secs(4)));
+    }
+
+    /// Tests that exactly the maximum number of attempts is made before the circuit opens
+    #[test]
+    fn test_max_attempts_then_circuit_opens() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut backoff = Backoff::new(policy(Some(5)));
+        let now = Instant::now();
+
+        // Attempts 1 to 4 are followed by a retry, attempt 5 gives up
+        for _ in 1..5 {
+            assert!(backoff.failed_at(now, &mut rng).is_some());
+        }
+        assert_eq!(backoff.failed_at(now, &mut rng), None);
+
+        assert_eq!(backoff.open_for_at(now), Some(Duration::from_secs(600)));
+        assert_eq!(backoff.open_for_at(now + Duration::from_secs(600)), None);
+        // After the circuit closes, there is a full set of attempts again
+        assert!(backoff.failed_at(now, &mut rng).is_some());
+    }
+
+    /// Tests that unlimited policies never give up
+    #[test]
+    fn test_unlimited_attempts() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut backoff = Backoff::new(policy(None));
+        for _ in 0..1000 {
+            assert!(backoff.failed_at(Instant::now(), &mut rng).is_some());
+        }
+        assert_eq!(backoff.open_for(), None);
+    }
+
+    /// Tests that failures are only forgotten once a connection has been stable for a while
+    #[test]
+    fn test_reset_after_stable_period() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut backoff = Backoff::new(policy(None));
+        let now = Instant::now();
+        for _ in 0..3 {
+            backoff.failed_at(now, &mut rng);
+        }
+
+        // A connection that drops right away doesn't reset the backoff
+        backoff.succeeded_at(now);
+        backoff.failed_at(now + Duration::from_secs(10), &mut rng);
+        assert_eq!(backoff.failures(), 4);
+
+        // One that stayed up does
+        backoff.succeeded_at(now);
+        backoff.failed_at(now + Duration::from_secs(60), &mut rng);
+        assert_eq!(backoff.failures(), 1);
+    }
+}
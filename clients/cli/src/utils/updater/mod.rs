@@ -0,0 +1,1118 @@
+//! Core auto-updater functionality and version management
+//!
+//! This module provides the underlying implementation for:
+//! - Version tracking and persistence
+//! - Version detection from pluggable version sources, narrowed down by update policies
+//! - Update application logic, from signed release binaries or from source
+//! - Process management for CLI restarts
+//!
+//! The code here is used by the auto-updater thread (./updater.rs) to handle the mechanics of
+//! checking versions and applying updates in both test and production environments.
+
+pub mod policy;
+pub mod release;
+pub mod rollback;
+pub mod source;
+
+use chrono::{DateTime, Utc};
+use parking_lot::{Condvar, Mutex, RwLock};
+use semver::Version;
+use std::ffi::OsString;
+use std::fs;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use policy::UpdatePolicy;
+use source::{GitRemote, LocalGitRepo, VersionSource};
+
+// Constants
+
+// ANSI escape codes for colors for pretty printing
+pub const BLUE: &str = "\x1b[34m"; // Normal blue
+pub const RESET: &str = "\x1b[0m"; // Reset color
+
+// The file to store the current version in, in the state directory
+pub const VERSION_FILE: &str = "current-version";
+// Where the CLI crate lives in the repository
+const CLI_CRATE_DIR: &str = "clients/cli";
+pub const FALLBACK_VERSION: Version = Version::new(0, 3, 6);
+// How long a restarted process has to stay up before the old one hands over to it
+pub const RESTART_STARTUP_CHECK: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum AutoUpdaterMode {
+    Production,
+    Test,
+}
+
+/// Struct to manage the updater configuration
+#[derive(Clone)]
+pub struct UpdaterConfig {
+    pub mode: AutoUpdaterMode,
+    pub update_interval: u64,
+    pub repo_path: String,
+    pub remote_repo: String,
+    // How this process was started, so an update restarts it the same way
+    pub launch: LaunchSpec,
+    // Backups, the skip list and the updater log
+    pub updater_dir: PathBuf,
+    // The installed version, and the PID of the process running it
+    pub version_file: PathBuf,
+    pub pid_file: PathBuf,
+    // How long a new version has to connect and prove its first step before it is rolled back
+    pub readiness_timeout: Duration,
+    // Where to look for new versions
+    pub version_source: Arc<dyn VersionSource>,
+    // Which versions to install, and whether to install them at all
+    pub policy: UpdatePolicy,
+    // Where to find the manifest of prebuilt release binaries, with `{version}` in place of the
+    // version. Without it (or a release public key), updates are built from source.
+    pub release_manifest_url: Option<String>,
+    pub release_public_key: Option<String>,
+}
+
+impl UpdaterConfig {
+    pub fn new(mode: AutoUpdaterMode, launch: LaunchSpec) -> Self {
+        match mode {
+            AutoUpdaterMode::Production => {
+                let remote_repo = String::from("https://github.com/nexus-xyz/network-api.git");
+                Self {
+                    mode,
+                    repo_path: crate::state::current()
+                        .map(|dirs| dirs.cache)
+                        .unwrap_or_else(|| PathBuf::from(".nexus"))
+                        .join("network-api")
+                        .to_string_lossy()
+                        .into_owned(),
+                    version_source: Arc::new(GitRemote {
+                        url: remote_repo.clone(),
+                    }),
+                    remote_repo,
+                    update_interval: 3600, // check for updates every 1 hour (3600 seconds)
+                    launch,
+                    updater_dir: rollback::default_updater_dir(),
+                    version_file: state_file(VERSION_FILE),
+                    pid_file: state_file("prover.pid"),
+                    readiness_timeout: Duration::from_secs(15 * 60),
+                    policy: UpdatePolicy::default(),
+                    // Set from the configuration file: only releases signed with the release key
+                    // can be installed, so there is no default
+                    release_manifest_url: None,
+                    release_public_key: Some(release::RELEASE_PUBLIC_KEY.to_string()),
+                }
+            }
+            AutoUpdaterMode::Test => {
+                let repo_path = std::env::current_dir().expect("Failed to get current directory");
+                Self {
+                    mode,
+                    version_source: Arc::new(LocalGitRepo {
+                        path: repo_path.clone(),
+                    }),
+                    repo_path: repo_path.to_string_lossy().into_owned(),
+                    remote_repo: String::from("."),
+                    update_interval: 30, // check for updates every 30 seconds
+                    launch,
+                    updater_dir: rollback::default_updater_dir(),
+                    version_file: state_file(VERSION_FILE),
+                    pid_file: state_file("prover.pid"),
+                    readiness_timeout: Duration::from_secs(5 * 60),
+                    policy: UpdatePolicy::default(),
+                    release_manifest_url: None,
+                    release_public_key: None,
+                }
+            }
+        }
+    }
+}
+
+impl UpdaterConfig {
+    /// The manifest of the CLI crate that updates are built from source with
+    fn cli_manifest_path(&self) -> PathBuf {
+        let repo_path = Path::new(&self.repo_path);
+        match self.mode {
+            // A checkout of the whole repository
+            AutoUpdaterMode::Production => repo_path.join(CLI_CRATE_DIR).join("Cargo.toml"),
+            // The test mode builds the crate it was started from
+            AutoUpdaterMode::Test => repo_path.join("Cargo.toml"),
+        }
+    }
+}
+
+/// A file in the state directory, or in the working directory if there is none
+fn state_file(name: &str) -> PathBuf {
+    crate::state::current()
+        .map(|dirs| dirs.state.join(name))
+        .unwrap_or_else(|| PathBuf::from(name))
+}
+
+/// The command line, environment and working directory the CLI was started with
+#[derive(Debug, Clone)]
+pub struct LaunchSpec {
+    /// The running executable. Captured at startup, because once an update has replaced it,
+    /// `current_exe` no longer resolves to the installed path.
+    pub executable: PathBuf,
+    /// Arguments after the program name
+    pub args: Vec<OsString>,
+    pub env: Vec<(OsString, OsString)>,
+    pub current_dir: PathBuf,
+}
+
+impl LaunchSpec {
+    /// Capture how the current process was started. Call this early, before anything changes
+    /// the environment or the working directory.
+    pub fn capture() -> Self {
+        Self {
+            executable: std::env::current_exe().unwrap_or_else(|_| PathBuf::from("prover")),
+            args: std::env::args_os().skip(1).collect(),
+            // The readiness file and lock handover are specific to one restart
+            env: std::env::vars_os()
+                .filter(|(key, _)| {
+                    key != rollback::READINESS_FILE_ENV && key != crate::state::LOCK_HANDOVER_ENV
+                })
+                .collect(),
+            current_dir: std::env::current_dir().unwrap_or_else(|_| PathBuf::from(".")),
+        }
+    }
+
+    /// Apply the arguments, environment and working directory to a command
+    fn apply(&self, command: &mut Command) {
+        command
+            .args(&self.args)
+            .env_clear()
+            .envs(self.env.iter().map(|(key, value)| (key, value)))
+            .current_dir(&self.current_dir);
+    }
+}
+
+/// A new version that has been installed and is waiting for the CLI to restart into it
+#[derive(Debug, Clone)]
+pub struct PreparedUpdate {
+    pub version: Version,
+    /// The installed executable, or None to restart with `cargo run` in the repository
+    pub executable: Option<PathBuf>,
+    /// The version that was running before, and a copy of its executable
+    pub previous_version: Version,
+    pub backup: Option<PathBuf>,
+}
+
+/// Hands installed updates from the updater thread to the prover, which restarts into them only
+/// between jobs so an update never interrupts a proof
+#[derive(Default)]
+pub struct UpdateCoordinator {
+    pending: Mutex<Option<PreparedUpdate>>,
+    // Whether the prover asked for an update check before the next scheduled one
+    check_requested: Mutex<bool>,
+    check_requested_changed: Condvar,
+}
+
+impl UpdateCoordinator {
+    pub fn set_pending(&self, update: PreparedUpdate) {
+        *self.pending.lock() = Some(update);
+    }
+
+    pub fn has_pending(&self) -> bool {
+        self.pending.lock().is_some()
+    }
+
+    pub fn pending(&self) -> Option<PreparedUpdate> {
+        self.pending.lock().clone()
+    }
+
+    /// Forget the pending update, e.g. after it was rolled back, so the updater checks again
+    pub fn clear_pending(&self) {
+        self.pending.lock().take();
+    }
+
+    /// Ask the updater to check for updates now, e.g. when the orchestrator says this version is
+    /// too old
+    pub fn request_check(&self) {
+        *self.check_requested.lock() = true;
+        self.check_requested_changed.notify_all();
+    }
+
+    /// Wait until the next scheduled check, or until a check is requested. Returns whether a
+    /// check was requested.
+    pub fn wait_for_check(&self, timeout: Duration) -> bool {
+        let mut check_requested = self.check_requested.lock();
+        if !*check_requested {
+            self.check_requested_changed.wait_while_for(
+                &mut check_requested,
+                |requested| !*requested,
+                timeout,
+            );
+        }
+        std::mem::take(&mut *check_requested)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum VersionStatus {
+    UpdateAvailable(Version), // in case there is an update available, there is a semver `Version` type
+    UpToDate,
+}
+
+// Struct to manage the version of the CLI it is running at all times
+// This is also used to check for updates and apply them
+pub struct VersionManager {
+    current_version: Arc<RwLock<Version>>,
+    config: UpdaterConfig,
+}
+
+impl VersionManager {
+    /// Initialize the version manager
+    pub fn new(config: UpdaterConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let current_version = Arc::new(RwLock::new(
+            read_version_from_file(&config.version_file).unwrap_or(FALLBACK_VERSION),
+        ));
+        Ok(Self {
+            current_version,
+            config,
+        })
+    }
+
+    /// Fetch the current version of the CLI and persist it to a file
+    pub fn fetch_and_persist_cli_version(&self) -> Result<Version, Box<dyn std::error::Error>> {
+        // 1. Get the current git tag version (which depends on the updater mode)
+        let current_git_version = self.get_cli_release_version(false)?;
+
+        // 2. Convert the semver to a number and write it to a file (so it can persist across updates)
+        write_version_to_file(&self.config.version_file, &current_git_version)?;
+
+        println!(
+            "{}[auto-updater]{} Wrote version to file: {}",
+            BLUE, RESET, current_git_version
+        );
+
+        Ok(current_git_version)
+    }
+
+    /// Get the release version of the CLI to run: the highest release allowed by the update
+    /// policy that has been out long enough
+    fn get_cli_release_version(
+        &self,
+        should_write: bool,
+    ) -> Result<Version, Box<dyn std::error::Error>> {
+        let policy = &self.config.policy;
+        let skipped = rollback::load_skipped_versions(&self.config.updater_dir);
+        let current_version = self.current_version.read().clone();
+        let now = Utc::now();
+
+        let version = policy
+            .candidates(self.config.version_source.versions()?, &skipped)
+            .into_iter()
+            .find(|version| {
+                if *version == current_version || policy.min_release_age.is_zero() {
+                    return true;
+                }
+                let old_enough = policy.is_old_enough(self.release_time(version), now);
+                if !old_enough {
+                    println!(
+                        "{}[auto-updater]{} Version {} is newer than the minimum release age of {} hours",
+                        BLUE,
+                        RESET,
+                        version,
+                        policy.min_release_age.as_secs() / 3600
+                    );
+                }
+                old_enough
+            })
+            .ok_or("No release versions allowed by the update policy")?;
+
+        // Optionally persist the version to disk
+        if should_write {
+            write_version_to_file(&self.config.version_file, &version)?;
+            println!(
+                "{}[auto-updater]{} Wrote version to file: {}",
+                BLUE, RESET, version
+            );
+        }
+
+        Ok(version)
+    }
+
+    /// When a version was released, according to the version source or else its release
+    /// manifest
+    fn release_time(&self, version: &Version) -> Option<DateTime<Utc>> {
+        self.config
+            .version_source
+            .release_time(version)
+            .or_else(|| {
+                let template = self.config.release_manifest_url.as_ref()?;
+                release::fetch_manifest(&release::manifest_url(template, version))
+                    .ok()?
+                    .published_at
+            })
+    }
+
+    /// Install a new version of the CLI, preferring a signed release binary and falling back to
+    /// building the release from source. The running process keeps the old code until it is
+    /// restarted with `restart_cli_process_with_new_version`.
+    pub fn prepare_update(
+        &self,
+        new_version: &Version,
+    ) -> Result<PreparedUpdate, Box<dyn std::error::Error>> {
+        let previous_version = self.current_version.read().clone();
+        let installed_executable = &self.config.launch.executable;
+        let backup =
+            match rollback::backup_executable(installed_executable, &self.config.updater_dir) {
+                Ok(backup) => Some(backup),
+                Err(e) => {
+                    println!(
+                        "{}[auto-updater]{} Could not back up the current executable: {}",
+                        BLUE, RESET, e
+                    );
+                    None
+                }
+            };
+
+        let mut executable = None;
+        if let (Some(manifest_url), Some(public_key)) = (
+            &self.config.release_manifest_url,
+            &self.config.release_public_key,
+        ) {
+            match release::install_release(
+                manifest_url,
+                new_version,
+                public_key,
+                installed_executable,
+            ) {
+                Ok(()) => executable = Some(installed_executable.clone()),
+                Err(e) => println!(
+                    "{}[auto-updater]{} Binary update failed, building from source instead: {}",
+                    BLUE, RESET, e
+                ),
+            }
+        }
+        if executable.is_none() {
+            self.build_update_from_source(new_version)?;
+        }
+
+        // Update version tracking
+        *self.current_version.write() = new_version.clone();
+        write_version_to_file(&self.config.version_file, new_version)?;
+        rollback::log_update(
+            &self.config.updater_dir,
+            &format!(
+                "installed {} (previously {}) {}",
+                new_version,
+                previous_version,
+                if executable.is_some() {
+                    "from a release binary"
+                } else {
+                    "from source"
+                }
+            ),
+        );
+
+        Ok(PreparedUpdate {
+            version: new_version.clone(),
+            executable,
+            previous_version,
+            backup,
+        })
+    }
+
+    /// Check out and build a new version with git and cargo
+    fn build_update_from_source(
+        &self,
+        new_version: &Version,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        println!(
+            "{}[auto-updater]{} Using repo path: {}",
+            BLUE, RESET, self.config.repo_path
+        );
+
+        let repo_path = std::path::Path::new(&self.config.repo_path);
+        if !repo_path.exists() {
+            return Err(format!("Repository not found at: {}", self.config.repo_path).into());
+        }
+
+        match self.config.mode {
+            AutoUpdaterMode::Test => {
+                println!(
+                    "{}[auto-updater]{} Building version {} from local repository...",
+                    BLUE, RESET, new_version
+                );
+                let build_output = Command::new("cargo")
+                    .args(["build", "--release", "--bin", "prover", "--manifest-path"])
+                    .arg(self.config.cli_manifest_path())
+                    .envs(crate::proxy::current().child_env())
+                    .current_dir(repo_path)
+                    .output()?;
+
+                if !build_output.status.success() {
+                    return Err(format!(
+                        "Build failed: {}",
+                        String::from_utf8_lossy(&build_output.stderr)
+                    )
+                    .into());
+                }
+            }
+            AutoUpdaterMode::Production => {
+                if repo_path.read_dir()?.next().is_none() {
+                    println!(
+                        "{}[auto-updater]{} Cloning remote repository...",
+                        BLUE, RESET
+                    );
+                    Command::new("git")
+                        .args(["clone", &self.config.remote_repo, &self.config.repo_path])
+                        .envs(crate::proxy::current().child_env())
+                        .output()?;
+                }
+
+                println!("{}[auto-updater]{} Fetching updates...", BLUE, RESET);
+                Command::new("git")
+                    .args(["fetch", "--all", "--tags", "--prune"])
+                    .envs(crate::proxy::current().child_env())
+                    .current_dir(repo_path)
+                    .output()?;
+
+                println!(
+                    "{}[auto-updater]{} Checking out version {}...",
+                    BLUE, RESET, new_version
+                );
+                checkout_version(repo_path, new_version)?;
+
+                println!(
+                    "{}[auto-updater]{} Building version {} from remote repository...",
+                    BLUE, RESET, new_version
+                );
+                let build_output = Command::new("cargo")
+                    .args(["build", "--release", "--bin", "prover", "--manifest-path"])
+                    .arg(self.config.cli_manifest_path())
+                    .envs(crate::proxy::current().child_env())
+                    .current_dir(repo_path)
+                    .output()?;
+
+                if !build_output.status.success() {
+                    return Err(format!(
+                        "Build failed: {}",
+                        String::from_utf8_lossy(&build_output.stderr)
+                    )
+                    .into());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn current_version(&self) -> Version {
+        self.current_version.read().clone()
+    }
+
+    /// Go back to tracking the version that was running before a rolled back update
+    pub fn rolled_back(&self, update: &PreparedUpdate) {
+        *self.current_version.write() = update.previous_version.clone();
+    }
+
+    /// update the version status of the CLI. is there an update available?
+    pub fn update_version_status(&self) -> Result<VersionStatus, Box<dyn std::error::Error>> {
+        let this_repo_version = self.current_version.read().clone();
+
+        // debug output
+        println!(
+            "{}[auto-updater]{} Checking for updates from: {}",
+            BLUE,
+            RESET,
+            self.config.version_source.describe()
+        );
+
+        let latest_version = match self.get_cli_release_version(false) {
+            Ok(version) => version,
+            Err(e) => {
+                println!(
+                    "{}[auto-updater]{} Version check failed: {}",
+                    BLUE, RESET, e
+                );
+                return Ok(VersionStatus::UpToDate); // Gracefully handle error by assuming up-to-date
+            }
+        };
+
+        println!(
+            "{}[auto-updater]{} Current version of CLI: {} | Latest allowed version of CLI: {}",
+            BLUE, RESET, this_repo_version, latest_version
+        );
+
+        if this_repo_version == latest_version {
+            Ok(VersionStatus::UpToDate)
+        } else {
+            Ok(VersionStatus::UpdateAvailable(latest_version))
+        }
+    }
+}
+
+/// function to read the current git tag version from a file
+pub fn read_version_from_file(path: &Path) -> Result<Version, Box<dyn std::error::Error>> {
+    let version_str = fs::read_to_string(path)?;
+    Ok(Version::parse(&version_str)?)
+}
+
+/// function to write the current git tag version to a file so it can be read by the updater thread
+/// We write to a file because storing the version in memory is not persistent across updates
+pub fn write_version_to_file(
+    path: &Path,
+    version: &Version,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, version.to_string())?;
+    Ok(())
+}
+
+/// Start the CLI with an installed update, with the same arguments, environment and working
+/// directory as this process. The executable of the update is run directly, or for updates
+/// built from source, with `cargo run` in the repository.
+///
+/// Returns the PID of the new process once it has reported readiness; the caller is expected to
+/// exit afterwards. If it doesn't become ready in time, it is stopped and the update is rolled
+/// back, so the caller can keep running the previous version. This blocks for as long as that
+/// takes, so async callers have to keep it off the runtime's workers.
+pub fn restart_cli_process_with_new_version(
+    update: &PreparedUpdate,
+    config: &UpdaterConfig,
+) -> Result<u32, Box<dyn std::error::Error>> {
+    let mut command = match &update.executable {
+        Some(executable) => {
+            let mut command = Command::new(executable);
+            config.launch.apply(&mut command);
+            command
+        }
+        None => {
+            let mut command = Command::new("cargo");
+            command
+                .args(["run", "--release", "--bin", "prover", "--manifest-path"])
+                .arg(config.cli_manifest_path())
+                .arg("--");
+            config.launch.apply(&mut command);
+            command
+        }
+    };
+
+    fs::create_dir_all(&config.updater_dir)?;
+    let readiness_file = config.updater_dir.join(format!("ready-{}", update.version));
+    let _ = fs::remove_file(&readiness_file);
+    command
+        .env(rollback::READINESS_FILE_ENV, &readiness_file)
+        .env(
+            crate::state::LOCK_HANDOVER_ENV,
+            std::process::id().to_string(),
+        )
+        .process_group(0);
+
+    println!(
+        "{}[auto-updater]{} Restarting with new version {}...",
+        BLUE, RESET, update.version
+    );
+    let started = spawn_verified(&mut command, RESTART_STARTUP_CHECK)
+        .map_err(|e| e.to_string())
+        .and_then(|mut child| {
+            println!(
+                "{}[auto-updater]{} Started new process with PID {}, waiting until it is ready...",
+                BLUE,
+                RESET,
+                child.id()
+            );
+            match rollback::wait_for_readiness(
+                &mut child,
+                &readiness_file,
+                config.readiness_timeout,
+            ) {
+                Ok(()) => Ok(child),
+                Err(reason) => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    Err(reason)
+                }
+            }
+        });
+    let _ = fs::remove_file(&readiness_file);
+
+    match started {
+        Ok(child) => {
+            // Write the new PID to a file
+            fs::write(&config.pid_file, child.id().to_string())?;
+            rollback::log_update(
+                &config.updater_dir,
+                &format!(
+                    "{} is ready (PID {}), handing over",
+                    update.version,
+                    child.id()
+                ),
+            );
+            Ok(child.id())
+        }
+        Err(reason) => {
+            roll_back_update(update, config, &reason);
+            Err(format!(
+                "Rolled back version {} to {}: {}",
+                update.version, update.previous_version, reason
+            )
+            .into())
+        }
+    }
+}
+
+/// Check out the release tag of a version
+fn checkout_version(repo_path: &Path, version: &Version) -> Result<(), Box<dyn std::error::Error>> {
+    let checkout_output = Command::new("git")
+        .args(["checkout", &format!("tags/{}", version)])
+        .current_dir(repo_path)
+        .output()?;
+
+    if !checkout_output.status.success() {
+        return Err(format!(
+            "Failed to checkout version: {}",
+            String::from_utf8_lossy(&checkout_output.stderr)
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Put the previous version back and make sure the failed one is not installed again
+fn roll_back_update(update: &PreparedUpdate, config: &UpdaterConfig, reason: &str) {
+    // Updates built from source never replaced the running executable
+    if let (Some(_), Some(backup)) = (&update.executable, &update.backup) {
+        if let Err(e) = rollback::restore_executable(backup, &config.launch.executable) {
+            eprintln!(
+                "{}[auto-updater]{} Failed to restore the previous executable: {}",
+                BLUE, RESET, e
+            );
+        }
+    }
+    // but later restarts run whatever the checkout they were built in holds (the test mode
+    // builds the working tree instead of a checkout)
+    if update.executable.is_none() && config.mode == AutoUpdaterMode::Production {
+        let repo_path = Path::new(&config.repo_path);
+        if let Err(e) = checkout_version(repo_path, &update.previous_version) {
+            eprintln!(
+                "{}[auto-updater]{} Failed to check out the previous version: {}",
+                BLUE, RESET, e
+            );
+        }
+    }
+    if let Err(e) = write_version_to_file(&config.version_file, &update.previous_version) {
+        eprintln!(
+            "{}[auto-updater]{} Failed to restore the version file: {}",
+            BLUE, RESET, e
+        );
+    }
+    if let Err(e) = rollback::skip_version(&config.updater_dir, &update.version) {
+        eprintln!(
+            "{}[auto-updater]{} Failed to skip version {}: {}",
+            BLUE, RESET, update.version, e
+        );
+    }
+    rollback::log_update(
+        &config.updater_dir,
+        &format!(
+            "rolled back {} to {}: {}",
+            update.version, update.previous_version, reason
+        ),
+    );
+}
+
+/// Spawn a command and make sure it is still running after `startup_check`
+pub fn spawn_verified(
+    command: &mut Command,
+    startup_check: Duration,
+) -> Result<Child, Box<dyn std::error::Error>> {
+    let mut child = command.spawn()?;
+    let deadline = Instant::now() + startup_check;
+    while Instant::now() < deadline {
+        if let Some(status) = child.try_wait()? {
+            return Err(format!("The new process exited right after starting ({})", status).into());
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    Ok(child)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that a process that keeps running passes the startup check
+    #[test]
+    fn test_spawn_verified_accepts_running_process() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "sleep 5"]);
+        let mut child = spawn_verified(&mut command, Duration::from_millis(300))
+            .expect("Process should still be running");
+        child.kill().unwrap();
+        let _ = child.wait();
+    }
+
+    /// Tests that a process that exits immediately fails the startup check
+    #[test]
+    fn test_spawn_verified_rejects_crashing_process() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "exit 3"]);
+        assert!(spawn_verified(&mut command, Duration::from_millis(300)).is_err());
+    }
+
+    /// Tests that a requested update check ends the wait for the next scheduled one
+    #[test]
+    fn test_request_check() {
+        let coordinator = Arc::new(UpdateCoordinator::default());
+        assert!(!coordinator.wait_for_check(Duration::from_millis(10)));
+
+        let requester = coordinator.clone();
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            requester.request_check();
+        });
+        let started = Instant::now();
+        assert!(coordinator.wait_for_check(Duration::from_secs(60)));
+        assert!(started.elapsed() < Duration::from_secs(60));
+        handle.join().unwrap();
+
+        // The request is used up
+        assert!(!coordinator.wait_for_check(Duration::from_millis(10)));
+    }
+
+    fn test_config(temp_dir: &std::path::Path) -> UpdaterConfig {
+        let mut config = UpdaterConfig::new(
+            AutoUpdaterMode::Test,
+            LaunchSpec {
+                executable: temp_dir.join("prover"),
+                args: Vec::new(),
+                env: vec![("PATH".into(), std::env::var_os("PATH").unwrap_or_default())],
+                current_dir: temp_dir.to_path_buf(),
+            },
+        );
+        config.updater_dir = temp_dir.join("updater");
+        config.version_file = temp_dir.join(VERSION_FILE);
+        config.pid_file = temp_dir.join("prover.pid");
+        config.readiness_timeout = Duration::from_secs(2);
+        config
+    }
+
+    fn fake_update(temp_dir: &std::path::Path, script: &str) -> PreparedUpdate {
+        use std::os::unix::fs::PermissionsExt;
+
+        let executable = temp_dir.join("prover");
+        fs::write(&executable, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        fs::set_permissions(&executable, fs::Permissions::from_mode(0o755)).unwrap();
+        PreparedUpdate {
+            version: Version::new(0, 9, 9),
+            executable: Some(executable),
+            previous_version: Version::new(0, 3, 6),
+            // Restoring a backup would replace the test binary itself
+            backup: None,
+        }
+    }
+
+    /// Tests that the old process hands over to a new version that reports readiness
+    #[test]
+    fn test_restart_hands_over_to_ready_version() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let config = test_config(temp_dir.path());
+        let update = fake_update(
+            temp_dir.path(),
+            "echo $$ > \"$NEXUS_UPDATE_READINESS_FILE\"; sleep 10",
+        );
+
+        let pid = restart_cli_process_with_new_version(&update, &config)
+            .expect("New version should be ready");
+        let _ = Command::new("kill").arg(pid.to_string()).status();
+
+        let log = fs::read_to_string(config.updater_dir.join("updater.log")).unwrap();
+        assert!(log.contains("0.9.9 is ready"));
+        assert!(rollback::load_skipped_versions(&config.updater_dir).is_empty());
+    }
+
+    /// Tests that a version that never becomes ready is rolled back and skipped
+    #[test]
+    fn test_restart_rolls_back_unready_version() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let config = test_config(temp_dir.path());
+        let update = fake_update(temp_dir.path(), "sleep 10");
+
+        assert!(restart_cli_process_with_new_version(&update, &config).is_err());
+
+        assert!(rollback::load_skipped_versions(&config.updater_dir).contains(&update.version));
+        let version = fs::read_to_string(&config.version_file).unwrap();
+        assert_eq!(version, "0.3.6");
+        let log = fs::read_to_string(config.updater_dir.join("updater.log")).unwrap();
+        assert!(log.contains("rolled back 0.9.9 to 0.3.6"));
+    }
+
+    fn version_manager(config: UpdaterConfig, current_version: Version) -> VersionManager {
+        VersionManager {
+            current_version: Arc::new(RwLock::new(current_version)),
+            config,
+        }
+    }
+
+    /// Tests a full update against a local repository: a new version tag is picked up, and the
+    /// running CLI hands over to a new process running the new version
+    #[test]
+    fn test_update_from_local_repository() {
+        let repo = source::tests::TestRepo::new();
+        repo.tag("0.3.5");
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let mut config = test_config(temp_dir.path());
+        config.version_source = Arc::new(LocalGitRepo {
+            path: repo.path().to_path_buf(),
+        });
+        let manager = version_manager(config.clone(), Version::new(0, 3, 5));
+
+        assert_eq!(
+            manager.update_version_status().unwrap(),
+            VersionStatus::UpToDate
+        );
+
+        // A new version is released
+        repo.commit("Update");
+        repo.tag("0.9.9");
+        assert_eq!(
+            manager.update_version_status().unwrap(),
+            VersionStatus::UpdateAvailable(Version::new(0, 9, 9))
+        );
+
+        // Building from source is replaced with a script standing in for the new version
+        let update = fake_update(
+            temp_dir.path(),
+            "echo $$ > \"$NEXUS_UPDATE_READINESS_FILE\"; sleep 10",
+        );
+        let pid = restart_cli_process_with_new_version(&update, &config)
+            .expect("New version should be ready");
+        let pid_file = fs::read_to_string(&config.pid_file).unwrap();
+        let _ = Command::new("kill").arg(pid.to_string()).status();
+
+        assert_eq!(pid_file, pid.to_string());
+        assert_ne!(pid, std::process::id());
+    }
+
+    /// Tests a full update from a signed release binary: it replaces the installed executable, and
+    /// the CLI restarts into it through the path `prepare_update` returns
+    #[test]
+    fn test_update_from_release_binary() {
+        use base64::prelude::{Engine, BASE64_STANDARD};
+        use ed25519_dalek::{Signer, SigningKey};
+        use sha2::{Digest, Sha256};
+
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let mut config = test_config(temp_dir.path());
+        fs::write(&config.launch.executable, "#!/bin/sh\nexit 1\n").unwrap();
+
+        // Publish 0.9.9 as a signed release
+        let releases = temp_dir.path().join("releases");
+        fs::create_dir_all(&releases).unwrap();
+        let binary = "#!/bin/sh\necho $$ > \"$NEXUS_UPDATE_READINESS_FILE\"\nsleep 10\n";
+        let artifact = releases.join("prover-0.9.9");
+        fs::write(&artifact, binary).unwrap();
+        let signing_key = SigningKey::from_bytes(&[7u8; 32]);
+        let signature = BASE64_STANDARD.encode(signing_key.sign(binary.as_bytes()).to_bytes());
+        let manifest = serde_json::json!({
+            "version": "0.9.9",
+            "artifacts": {
+                release::TARGET: {
+                    "url": format!("file://{}", artifact.display()),
+                    "sha256": hex::encode(Sha256::digest(binary)),
+                    "signature": signature,
+                }
+            }
+        });
+        fs::write(releases.join("0.9.9.json"), manifest.to_string()).unwrap();
+        config.release_manifest_url =
+            Some(format!("file://{}/{{version}}.json", releases.display()));
+        config.release_public_key =
+            Some(BASE64_STANDARD.encode(signing_key.verifying_key().to_bytes()));
+
+        let manager = version_manager(config.clone(), Version::new(0, 3, 5));
+        let update = manager
+            .prepare_update(&Version::new(0, 9, 9))
+            .expect("Release should install");
+        assert_eq!(update.executable.as_ref(), Some(&config.launch.executable));
+        assert_eq!(
+            fs::read_to_string(&config.launch.executable).unwrap(),
+            binary
+        );
+
+        let pid = restart_cli_process_with_new_version(&update, &config)
+            .expect("New version should be ready");
+        let _ = Command::new("kill").arg(pid.to_string()).status();
+        let log = fs::read_to_string(config.updater_dir.join("updater.log")).unwrap();
+        assert!(log.contains("installed 0.9.9 (previously 0.3.5) from a release binary"));
+    }
+
+    /// Release a version of a crate laid out like the CLI in the repository, which records which
+    /// version runs and then reports readiness, or exits right away if it is broken
+    fn release_from_source(repo: &source::tests::TestRepo, version: &str, ready: bool) {
+        let crate_dir = repo.path().join(CLI_CRATE_DIR);
+        fs::create_dir_all(crate_dir.join("src")).unwrap();
+        fs::write(
+            crate_dir.join("Cargo.toml"),
+            "[package]\nname = \"nexus-network\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n\
+             [[bin]]\nname = \"prover\"\npath = \"src/prover.rs\"\n\n[workspace]\n",
+        )
+        .unwrap();
+        let main = if ready {
+            format!(
+                "fn main() {{\n    std::fs::write(\"running-version\", \"{}\").unwrap();\n    \
+                 std::fs::write(std::env::var(\"{}\").unwrap(), \"\").unwrap();\n    \
+                 std::thread::sleep(std::time::Duration::from_secs(10));\n}}\n",
+                version,
+                rollback::READINESS_FILE_ENV
+            )
+        } else {
+            String::from("fn main() {}\n")
+        };
+        fs::write(crate_dir.join("src").join("prover.rs"), main).unwrap();
+        repo.git(&["add", "--all"]);
+        repo.commit(version);
+    }
+
+    /// Build updates from source in a checkout of `repo`
+    fn source_update_config(temp_dir: &Path, repo: &source::tests::TestRepo) -> UpdaterConfig {
+        let checkout = temp_dir.join("network-api");
+        fs::create_dir_all(&checkout).unwrap();
+        let mut config = test_config(temp_dir);
+        config.mode = AutoUpdaterMode::Production;
+        config.remote_repo = repo.path().to_string_lossy().into_owned();
+        config.repo_path = checkout.to_string_lossy().into_owned();
+        config.version_source = Arc::new(GitRemote {
+            url: config.remote_repo.clone(),
+        });
+        // cargo needs the whole environment, and time to build
+        config.launch.env = std::env::vars_os().collect();
+        config.readiness_timeout = Duration::from_secs(60);
+        config
+    }
+
+    /// Tests a full update built from source: the release tag is cloned from a repository, checked
+    /// out and built with cargo, and the CLI hands over to the built program
+    #[test]
+    fn test_update_built_from_source() {
+        let repo = source::tests::TestRepo::new();
+        release_from_source(&repo, "0.3.5", true);
+        repo.tag("0.3.5");
+        release_from_source(&repo, "0.9.9", true);
+        repo.tag("0.9.9");
+        // Work on the next version must not be installed
+        release_from_source(&repo, "unreleased", true);
+
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let config = source_update_config(temp_dir.path(), &repo);
+        let manager = version_manager(config.clone(), Version::new(0, 3, 5));
+
+        let VersionStatus::UpdateAvailable(version) = manager.update_version_status().unwrap()
+        else {
+            panic!("0.9.9 should be available");
+        };
+        let update = manager
+            .prepare_update(&version)
+            .expect("Update should build");
+        assert_eq!(update.executable, None);
+        let pid = restart_cli_process_with_new_version(&update, &config)
+            .expect("New version should be ready");
+        // Stop cargo along with the program it runs
+        let _ = Command::new("kill")
+            .args(["--", &format!("-{}", pid)])
+            .status();
+
+        // The new version runs in the working directory the CLI was started in
+        let running_version = fs::read_to_string(temp_dir.path().join("running-version")).unwrap();
+        assert_eq!(running_version, "0.9.9");
+        assert_eq!(fs::read_to_string(&config.version_file).unwrap(), "0.9.9");
+        let log = fs::read_to_string(config.updater_dir.join("updater.log")).unwrap();
+        assert!(log.contains("installed 0.9.9 (previously 0.3.5) from source"));
+    }
+
+    /// Tests that rolling back an update built from source checks the previous version out again,
+    /// so later restarts don't build the broken one
+    #[test]
+    fn test_roll_back_update_built_from_source() {
+        let repo = source::tests::TestRepo::new();
+        release_from_source(&repo, "0.3.5", true);
+        repo.tag("0.3.5");
+        release_from_source(&repo, "0.9.9", false);
+        repo.tag("0.9.9");
+
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let config = source_update_config(temp_dir.path(), &repo);
+        let manager = version_manager(config.clone(), Version::new(0, 3, 5));
+        let update = manager
+            .prepare_update(&Version::new(0, 9, 9))
+            .expect("Update should build");
+        assert!(restart_cli_process_with_new_version(&update, &config).is_err());
+
+        let commit_of = |revision: &str| {
+            let output = Command::new("git")
+                .args(["rev-parse", revision])
+                .current_dir(&config.repo_path)
+                .output()
+                .unwrap();
+            String::from_utf8(output.stdout).unwrap()
+        };
+        assert_eq!(commit_of("HEAD"), commit_of("0.3.5^{commit}"));
+        assert!(rollback::load_skipped_versions(&config.updater_dir).contains(&update.version));
+    }
+
+    /// Tests that versions come from the configured source and respect the minimum release age
+    #[test]
+    fn test_update_from_fixed_versions() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let now = chrono::Utc::now();
+        let mut config = test_config(temp_dir.path());
+        config.version_source = Arc::new(source::FixedVersions {
+            releases: vec![
+                (Version::new(0, 3, 6), Some(now - chrono::Duration::days(7))),
+                (
+                    Version::new(0, 4, 0),
+                    Some(now - chrono::Duration::hours(1)),
+                ),
+            ],
+        });
+        config.policy.min_release_age = Duration::from_secs(24 * 3600);
+
+        let manager = version_manager(config, Version::new(0, 3, 5));
+        assert_eq!(
+            manager.update_version_status().unwrap(),
+            VersionStatus::UpdateAvailable(Version::new(0, 3, 6))
+        );
+    }
+
+    /// Tests that a restarted process gets the original arguments and environment
+    #[test]
+    fn test_launch_spec_is_applied() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let output_file = temp_dir.path().join("launch.txt");
+        let launch = LaunchSpec {
+            executable: PathBuf::from("sh"),
+            args: vec![
+                "-c".into(),
+                format!(
+                    "echo \"$0 $1 $NEXUS_TEST_VAR $(pwd)\" > {}",
+                    output_file.display()
+                )
+                .into(),
+                "--run-id".into(),
+                "7".into(),
+            ],
+            env: vec![
+                ("NEXUS_TEST_VAR".into(), "kept".into()),
+                ("PATH".into(), std::env::var_os("PATH").unwrap_or_default()),
+            ],
+            current_dir: temp_dir.path().to_path_buf(),
+        };
+
+        let mut command = Command::new("sh");
+        launch.apply(&mut command);
+        assert!(command.status().unwrap().success());
+
+        let output = std::fs::read_to_string(&output_file).unwrap();
+        let expected_dir = temp_dir.path().canonicalize().unwrap();
+        assert_eq!(
+            output.trim(),
+            format!("--run-id 7 kept {}", expected_dir.display())
+        );
+    }
+}
@@ -0,0 +1,170 @@
+//! Update policies
+//!
+//! By default every node installs the highest stable release as soon as it is tagged. To stage a
+//! rollout across a fleet, the `updates` section of the configuration file narrows that down:
+//! - `channel`: `stable` ignores prereleases such as 0.4.0-rc.1, `prerelease` includes them
+//! - `pin`: a semver requirement the version must match, e.g. `=0.4.0` or `~0.4`
+//! - `min_release_age_hours`: only install releases that have been out for at least this long
+//! - `notify_only`: report available updates without installing them
+//!
+//! Following semver, a pin only matches prereleases of the exact version it names (`=0.4.0-rc.1`
+//! matches on either channel). On the prerelease channel, a pin also lets through prereleases of
+//! the versions it matches, so `~0.4` there includes 0.4.1-rc.1.
+
+use chrono::{DateTime, Utc};
+use semver::{Version, VersionReq};
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use crate::config::{UpdateChannel, UpdatesConfig};
+
+#[derive(Debug, Clone, Default)]
+pub struct UpdatePolicy {
+    pub channel: UpdateChannel,
+    pub pin: Option<VersionReq>,
+    pub min_release_age: Duration,
+    pub notify_only: bool,
+}
+
+impl UpdatePolicy {
+    pub fn from_config(config: &UpdatesConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let pin = config
+            .pin
+            .as_deref()
+            .map(VersionReq::parse)
+            .transpose()
+            .map_err(|e| format!("Invalid version pin: {}", e))?;
+        Ok(Self {
+            channel: config.channel,
+            pin,
+            min_release_age: Duration::from_secs(config.min_release_age_hours * 3600),
+            notify_only: config.notify_only,
+        })
+    }
+
+    /// Whether the channel and the pin allow running this version
+    pub fn allows(&self, version: &Version) -> bool {
+        let matches_pin =
+            |version: &Version| self.pin.as_ref().is_none_or(|pin| pin.matches(version));
+        if version.pre.is_empty() {
+            return matches_pin(version);
+        }
+        match self.channel {
+            UpdateChannel::Stable => self.pin.is_some() && matches_pin(version),
+            UpdateChannel::Prerelease => {
+                matches_pin(version)
+                    || matches_pin(&Version::new(version.major, version.minor, version.patch))
+            }
+        }
+    }
+
+    /// The versions the policy allows, highest first, leaving out versions that were rolled back
+    pub fn candidates(
+        &self,
+        versions: impl IntoIterator<Item = Version>,
+        skipped: &BTreeSet<Version>,
+    ) -> Vec<Version> {
+        let mut candidates: Vec<Version> = versions
+            .into_iter()
+            .filter(|version| self.allows(version) && !skipped.contains(version))
+            .collect();
+        candidates.sort_unstable_by(|a, b| b.cmp(a));
+        candidates.dedup();
+        candidates
+    }
+
+    /// Whether a release published at `published` has been out long enough to install. Releases
+    /// of unknown age are only installed without a minimum age.
+    pub fn is_old_enough(&self, published: Option<DateTime<Utc>>, now: DateTime<Utc>) -> bool {
+        if self.min_release_age.is_zero() {
+            return true;
+        }
+        published.is_some_and(|published| {
+            (now - published)
+                .to_std()
+                .is_ok_and(|age| age >= self.min_release_age)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn versions(versions: &[&str]) -> Vec<Version> {
+        versions
+            .iter()
+            .map(|v| Version::parse(v).unwrap())
+            .collect()
+    }
+
+    fn policy(channel: UpdateChannel, pin: Option<&str>) -> UpdatePolicy {
+        UpdatePolicy {
+            channel,
+            pin: pin.map(|pin| VersionReq::parse(pin).unwrap()),
+            ..UpdatePolicy::default()
+        }
+    }
+
+    /// Tests that channels and pins pick the right version out of the released ones
+    #[test]
+    fn test_candidates_follow_channel_and_pin() {
+        let released = versions(&["0.3.6", "0.4.0", "0.4.1-rc.1", "0.5.0-rc.1", "0.4.0"]);
+        let highest = |policy: &UpdatePolicy| {
+            policy
+                .candidates(released.clone(), &BTreeSet::new())
+                .first()
+                .map(|v| v.to_string())
+        };
+
+        assert_eq!(
+            highest(&policy(UpdateChannel::Stable, None)).unwrap(),
+            "0.4.0"
+        );
+        assert_eq!(
+            highest(&policy(UpdateChannel::Prerelease, None)).unwrap(),
+            "0.5.0-rc.1"
+        );
+        assert_eq!(
+            highest(&policy(UpdateChannel::Stable, Some("=0.3.6"))).unwrap(),
+            "0.3.6"
+        );
+        assert_eq!(
+            highest(&policy(UpdateChannel::Stable, Some("~0.4"))).unwrap(),
+            "0.4.0"
+        );
+        assert_eq!(
+            highest(&policy(UpdateChannel::Prerelease, Some("~0.4"))).unwrap(),
+            "0.4.1-rc.1"
+        );
+        assert_eq!(
+            highest(&policy(UpdateChannel::Stable, Some("=0.5.0-rc.1"))).unwrap(),
+            "0.5.0-rc.1"
+        );
+        assert_eq!(highest(&policy(UpdateChannel::Stable, Some("^1"))), None);
+    }
+
+    /// Tests that rolled back versions are never candidates
+    #[test]
+    fn test_candidates_leave_out_skipped_versions() {
+        let skipped = BTreeSet::from([Version::new(0, 4, 0)]);
+        let candidates =
+            UpdatePolicy::default().candidates(versions(&["0.3.6", "0.4.0"]), &skipped);
+        assert_eq!(candidates, versions(&["0.3.6"]));
+    }
+
+    /// Tests that releases are held back until they reach the minimum age
+    #[test]
+    fn test_minimum_release_age() {
+        let now = Utc::now();
+        let policy = UpdatePolicy {
+            min_release_age: Duration::from_secs(24 * 3600),
+            ..UpdatePolicy::default()
+        };
+
+        assert!(policy.is_old_enough(Some(now - chrono::Duration::hours(25)), now));
+        assert!(!policy.is_old_enough(Some(now - chrono::Duration::hours(23)), now));
+        assert!(!policy.is_old_enough(None, now));
+        assert!(UpdatePolicy::default().is_old_enough(None, now));
+    }
+}
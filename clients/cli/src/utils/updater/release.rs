@@ -0,0 +1,291 @@
+//! Binary release updates
+//!
+//! Building every new version from source takes a long time and needs a full Rust toolchain on
+//! every node. Instead, the updater downloads a prebuilt binary for the current target, as
+//! described by a release manifest:
+//!
+//! ```json
+//! {
+//!   "version": "0.4.0",
+//!   "published_at": "2024-12-02T17:00:00Z",
+//!   "artifacts": {
+//!     "x86_64-unknown-linux-gnu": {
+//!       "url": "https://.../prover-x86_64-unknown-linux-gnu",
+//!       "sha256": "<hex digest of the binary>",
+//!       "signature": "<base64 Ed25519 signature of the binary>"
+//!     }
+//!   }
+//! }
+//! ```
+//!
+//! An artifact only replaces the running executable after both its checksum and its signature
+//! (made with the release key) have been verified. The swap is atomic, so an interrupted update
+//! never leaves a half-written executable behind.
+//!
+//! Every release has its own manifest; the manifest URL of the updater config is a template in
+//! which `{version}` is replaced with the version to install.
+
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use semver::Version;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use super::{BLUE, RESET};
+
+/// The target triple this binary was built for, e.g. `x86_64-unknown-linux-gnu`
+pub const TARGET: &str = env!("TARGET");
+
+/// The base64 Ed25519 public key release artifacts are signed with. The release workflow signs
+/// them with the matching private key, kept in the `RELEASE_SIGNING_KEY` secret.
+pub const RELEASE_PUBLIC_KEY: &str = "xZFg1EUSjvNHUkhVTa+6Wd0H2GXhEYbtaxijooepkfY=";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReleaseManifest {
+    pub version: Version,
+    /// When the release was published, used for the minimum release age of update policies
+    #[serde(default)]
+    pub published_at: Option<DateTime<Utc>>,
+    /// Artifacts by target triple
+    pub artifacts: BTreeMap<String, ReleaseArtifact>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReleaseArtifact {
+    pub url: String,
+    /// Hex-encoded SHA-256 digest of the binary
+    pub sha256: String,
+    /// Base64-encoded Ed25519 signature of the binary
+    pub signature: String,
+}
+
+impl ReleaseManifest {
+    pub fn artifact_for_target(
+        &self,
+        target: &str,
+    ) -> Result<&ReleaseArtifact, Box<dyn std::error::Error>> {
+        self.artifacts.get(target).ok_or_else(|| {
+            format!(
+                "Release {} has no binary for target {}",
+                self.version, target
+            )
+            .into()
+        })
+    }
+}
+
+/// The manifest URL of a release, from a template containing `{version}`
+pub fn manifest_url(template: &str, version: &Version) -> String {
+    template.replace("{version}", &version.to_string())
+}
+
+/// Read a URL, or a local file for `file://` URLs (used to stage releases without a server)
+pub fn fetch(url: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if let Some(path) = url.strip_prefix("file://") {
+        return Ok(std::fs::read(path)?);
+    }
+
+    let response = crate::proxy::blocking_http_client()?
+        .get(url)
+        .send()?
+        .error_for_status()?;
+    Ok(response.bytes()?.to_vec())
+}
+
+pub fn fetch_manifest(url: &str) -> Result<ReleaseManifest, Box<dyn std::error::Error>> {
+    Ok(serde_json::from_slice(&fetch(url)?)?)
+}
+
+/// Check a downloaded binary against the checksum and signature of its artifact
+pub fn verify_artifact(
+    binary: &[u8],
+    artifact: &ReleaseArtifact,
+    public_key: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let digest = hex::encode(Sha256::digest(binary));
+    if !digest.eq_ignore_ascii_case(artifact.sha256.trim()) {
+        return Err(format!(
+            "Checksum mismatch: expected {}, got {}",
+            artifact.sha256, digest
+        )
+        .into());
+    }
+
+    let public_key: [u8; 32] = BASE64_STANDARD
+        .decode(public_key.trim())?
+        .try_into()
+        .map_err(|_| "The release public key must be 32 bytes")?;
+    let signature: [u8; 64] = BASE64_STANDARD
+        .decode(artifact.signature.trim())?
+        .try_into()
+        .map_err(|_| "The artifact signature must be 64 bytes")?;
+    VerifyingKey::from_bytes(&public_key)?
+        .verify(binary, &Signature::from_bytes(&signature))
+        .map_err(|_| "Invalid signature: the binary was not signed with the release key")?;
+    Ok(())
+}
+
+/// Atomically replace an executable (possibly the running one) with a new binary. The binary is
+/// written next to it first, so it can be renamed into place.
+pub fn replace_executable(
+    executable: &Path,
+    binary: &[u8],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let directory = executable
+        .parent()
+        .ok_or_else(|| format!("{} is not in a directory", executable.display()))?;
+    let temp_dir = self_update::TempDir::new_in(directory)?;
+    let new_executable = temp_dir.path().join("prover");
+    std::fs::write(&new_executable, binary)?;
+    make_executable(&new_executable)?;
+
+    std::fs::rename(&new_executable, executable)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn make_executable(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn make_executable(_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    Ok(())
+}
+
+/// Download, verify and install the binary of a release for the current target in place of
+/// `executable`
+pub fn install_release(
+    manifest_url_template: &str,
+    expected_version: &Version,
+    public_key: &str,
+    executable: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let manifest_url = manifest_url(manifest_url_template, expected_version);
+    println!(
+        "{}[auto-updater]{} Fetching release manifest from {}...",
+        BLUE, RESET, manifest_url
+    );
+    let manifest = fetch_manifest(&manifest_url)?;
+    if &manifest.version != expected_version {
+        return Err(format!(
+            "The release manifest describes version {}, expected {}",
+            manifest.version, expected_version
+        )
+        .into());
+    }
+
+    let artifact = manifest.artifact_for_target(TARGET)?;
+    println!(
+        "{}[auto-updater]{} Downloading {} for {}...",
+        BLUE, RESET, manifest.version, TARGET
+    );
+    let binary = fetch(&artifact.url)?;
+    verify_artifact(&binary, artifact, public_key)?;
+
+    println!(
+        "{}[auto-updater]{} Verified the release binary, installing it...",
+        BLUE, RESET
+    );
+    replace_executable(executable, &binary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    fn signed_artifact(binary: &[u8]) -> (ReleaseArtifact, String) {
+        let signing_key = SigningKey::from_bytes(&[7u8; 32]);
+        let artifact = ReleaseArtifact {
+            url: "file:///tmp/prover".into(),
+            sha256: hex::encode(Sha256::digest(binary)),
+            signature: BASE64_STANDARD.encode(signing_key.sign(binary).to_bytes()),
+        };
+        let public_key = BASE64_STANDARD.encode(signing_key.verifying_key().to_bytes());
+        (artifact, public_key)
+    }
+
+    /// Tests that a binary matching its checksum and signature is accepted
+    #[test]
+    fn test_verify_artifact_accepts_signed_binary() {
+        let binary = b"new prover binary";
+        let (artifact, public_key) = signed_artifact(binary);
+        verify_artifact(binary, &artifact, &public_key).expect("Binary should verify");
+    }
+
+    /// Tests that tampered binaries and foreign signatures are rejected
+    #[test]
+    fn test_verify_artifact_rejects_tampering() {
+        let binary = b"new prover binary";
+        let (artifact, public_key) = signed_artifact(binary);
+        assert!(verify_artifact(b"tampered binary", &artifact, &public_key).is_err());
+
+        // Matching checksum, but signed with another key
+        let other_key = SigningKey::from_bytes(&[8u8; 32]);
+        let forged = ReleaseArtifact {
+            signature: BASE64_STANDARD.encode(other_key.sign(binary).to_bytes()),
+            ..artifact
+        };
+        assert!(verify_artifact(binary, &forged, &public_key).is_err());
+    }
+
+    /// Tests that signatures made by the release workflow (`openssl pkeyutl -sign -rawin`) verify
+    /// against the release public key
+    #[test]
+    fn test_verify_artifact_signed_by_release_workflow() {
+        let binary = b"hello binary";
+        let artifact = ReleaseArtifact {
+            url: "file:///tmp/prover".into(),
+            sha256: hex::encode(Sha256::digest(binary)),
+            signature: "WQ2LuJoKh2FJwtekgqlUguIhV8h7I2qaYHxJ5/ed/S/tcailmcfkQ0+calecfqIgigoOJLRFW5Oj1+4RUYweCA=="
+                .into(),
+        };
+        verify_artifact(binary, &artifact, RELEASE_PUBLIC_KEY).expect("Binary should verify");
+    }
+
+    /// Tests that the manifest is parsed and artifacts are looked up by target
+    #[test]
+    fn test_manifest_artifact_for_target() {
+        let manifest: ReleaseManifest = serde_json::from_str(
+            r#"{
+                "version": "0.4.0",
+                "artifacts": {
+                    "x86_64-unknown-linux-gnu": {
+                        "url": "https://example.com/prover",
+                        "sha256": "00",
+                        "signature": "AA=="
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(manifest.version, Version::new(0, 4, 0));
+        assert_eq!(manifest.published_at, None);
+        assert!(manifest
+            .artifact_for_target("x86_64-unknown-linux-gnu")
+            .is_ok());
+        assert!(manifest
+            .artifact_for_target("riscv64gc-unknown-none")
+            .is_err());
+    }
+
+    /// Tests that manifest URL templates are filled in with the version
+    #[test]
+    fn test_manifest_url() {
+        assert_eq!(
+            manifest_url(
+                "https://example.com/releases/download/{version}/release-manifest.json",
+                &Version::parse("0.4.1-rc.1").unwrap()
+            ),
+            "https://example.com/releases/download/0.4.1-rc.1/release-manifest.json"
+        );
+    }
+}
@@ -0,0 +1,121 @@
+//! Health checks and rollback for updates
+//!
+//! An update is only kept once the new process proves it works: it is started with the path of
+//! a readiness file in its environment, and writes that file once it is connected to the
+//! orchestrator and has proven its first step. If that doesn't happen before a deadline, the old
+//! process stops the new one, puts the previous build back and keeps proving, and the version is
+//! added to a skip list so it isn't installed again. Every outcome is appended to the updater log.
+
+use semver::Version;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Child;
+use std::time::{Duration, Instant};
+
+/// Set by the updater for the new process: where to report readiness
+pub const READINESS_FILE_ENV: &str = "NEXUS_UPDATE_READINESS_FILE";
+
+const BACKUP_EXECUTABLE: &str = "prover.previous";
+const SKIPPED_VERSIONS_FILE: &str = "skipped-versions.json";
+const UPDATER_LOG_FILE: &str = "updater.log";
+
+/// Where the updater keeps its state, e.g. ~/.local/state/nexus/updater
+pub fn default_updater_dir() -> PathBuf {
+    crate::state::current()
+        .map(|dirs| dirs.state)
+        .unwrap_or_else(|| PathBuf::from(".nexus"))
+        .join("updater")
+}
+
+/// Report that this process is connected and proving, if it was started by an update
+pub fn report_ready() {
+    if let Some(path) = std::env::var_os(READINESS_FILE_ENV) {
+        if let Err(e) = fs::write(&path, std::process::id().to_string()) {
+            eprintln!("Failed to report readiness to the previous version: {}", e);
+        }
+    }
+}
+
+/// Wait until the new process reports readiness. Fails if it exits or misses the deadline.
+pub fn wait_for_readiness(
+    child: &mut Child,
+    readiness_file: &Path,
+    timeout: Duration,
+) -> Result<(), String> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if readiness_file.exists() {
+            return Ok(());
+        }
+        match child.try_wait() {
+            Ok(Some(status)) => return Err(format!("the new version exited ({})", status)),
+            Ok(None) => {}
+            Err(e) => return Err(format!("could not check the new version: {}", e)),
+        }
+        if Instant::now() >= deadline {
+            return Err(format!(
+                "the new version was not ready within {} seconds",
+                timeout.as_secs()
+            ));
+        }
+        std::thread::sleep(Duration::from_millis(200));
+    }
+}
+
+/// Keep a copy of the running executable so an update can be undone
+pub fn backup_executable(
+    executable: &Path,
+    updater_dir: &Path,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    fs::create_dir_all(updater_dir)?;
+    let backup = updater_dir.join(BACKUP_EXECUTABLE);
+    fs::copy(executable, &backup)?;
+    Ok(backup)
+}
+
+/// Put the backed up executable back in place of the installed one
+pub fn restore_executable(
+    backup: &Path,
+    executable: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    super::release::replace_executable(executable, &fs::read(backup)?)
+}
+
+pub fn load_skipped_versions(updater_dir: &Path) -> BTreeSet<Version> {
+    fs::read_to_string(updater_dir.join(SKIPPED_VERSIONS_FILE))
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+/// Never install this version again
+pub fn skip_version(
+    updater_dir: &Path,
+    version: &Version,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut skipped = load_skipped_versions(updater_dir);
+    skipped.insert(version.clone());
+    fs::create_dir_all(updater_dir)?;
+    fs::write(
+        updater_dir.join(SKIPPED_VERSIONS_FILE),
+        serde_json::to_string_pretty(&skipped)?,
+    )?;
+    Ok(())
+}
+
+/// Append a line to the updater log
+pub fn log_update(updater_dir: &Path, message: &str) {
+    let line = format!("{} {}\n", chrono::Local::now().to_rfc3339(), message);
+    let result = fs::create_dir_all(updater_dir).and_then(|_| {
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(updater_dir.join(UPDATER_LOG_FILE))?
+            .write_all(line.as_bytes())
+    });
+    if let Err(e) = result {
+        eprintln!("Failed to write the updater log: {}", e);
+    }
+}
@@ -0,0 +1,327 @@
+//! Where the updater learns about released versions
+//!
+//! A [`VersionSource`] lists the released versions of the CLI, and optionally when each one was
+//! released. Production nodes read the version tags of the GitHub repository and the test mode
+//! reads the tags of a local clone, but a source can also be an HTTP release index or a fixed list
+//! of versions, which the tests use to run the updater without a network:
+//!
+//! ```json
+//! {"updates": {"source": {"type": "http_index", "url": "https://example.com/releases.json"}}}
+//! ```
+//!
+//! where the release index looks like
+//! `{"releases": [{"version": "0.4.0", "published_at": "2024-12-02T17:00:00Z"}]}`.
+
+use chrono::{DateTime, Utc};
+use semver::Version;
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Arc;
+
+use super::release;
+use crate::config::VersionSourceConfig;
+
+// Only version tags (X.Y.Z format, or X.Y.Z-pre for prereleases), using git's pattern matching
+// Example matches: "1.2.3", "0.3.5", "0.4.0-rc.1"
+// Won't match: "latest", "stable", or other non-version tags
+const VERSION_TAGS: &str = "refs/tags/[0-9]*.[0-9]*.[0-9]*";
+
+pub trait VersionSource: Send + Sync {
+    /// All released versions, in any order
+    fn versions(&self) -> Result<Vec<Version>, Box<dyn std::error::Error>>;
+
+    /// When a version was released, if the source knows
+    fn release_time(&self, _version: &Version) -> Option<DateTime<Utc>> {
+        None
+    }
+
+    /// Where the versions come from, for the logs
+    fn describe(&self) -> String;
+}
+
+/// The version tags of a remote git repository, listed with `git ls-remote`
+pub struct GitRemote {
+    pub url: String,
+}
+
+impl VersionSource for GitRemote {
+    fn versions(&self) -> Result<Vec<Version>, Box<dyn std::error::Error>> {
+        let output = Command::new("git")
+            .args(["ls-remote", "--refs", &self.url, VERSION_TAGS])
+            .envs(crate::proxy::current().child_env())
+            .output()?;
+        if !output.status.success() {
+            return Err(format!(
+                "git ls-remote failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
+        }
+        Ok(parse_tags(&String::from_utf8(output.stdout)?))
+    }
+
+    fn describe(&self) -> String {
+        self.url.clone()
+    }
+}
+
+/// The version tags of a local git repository, dated by when they were tagged
+pub struct LocalGitRepo {
+    pub path: PathBuf,
+}
+
+impl LocalGitRepo {
+    fn git(&self, args: &[&str]) -> Result<String, Box<dyn std::error::Error>> {
+        let output = Command::new("git")
+            .args(args)
+            .current_dir(&self.path)
+            .output()?;
+        if !output.status.success() {
+            return Err(format!(
+                "git {} failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
+        }
+        Ok(String::from_utf8(output.stdout)?)
+    }
+}
+
+impl VersionSource for LocalGitRepo {
+    fn versions(&self) -> Result<Vec<Version>, Box<dyn std::error::Error>> {
+        Ok(parse_tags(&self.git(&[
+            "for-each-ref",
+            "--format=%(refname)",
+            VERSION_TAGS,
+        ])?))
+    }
+
+    fn release_time(&self, version: &Version) -> Option<DateTime<Utc>> {
+        let timestamp = self
+            .git(&[
+                "for-each-ref",
+                "--format=%(creatordate:unix)",
+                &format!("refs/tags/{}", version),
+            ])
+            .ok()?;
+        DateTime::from_timestamp(timestamp.trim().parse().ok()?, 0)
+    }
+
+    fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ReleaseIndex {
+    releases: Vec<ReleaseIndexEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReleaseIndexEntry {
+    version: Version,
+    #[serde(default)]
+    published_at: Option<DateTime<Utc>>,
+}
+
+/// A JSON release index served over HTTP (or read from a `file://` URL)
+pub struct HttpIndex {
+    pub url: String,
+}
+
+impl HttpIndex {
+    fn fetch(&self) -> Result<ReleaseIndex, Box<dyn std::error::Error>> {
+        Ok(serde_json::from_slice(&release::fetch(&self.url)?)?)
+    }
+}
+
+impl VersionSource for HttpIndex {
+    fn versions(&self) -> Result<Vec<Version>, Box<dyn std::error::Error>> {
+        Ok(self
+            .fetch()?
+            .releases
+            .into_iter()
+            .map(|release| release.version)
+            .collect())
+    }
+
+    fn release_time(&self, version: &Version) -> Option<DateTime<Utc>> {
+        self.fetch()
+            .ok()?
+            .releases
+            .into_iter()
+            .find(|release| &release.version == version)?
+            .published_at
+    }
+
+    fn describe(&self) -> String {
+        self.url.clone()
+    }
+}
+
+/// A fixed list of versions and their release times
+#[derive(Default)]
+pub struct FixedVersions {
+    pub releases: Vec<(Version, Option<DateTime<Utc>>)>,
+}
+
+impl VersionSource for FixedVersions {
+    fn versions(&self) -> Result<Vec<Version>, Box<dyn std::error::Error>> {
+        Ok(self
+            .releases
+            .iter()
+            .map(|(version, _)| version.clone())
+            .collect())
+    }
+
+    fn release_time(&self, version: &Version) -> Option<DateTime<Utc>> {
+        self.releases
+            .iter()
+            .find(|(released, _)| released == version)
+            .and_then(|(_, published)| *published)
+    }
+
+    fn describe(&self) -> String {
+        "a fixed list of versions".into()
+    }
+}
+
+pub fn from_config(config: &VersionSourceConfig) -> Arc<dyn VersionSource> {
+    match config {
+        VersionSourceConfig::GitRemote { url } => Arc::new(GitRemote { url: url.clone() }),
+        VersionSourceConfig::LocalGit { path } => Arc::new(LocalGitRepo {
+            path: PathBuf::from(path),
+        }),
+        VersionSourceConfig::HttpIndex { url } => Arc::new(HttpIndex { url: url.clone() }),
+        VersionSourceConfig::Fixed { versions } => Arc::new(FixedVersions {
+            releases: versions
+                .iter()
+                .map(|version| (version.clone(), None))
+                .collect(),
+        }),
+    }
+}
+
+/// Versions from `git ls-remote` or `git for-each-ref` output: the last path segment of each ref
+fn parse_tags(output: &str) -> Vec<Version> {
+    output
+        .lines()
+        .filter_map(|line| line.rsplit('/').next())
+        .filter_map(|tag| Version::parse(tag.trim()).ok())
+        .collect()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::path::Path;
+
+    /// A throwaway git repository, committed and tagged like a release repository
+    pub(crate) struct TestRepo {
+        pub dir: tempfile::TempDir,
+    }
+
+    impl TestRepo {
+        pub fn new() -> Self {
+            let repo = Self {
+                dir: tempfile::TempDir::new().unwrap(),
+            };
+            repo.git(&["init", "--quiet"]);
+            repo.commit("Initial commit");
+            repo
+        }
+
+        pub fn path(&self) -> &Path {
+            self.dir.path()
+        }
+
+        pub fn git(&self, args: &[&str]) {
+            let status = Command::new("git")
+                .args(args)
+                .current_dir(self.path())
+                .env("GIT_AUTHOR_NAME", "test")
+                .env("GIT_AUTHOR_EMAIL", "test@example.com")
+                .env("GIT_COMMITTER_NAME", "test")
+                .env("GIT_COMMITTER_EMAIL", "test@example.com")
+                .status()
+                .unwrap();
+            assert!(status.success(), "git {} failed", args.join(" "));
+        }
+
+        pub fn commit(&self, message: &str) {
+            self.git(&["commit", "--quiet", "--allow-empty", "-m", message]);
+        }
+
+        pub fn tag(&self, tag: &str) {
+            self.git(&["tag", tag]);
+        }
+    }
+
+    fn sorted(mut versions: Vec<Version>) -> Vec<String> {
+        versions.sort();
+        versions.iter().map(Version::to_string).collect()
+    }
+
+    /// Tests that only version tags of a local repository are listed, with their tag dates
+    #[test]
+    fn test_local_git_repo_lists_version_tags() {
+        let repo = TestRepo::new();
+        repo.tag("0.3.5");
+        repo.tag("latest");
+        repo.commit("Release candidate");
+        repo.tag("0.4.0-rc.1");
+
+        let source = LocalGitRepo {
+            path: repo.path().to_path_buf(),
+        };
+        assert_eq!(sorted(source.versions().unwrap()), ["0.3.5", "0.4.0-rc.1"]);
+        let tagged = source.release_time(&Version::new(0, 3, 5)).unwrap();
+        assert!((Utc::now() - tagged).num_minutes() < 5);
+        assert_eq!(source.release_time(&Version::new(1, 0, 0)), None);
+    }
+
+    /// Tests that a remote repository is read with `git ls-remote`
+    #[test]
+    fn test_git_remote_lists_version_tags() {
+        let repo = TestRepo::new();
+        repo.tag("0.3.5");
+        repo.tag("0.3.6");
+
+        let source = GitRemote {
+            url: repo.path().display().to_string(),
+        };
+        assert_eq!(sorted(source.versions().unwrap()), ["0.3.5", "0.3.6"]);
+        assert!(GitRemote {
+            url: repo.path().join("missing").display().to_string()
+        }
+        .versions()
+        .is_err());
+    }
+
+    /// Tests that an HTTP release index is parsed, with its release times
+    #[test]
+    fn test_http_index() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let index = dir.path().join("releases.json");
+        std::fs::write(
+            &index,
+            r#"{"releases": [
+                {"version": "0.4.0", "published_at": "2024-12-02T17:00:00Z"},
+                {"version": "0.3.6"}
+            ]}"#,
+        )
+        .unwrap();
+
+        let source = HttpIndex {
+            url: format!("file://{}", index.display()),
+        };
+        assert_eq!(sorted(source.versions().unwrap()), ["0.3.6", "0.4.0"]);
+        assert_eq!(
+            source.release_time(&Version::new(0, 4, 0)).unwrap(),
+            "2024-12-02T17:00:00Z".parse::<DateTime<Utc>>().unwrap()
+        );
+        assert_eq!(source.release_time(&Version::new(0, 3, 6)), None);
+    }
+}
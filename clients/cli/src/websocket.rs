@@ -1,3 +1,127 @@
+//! The websocket connection to the orchestrator
+
+use crate::connection::{connect_to_orchestrator, ConnectionSettings};
+use crate::protocol::Protocol;
+use crate::transport::{Connector, Transport, TransportError};
+use futures::future::BoxFuture;
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Message};
+use tokio_tungstenite::WebSocketStream;
+
+/// Connects to the orchestrator at a websocket URL, e.g. wss://beta.orchestrator.nexus.xyz/prove
+pub struct WebSocketConnector {
+    pub url: String,
+    pub settings: ConnectionSettings,
+}
+
+impl Connector for WebSocketConnector {
+    fn connect(&self) -> BoxFuture<'_, Result<Box<dyn Transport>, TransportError>> {
+        Box::pin(async move {
+            let (stream, protocol) = connect_to_orchestrator(&self.url, &self.settings).await?;
+            Ok(Box::new(Negotiated { stream, protocol }) as Box<dyn Transport>)
+        })
+    }
+
+    fn address(&self) -> &str {
+        &self.url
+    }
+}
+
+/// A websocket connection, with the protocol picked in its handshake
+struct Negotiated<S> {
+    stream: WebSocketStream<S>,
+    protocol: Protocol,
+}
+
+impl<S> Transport for Negotiated<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    fn send(&mut self, message: Message) -> BoxFuture<'_, Result<(), TransportError>> {
+        Transport::send(&mut self.stream, message)
+    }
+
+    fn receive(&mut self) -> BoxFuture<'_, Option<Result<Message, TransportError>>> {
+        Transport::receive(&mut self.stream)
+    }
+
+    fn close(
+        &mut self,
+        frame: Option<CloseFrame<'static>>,
+    ) -> BoxFuture<'_, Result<(), TransportError>> {
+        Transport::close(&mut self.stream, frame)
+    }
+
+    fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+}
+
+impl<S> Transport for WebSocketStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    fn send(&mut self, message: Message) -> BoxFuture<'_, Result<(), TransportError>> {
+        Box::pin(async move { Ok(SinkExt::send(self, message).await?) })
+    }
+
+    fn receive(&mut self) -> BoxFuture<'_, Option<Result<Message, TransportError>>> {
+        Box::pin(async move { Some(self.next().await?.map_err(TransportError::from)) })
+    }
+
+    fn close(
+        &mut self,
+        frame: Option<CloseFrame<'static>>,
+    ) -> BoxFuture<'_, Result<(), TransportError>> {
+        Box::pin(async move { Ok(WebSocketStream::close(self, frame).await?) })
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::protocol::ProtocolMode;
+    use crate::proxy::ProxySettings;
+    use crate::tls::TlsSettings;
+    use tokio::net::TcpListener;
+
+    /// Tests that messages go through the websocket transport both ways
+    #[tokio::test]
+    async fn test_websocket_transport() -> Result<(), TransportError> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let connector = WebSocketConnector {
+            url: format!("ws://{}/prove", listener.local_addr()?),
+            settings: ConnectionSettings {
+                tls: TlsSettings::from_config(&Default::default()).unwrap(),
+                proxy: ProxySettings::default(),
+                prover_id: None,
+                auth_token: None,
+                protocol: ProtocolMode::Auto,
+            },
+        };
+
+        // An orchestrator echoing binary messages back
+        let server_handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            let mut ws_stream = tokio_tungstenite::accept_async(stream).await?;
+            while let Some(message) = ws_stream.next().await {
+                if let Message::Binary(data) = message? {
+                    SinkExt::send(&mut ws_stream, Message::Binary(data)).await?;
+                }
+            }
+            Ok::<_, TransportError>(())
+        });
+
+        let mut client = connector.connect().await?;
+        client.send(Message::Binary(vec![1, 2, 3])).await?;
+        assert_eq!(
+            client.receive().await.unwrap()?,
+            Message::Binary(vec![1, 2, 3])
+        );
+        client.close(None).await?;
+
+        server_handle.await??;
+        Ok(())
+    }
 }
@@ -1,12 +1,34 @@
 #!/bin/sh
 
 rustc --version || curl https://sh.rustup.rs -sSf | sh
-NEXUS_HOME=$HOME/.nexus
+# The same directories as the CLI's, see clients/cli/README.md
+case "$(uname)" in
+    Darwin)
+        NEXUS_STATE="$HOME/Library/Application Support/nexus"
+        NEXUS_CACHE="$HOME/Library/Caches/nexus"
+        ;;
+    *)
+        NEXUS_STATE="${XDG_STATE_HOME:-$HOME/.local/state}/nexus"
+        NEXUS_CACHE="${XDG_CACHE_HOME:-$HOME/.cache}/nexus"
+        ;;
+esac
+PROVER_ID_PATH="$NEXUS_STATE/prover-id"
+REPO_PATH="$NEXUS_CACHE/network-api"
+
+# Earlier versions kept these in ~/.nexus. The CLI moves them on its first start, but only after
+# they would be looked for here.
+LEGACY_HOME=$HOME/.nexus
+if [ -f "$LEGACY_HOME/prover-id" ] && [ ! -f "$PROVER_ID_PATH" ]; then
+    mkdir -p "$NEXUS_STATE" && mv "$LEGACY_HOME/prover-id" "$PROVER_ID_PATH"
+fi
+if [ -d "$LEGACY_HOME/network-api" ] && [ ! -d "$REPO_PATH" ]; then
+    mkdir -p "$NEXUS_CACHE" && mv "$LEGACY_HOME/network-api" "$REPO_PATH"
+fi
 GREEN='\033[1;32m'
 ORANGE='\033[1;33m'
 NC='\033[0m' # No Color
 
-while [ -z "$NONINTERACTIVE" ] && [ ! -f "$NEXUS_HOME/prover-id" ]; do
+while [ -z "$NONINTERACTIVE" ] && [ ! -f "$PROVER_ID_PATH" ]; do
     read -p "Do you agree to the Nexus Beta Terms of Use (https://nexus.xyz/terms-of-use)? (Y/n) " yn </dev/tty
     case $yn in
         [Nn]* ) exit;;
@@ -23,7 +45,7 @@ if [ $GIT_IS_AVAILABLE != 0 ]; then
   exit 1;
 fi
 
-PROVER_ID=$(cat $NEXUS_HOME/prover-id 2>/dev/null)
+PROVER_ID=$(cat "$PROVER_ID_PATH" 2>/dev/null)
 # Email verification prompt temporarily disabled
 SKIP_EMAIL_VERIFICATION=1
 if [ -z "$SKIP_EMAIL_VERIFICATION" ] && [ -z "$NONINTERACTIVE" ] && [ "${#PROVER_ID}" -ne "28" ]; then
@@ -34,12 +56,13 @@ if [ -z "$SKIP_EMAIL_VERIFICATION" ] && [ -z "$NONINTERACTIVE" ] && [ "${#PROVER
     read -p "Enter your Prover Id (optional)> " PROVER_ID </dev/tty
     while [ ! ${#PROVER_ID} -eq "0" ]; do
         if [ ${#PROVER_ID} -eq "28" ]; then
-            if [ -f "$NEXUS_HOME/prover-id" ]; then
-                echo Copying $NEXUS_HOME/prover-id to $NEXUS_HOME/prover-id.bak
-                cp $NEXUS_HOME/prover-id $NEXUS_HOME/prover-id.bak
+            if [ -f "$PROVER_ID_PATH" ]; then
+                echo "Copying $PROVER_ID_PATH to $PROVER_ID_PATH.bak"
+                cp "$PROVER_ID_PATH" "$PROVER_ID_PATH.bak"
             fi
-            echo "$PROVER_ID" > $NEXUS_HOME/prover-id
-            echo Prover id saved to $NEXUS_HOME/prover-id.
+            mkdir -p "$NEXUS_STATE"
+            echo "$PROVER_ID" > "$PROVER_ID_PATH"
+            echo "Prover id saved to $PROVER_ID_PATH."
             break;
         else
             echo Unable to validate $PROVER_ID. Please make sure the full prover id is copied.
@@ -48,14 +71,13 @@ if [ -z "$SKIP_EMAIL_VERIFICATION" ] && [ -z "$NONINTERACTIVE" ] && [ "${#PROVER
     done
 fi
 
-REPO_PATH=$NEXUS_HOME/network-api
 if [ -d "$REPO_PATH" ]; then
   echo "$REPO_PATH exists. Updating.";
-  (cd $REPO_PATH && git stash save && git fetch --tags)
+  (cd "$REPO_PATH" && git stash save && git fetch --tags)
 else
-  mkdir -p $NEXUS_HOME
-  (cd $NEXUS_HOME && git clone https://github.com/nexus-xyz/network-api)
+  mkdir -p "$NEXUS_CACHE"
+  (cd "$NEXUS_CACHE" && git clone https://github.com/nexus-xyz/network-api)
 fi
-(cd $REPO_PATH && git -c advice.detachedHead=false checkout $(git rev-list --tags --max-count=1))
+(cd "$REPO_PATH" && git -c advice.detachedHead=false checkout $(git rev-list --tags --max-count=1))
 
-(cd $REPO_PATH/clients/cli && cargo run --release --bin prover -- beta.orchestrator.nexus.xyz)
+(cd "$REPO_PATH/clients/cli" && cargo run --release --bin prover -- beta.orchestrator.nexus.xyz)